@@ -1,12 +1,177 @@
-use crate::hit::{HitRecord, World};
-use crate::ray::Ray;
+use crate::hit::{merge_packet_hits, Hit, HitRecord, World};
+use crate::ray::{Ray, RayPacket, PACKET_MASK, PACKET_SIZE};
 use crate::utils::aabb::Aabb;
+use crate::utils::util::{Float, Vec4};
 
-pub trait Accelerator {
+// the rays start this far from their origin, so that a ray leaving
+// a surface does not intersect the very same surface again
+pub const RAY_EPSILON: Float = 0.001;
+
+/*
+    Sync marker trait is required as the tracer shares the accelerator
+    between the rendering threads.
+    The queries follow the conventions of the Hit trait, so that the tracer
+    renders through any accelerator, down to the brute force World.
+*/
+pub trait Accelerator : Sync {
     // Method to build the acceleration structure
-    fn build(&mut self, world: &World);
+    // the accelerator takes the ownership of the world
+    fn build(&mut self, world: World);
 
     // Method to check for intersection with a ray
-    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    // returns the closest intersection in between t_min and t_max
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+
+    // Method to get the bounding box of all the objects in the accelerator
+    // returns None if it is empty or holds objects without a bounding box
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    // Method to check if anything blocks the ray in between t_min and t_max, as asked for
+    // by shadow rays. Any intersection will do, so the traversal stops at the first one
+    // found. The default looks for the closest one
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    // Method to check for intersection with a packet of coherent rays
+    // returns the closest intersection of every ray, the default traces them one by one
+    fn intersect_packet(&self, packet: &RayPacket, t_min: Float, t_max: Float) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+        std::array::from_fn(|lane| self.intersect(packet.ray(lane), t_min, t_max))
+    }
+}
+
+/*
+    The world itself is the brute force accelerator, which tests every
+    ray against all the objects. There is nothing to build.
+*/
+impl Accelerator for World {
+    fn build(&mut self, world: World) {
+        *self = world;
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Hit::bounding_box(self)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        Hit::occluded(self, ray, t_min, t_max)
+    }
+}
+
+/*
+    The objects without a bounding box, like planes, have no place in an acceleration
+    structure. The accelerators keep the indices of these objects apart and test them
+    with every ray ahead of their traversal, which goes on from the closest hit found.
+*/
+pub(crate) fn intersect_unbounded<'a>(world: &'a World, unbounded: &[usize], ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'a>> {
+    let mut closest_so_far = t_max;
+    let mut closest_hit = None;
+
+    for &i in unbounded {
+        if let Some(rec) = world[i].hit(ray, t_min, closest_so_far) {
+            closest_so_far = rec.t;
+            closest_hit = Some(rec);
+        }
+    }
+
+    closest_hit
+}
+
+pub(crate) fn occluded_unbounded(world: &World, unbounded: &[usize], ray: &Ray, t_min: Float, t_max: Float) -> bool {
+    unbounded.iter().any(|&i| world[i].occluded(ray, t_min, t_max))
+}
+
+// returns the closest hits of the rays of the packet together with their distances
+pub(crate) fn intersect_packet_unbounded<'a>(world: &'a World, unbounded: &[usize], packet: &RayPacket,
+                                             t_min: Float, t_max: Float) -> ([Option<HitRecord<'a>>; PACKET_SIZE], Vec4) {
+    let mut closest_so_far = Vec4::splat(t_max);
+    let mut closest_hits: [Option<HitRecord<'a>>; PACKET_SIZE] = Default::default();
+
+    for &i in unbounded {
+        let hits = world[i].hit_packet(packet, t_min, closest_so_far, PACKET_MASK);
+        merge_packet_hits(&mut closest_hits, &mut closest_so_far, hits);
+    }
+
+    (closest_hits, closest_so_far)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::accelerators::bvh::Bvh;
+    use crate::accelerators::bvh::tests::sphere_world;
+    use crate::accelerators::bvh4::Bvh4;
+    use crate::accelerators::grid::Grid;
+    use crate::accelerators::kdtree::KdTree;
+    use crate::utils::util::{Point3, Vec3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // the accelerator built over a copy of the world has to answer the queries of random
+    // rays just like the brute force world
+    pub(crate) fn assert_matches_world<A: Accelerator>(accelerator: &A, world: &World) {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for _ in 0..2000 {
+            let origin = Point3::new(rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0));
+            let direction = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            let ray = Ray::new(origin, direction);
+            let t_max = rng.gen_range(0.5..20.0);
+
+            match (world.intersect(&ray, RAY_EPSILON, Float::INFINITY), accelerator.intersect(&ray, RAY_EPSILON, Float::INFINITY)) {
+                (Some(expected), Some(actual)) => {
+                    assert!((expected.t - actual.t).abs() < 0.0001);
+                    assert!((expected.p - actual.p).length() < 0.0001);
+                }
+                (None, None) => {}
+                _ => panic!("accelerator and world disagree on a hit"),
+            }
+
+            assert_eq!(Accelerator::occluded(world, &ray, RAY_EPSILON, t_max), accelerator.occluded(&ray, RAY_EPSILON, t_max));
+
+            // the shadow ray is blocked exactly if there is a closest hit before t_max
+            let blocked = accelerator.intersect(&ray, RAY_EPSILON, t_max).is_some();
+            assert_eq!(blocked, accelerator.occluded(&ray, RAY_EPSILON, t_max));
+        }
+    }
+
+    // the accelerator is built over the spheres, which it bounds like the world does
+    fn assert_matches_sphere_world<A: Accelerator>(mut accelerator: A) {
+        let world = sphere_world(300);
+        accelerator.build(sphere_world(300));
+
+        let bounds = Accelerator::bounding_box(&world).unwrap();
+        assert!(accelerator.bounding_box().unwrap().approx_contains_aabb_eps(&bounds, 0.0001));
+        assert!(bounds.approx_contains_aabb_eps(&accelerator.bounding_box().unwrap(), 0.0001));
+
+        assert_matches_world(&accelerator, &world);
+    }
+
+    #[test]
+    fn accelerators_match_world() {
+        assert_matches_sphere_world(World::new());
+        assert_matches_sphere_world(Bvh::new(None, None));
+        assert_matches_sphere_world(Bvh4::new(None, None));
+        assert_matches_sphere_world(KdTree::new(None));
+        assert_matches_sphere_world(Grid::new(None));
+    }
+
+    #[test]
+    fn empty_accelerators_have_no_bounds() {
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(World::new());
+        assert!(bvh.bounding_box().is_none());
+
+        let mut world = World::new();
+        Accelerator::build(&mut world, World::new());
+        assert!(Accelerator::bounding_box(&world).is_none());
 
-}
\ No newline at end of file
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!Accelerator::occluded(&world, &ray, RAY_EPSILON, Float::INFINITY));
+        assert!(!bvh.occluded(&ray, RAY_EPSILON, Float::INFINITY));
+    }
+}
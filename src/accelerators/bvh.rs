@@ -1,20 +1,61 @@
-use crate::ray::Ray;
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
 use crate::utils::aabb::Aabb;
-use crate::utils::util::Point3;
-use crate::hit::HitRecord;
-use crate::hit::World;
-use crate::accelerators::accelerator::Accelerator;
+use crate::utils::util::{Float, Point3, Vec3};
+use crate::hit::{merge_packet_hits, HitRecord};
+use crate::hit::{Hit, World};
+use crate::accelerators::accelerator::{Accelerator, intersect_unbounded, occluded_unbounded, intersect_packet_unbounded};
 use crate::utils::stack::Stack;
+use crate::utils::morton::{encode_morton3, radix_sort, MortonPrimitive, MORTON_BITS};
 use rayon::prelude::*;
+use std::fmt;
 
+#[derive(Clone, Copy)]
 pub enum SplitMethod {
-    //SAH,
-    //HLBVH,
+    SAH(SahParams),
+    HLBVH(HlbvhParams),
     Middle,
-    //EqualCounts,
+    EqualCounts,
 }
 
+// parameters of the binned surface area heuristic
+#[derive(Clone, Copy)]
+pub struct SahParams {
+    pub bins: usize, // number of bins the centroid bounds are divided into
+    pub traversal_cost: Float, // cost of traversing an interior node
+    pub intersection_cost: Float, // cost of intersecting a single primitive
+}
+
+impl Default for SahParams {
+    fn default() -> Self {
+        // the traversal is assumed to be 1/8 as expensive as a primitive intersection
+        Self {
+            bins: 12,
+            traversal_cost: 0.125,
+            intersection_cost: 1.0,
+        }
+    }
+}
+
+// parameters of the hierarchical linear bvh builder
+#[derive(Clone, Copy)]
+pub struct HlbvhParams {
+    // number of the leading morton code bits that group the primitives into treelets
+    pub treelet_bits: u32,
+    // build the levels above the treelets with the surface area heuristic,
+    // otherwise the leading morton code bits decide the splits as well
+    pub upper_sah: Option<SahParams>,
+}
 
+impl Default for HlbvhParams {
+    fn default() -> Self {
+        Self {
+            treelet_bits: 12,
+            upper_sah: Some(SahParams::default()),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct BVHPrimitiveInfo {
     primitive_index: usize,
     bounds: Aabb,
@@ -42,205 +83,1601 @@ impl BVHPrimitiveInfo {
 }
 
 
+// subtrees over at least this many primitives are built in parallel
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+// refitted trees costing half as much again as the freshly built tree are rebuilt
+const DEFAULT_REBUILD_THRESHOLD: Float = 1.5;
+
+// a leaf holds at most as many primitives as fit into the packed count of a node
+const MAX_LEAF_PRIMITIVES: usize = u16::MAX as usize;
+
+/*
+    The nodes are stored in a single contiguous list in depth first order and are
+    packed into 32 bytes with single precision boxes, so that two of them fit into a
+    cache line. The double precision boxes of the f64 feature take 64 bytes, a whole
+    cache line per node. A leaf is marked by a non-zero number of primitives.
+*/
+#[derive(Clone)]
+#[repr(C, align(32))]
 pub struct BvhNode {
     aabb: Aabb, // bounding box of the node
-    primitives_per_node: usize, // number of primitives in the node
-    // if leaf node, index into the primitives array
+    // if leaf node, index into the first primitive of the leaf
     // if interior node, index into the right child in the node list
-    // as the left child is be default placed right after the parent 
+    // as the left child is be default placed right after the parent
     // node in the list
-    index: usize,
-    split_axis: usize, // axis along which the node is split
-    leaf: bool, // boolean flag to mark if it is a leaf node or not
-}
-
-impl Clone for BvhNode {
-    fn clone(&self) -> Self {
-        Self {
-            aabb: self.aabb.clone(), // assuming aabb implements Clone
-            primitives_per_node: self.primitives_per_node,
-            index: self.index,
-            split_axis: self.split_axis,
-            leaf: self.leaf,
-        }
-    }
+    index: u32,
+    primitives_per_node: u16, // number of primitives in a leaf, 0 for interior nodes
+    split_axis: u8, // axis along which the node is split
 }
 
-
 impl BvhNode {
     pub fn new(aabb: Aabb) -> Self {
         Self {
             aabb,
+            index: u32::MAX,
             primitives_per_node: 0,
-            index: usize::MAX,
-            split_axis: usize::MAX,
-            leaf: false,
+            split_axis: 0,
         }
     }
 
-    pub fn makeInterior(&mut self, index: usize, split_axis: usize){
+    pub fn make_interior(&mut self, index: usize, split_axis: usize){
 
-        // index into the right child in the node list  
-        self.index = index;
+        // index into the right child in the node list
+        self.index = index as u32;
         // the coordianate axis along which the node is split
-        self.split_axis = split_axis;
-        self.leaf = false;
+        self.split_axis = split_axis as u8;
+        self.primitives_per_node = 0;
     }
 
-    pub fn makeLeaf(&mut self, index: usize, num_primitives: usize){
+    pub fn make_leaf(&mut self, index: usize, num_primitives: usize){
+        debug_assert!(num_primitives > 0 && num_primitives <= MAX_LEAF_PRIMITIVES);
+
         // index into the primitives array
-        self.index = index;
-        self.primitives_per_node = num_primitives;
-        self.leaf = true;
+        self.index = index as u32;
+        self.primitives_per_node = num_primitives as u16;
     }
 
-    pub fn isLeaf(&self) -> bool {
-        self.leaf
-    } 
+    pub fn is_leaf(&self) -> bool {
+        self.primitives_per_node > 0
+    }
 
     // get the AABB of the node
     pub fn bounding_box(&self) -> Aabb {
         self.aabb.clone()
-    } 
+    }
+
+    // index of the first primitive of a leaf or of the right child of an interior node
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn num_primitives(&self) -> usize {
+        self.primitives_per_node as usize
+    }
+
+    pub fn split_axis(&self) -> usize {
+        self.split_axis as usize
+    }
+}
+
+// inconsistencies of the bvh found by Bvh::validate
+#[derive(Debug, PartialEq)]
+pub enum BvhError {
+    // a primitive of the world is neither in a leaf nor in the unbounded list
+    UnreachablePrimitive(usize),
+    // a primitive of the world is referenced more than once
+    DuplicatePrimitive(usize),
+    // an interior node points at a child outside of the node list or in front of itself
+    ChildOutOfRange { node: usize, child: usize },
+    // the bounding box of a child is not contained in the bounding box of its parent
+    ChildOutsideParent { node: usize, child: usize },
+    // a leaf refers to primitives outside of the primitive list
+    LeafOutOfRange(usize),
+    // a node is reached more than once while walking down from the root
+    DuplicateNode(usize),
+    // a node is not reached from the root
+    UnreachableNode(usize),
+}
+
+impl fmt::Display for BvhError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BvhError::UnreachablePrimitive(i) => write!(f, "primitive {} is not reachable", i),
+            BvhError::DuplicatePrimitive(i) => write!(f, "primitive {} is referenced more than once", i),
+            BvhError::ChildOutOfRange { node, child } => write!(f, "node {} points at child {} out of range", node, child),
+            BvhError::ChildOutsideParent { node, child } => write!(f, "child {} is not contained in node {}", child, node),
+            BvhError::LeafOutOfRange(node) => write!(f, "leaf {} refers to primitives out of range", node),
+            BvhError::DuplicateNode(node) => write!(f, "node {} is reached more than once", node),
+            BvhError::UnreachableNode(node) => write!(f, "node {} is not reachable", node),
+        }
+    }
+}
 
-    pub fn setNumPrimitives(&mut self, num_primitives: usize){
-        self.primitives_per_node = num_primitives;
-    } 
+// quality report of a built bvh, see Bvh::stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct BvhStats {
+    pub node_count: usize, // number of interior and leaf nodes
+    pub leaf_count: usize, // number of leaf nodes
+    pub unbounded_count: usize, // number of primitives kept out of the tree
+    pub max_depth: usize, // depth of the deepest leaf, the root has depth 0
+    pub average_depth: Float, // average depth of the leaves
+    pub average_primitives_per_leaf: Float,
+    pub sah_cost: Float, // expected cost of a ray through the tree by the surface area heuristic
+    pub leaf_size_histogram: Vec<usize>, // number of leaves per number of primitives in the leaf
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "BVH nodes: {}", self.node_count)?;
+        writeln!(f, "BVH leaves: {}", self.leaf_count)?;
+        writeln!(f, "Unbounded primitives: {}", self.unbounded_count)?;
+        writeln!(f, "Depth: max {}, average {:.2}", self.max_depth, self.average_depth)?;
+        writeln!(f, "Primitives per leaf: average {:.2}", self.average_primitives_per_leaf)?;
+        writeln!(f, "SAH cost: {:.4}", self.sah_cost)?;
+        write!(f, "Leaf sizes:")?;
+
+        for (size, &count) in self.leaf_size_histogram.iter().enumerate() {
+            if count > 0 {
+                write!(f, "\n  {:>4} primitives: {}", size, count)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub struct Bvh {
-    nodes: Vec<Box<BvhNode>>,
-    primitives: Vec<Box<BVHPrimitiveInfo>>,
+    world: World, // the intersectable objects the bvh is built over
+    world_order: Vec<usize>, // index of every object of the world in the world handed to build
+    unbounded: Vec<usize>, // indices of the objects without a bounding box
+    nodes: Vec<BvhNode>,
+    primitives: Vec<BVHPrimitiveInfo>,
     max_primitives_per_node: usize,
     split_method: SplitMethod,
-    parent_stack: Stack<usize>,
+    build_sah_cost: Float, // surface area heuristic cost of the tree right after the last build
+    rebuild_threshold: Float, // refitted trees costing more than this times the build cost are rebuilt
 }
 
 impl Bvh {
-    pub fn new(maxPrimitivesPerNode: Option<usize>,
-               splitMethod: Option<SplitMethod>) -> Self {
+    pub fn new(max_primitives_per_node: Option<usize>,
+               split_method: Option<SplitMethod>) -> Self {
         Self {
+            world: World::new(),
+            world_order: Vec::new(),
+            unbounded: Vec::new(),
             nodes: Vec::new(),
             primitives: Vec::new(),
-            max_primitives_per_node: maxPrimitivesPerNode.unwrap_or_else(|| 4),
-            split_method: splitMethod.unwrap_or_else(|| SplitMethod::Middle),
-            parent_stack: Stack::new(),
+            max_primitives_per_node: max_primitives_per_node.unwrap_or(4).min(MAX_LEAF_PRIMITIVES),
+            split_method: split_method.unwrap_or(SplitMethod::Middle),
+            build_sah_cost: 0.0,
+            rebuild_threshold: DEFAULT_REBUILD_THRESHOLD,
         }
     }
 
-    fn build_recursive(&mut self, left_index: usize, right_index: usize, mut depth: usize) -> usize {
+    // set the ratio of the surface area heuristic cost of a refitted tree to the cost right
+    // after the last build, above which the refitted tree is rebuilt from scratch
+    pub fn set_rebuild_threshold(&mut self, rebuild_threshold: Float) {
+        self.rebuild_threshold = rebuild_threshold;
+    }
 
-        // get the bounding box of the primitives in between 
-        // left_index and right_index inclusive
-        let mut aabb = Aabb::empty();
-        for i in left_index..right_index {
-            aabb = aabb.include(&self.primitives[i].bounding_box());
+    // refit the bounding boxes of the nodes bottom up to the objects of the given world, which
+    // holds the same objects in the same order as the world the bvh was built over but at new
+    // positions. The topology of the tree is kept, unless the objects do not fit it anymore or
+    // the refitted tree has degraded beyond the rebuild threshold, then the tree is rebuilt.
+    // Returns true if the tree was rebuilt. Like build, it takes the world it intersects by value
+    pub fn refit(&mut self, world: World) -> bool {
+        if world.len() != self.world.len() {
+            self.build(world);
+            return true;
         }
 
-        // get the number of primitives between left_index and right_index
-        let num_primitives : usize = (right_index - left_index) as usize;
+        // move the objects into the order of the leaves
+        let mut objects: Vec<Option<Box<dyn Hit>>> = world.into_iter().map(Some).collect();
+        self.world = self.world_order.iter().map(|&i| objects[i].take().unwrap()).collect();
 
-        // initiate a bvhnode with the expanded/merged bounding box
-        let node = Box::new(BvhNode::new(aabb));
+        // the objects in the leaves need a bounding box and the unbounded ones must not have one
+        let num_bounded = self.world.len() - self.unbounded.len();
+        let bounds: Vec<Option<Aabb>> = self.world.par_iter().map(|object| object.bounding_box()).collect();
 
-        // move the node into the nodes list
-        self.nodes[depth] = node;
+        if bounds.iter().enumerate().any(|(i, aabb)| aabb.is_some() != (i < num_bounded)) {
+            let world = self.take_world();
+            self.build(world);
+            return true;
+        }
 
-        // set the number of primitives in the node to num_primitives
-        self.nodes[depth].setNumPrimitives(num_primitives);
+        // the children are stored after their parents, so walking the
+        // nodes backwards visits the children before their parents
+        for i in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[i];
 
-        // if the number of primitives is less than the maximum number of primitives per node
-        // then make the node a leaf node
-        if num_primitives <= self.max_primitives_per_node {
-            self.nodes[depth].makeLeaf(left_index, num_primitives);
-        }
-        else {
-                    
-            // get the largest axis of the bounding box
-            // between left_index and right_index
-            let largest_axis = self.nodes[depth].bounding_box().largest_axis() as usize;
-
-            if self.nodes[depth].bounding_box().max()[largest_axis] - 
-               self.nodes[depth].bounding_box().min()[largest_axis] < f32::EPSILON {
-                self.nodes[depth].makeLeaf(left_index, num_primitives);
-                return depth;
-            }
-
-            // get the split index
-            let mut split_index = left_index + right_index / 2;
-
-            match self.split_method {
-                SplitMethod::Middle => {
-                    // sort the primitives along the largest axis
-                    self.primitives[left_index..right_index].sort_by(|a, b| {
-                        a.centroid()[largest_axis as usize].partial_cmp(&b.centroid()[largest_axis as usize]).unwrap()
-                    });
-
-                    // get the middle index of the sorted primitives
-                    split_index = (left_index + right_index) / 2;
-                                                  
+            let aabb = if node.is_leaf() {
+                bounds[node.index()..node.index() + node.num_primitives()]
+                    .iter()
+                    .fold(Aabb::empty(), |a, aabb| a.include(aabb.as_ref().unwrap()))
+            } else {
+                self.nodes[i + 1].aabb.include(&self.nodes[node.index()].aabb)
+            };
+
+            self.nodes[i].aabb = aabb;
+        }
+
+        if self.stats().sah_cost > self.build_sah_cost * self.rebuild_threshold {
+            let world = self.take_world();
+            self.build(world);
+            return true;
+        }
+
+        false
+    }
+
+    // move the objects out of the bvh in the order of the world the bvh was built over
+    fn take_world(&mut self) -> World {
+        let mut objects: Vec<Option<Box<dyn Hit>>> = std::mem::take(&mut self.world).into_iter().map(Some).collect();
+        let mut world_slots: Vec<usize> = vec![0; objects.len()];
+
+        for (slot, &i) in self.world_order.iter().enumerate() {
+            world_slots[i] = slot;
+        }
+
+        world_slots.iter().map(|&slot| objects[slot].take().unwrap()).collect()
+    }
+
+    // take the built bvh apart into the world in leaf order, the indices of the
+    // unbounded objects and the node list, the wide bvh is collapsed from these
+    pub(crate) fn into_parts(self) -> (World, Vec<usize>, Vec<BvhNode>) {
+        (self.world, self.unbounded, self.nodes)
+    }
+
+    // check the consistency of the built tree: every primitive of the world is reachable
+    // exactly once, the right child indices are in range and the bounding boxes of the
+    // children are contained in the bounding boxes of their parents
+    pub fn validate(&self) -> Result<(), BvhError> {
+        let mut primitive_references = vec![0usize; self.world.len()];
+
+        for &i in &self.unbounded {
+            primitive_references[i] += 1;
+        }
+
+        let mut visited = vec![false; self.nodes.len()];
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+
+        if !self.nodes.is_empty() {
+            nodes_to_visit.push(0);
+        }
+
+        while let Some(current) = nodes_to_visit.pop() {
+            if visited[current] {
+                return Err(BvhError::DuplicateNode(current));
+            }
+            visited[current] = true;
+
+            let node = &self.nodes[current];
+
+            if node.is_leaf() {
+                // the primitives of a leaf are a contiguous slice of the world
+                let leaf_references = primitive_references
+                    .get_mut(node.index()..node.index() + node.num_primitives())
+                    .ok_or(BvhError::LeafOutOfRange(current))?;
+
+                for references in leaf_references {
+                    *references += 1;
                 }
-                _ => {
-                    // Handle all other cases
+            } else {
+                // the left child is placed right after the parent and
+                // the right child after the subtree of the left child
+                for child in [current + 1, node.index()] {
+                    if child <= current || child >= self.nodes.len() {
+                        return Err(BvhError::ChildOutOfRange { node: current, child });
+                    }
+
+                    if !node.aabb.approx_contains_aabb_eps(&self.nodes[child].aabb, Float::EPSILON) {
+                        return Err(BvhError::ChildOutsideParent { node: current, child });
+                    }
+
+                    nodes_to_visit.push(child);
                 }
             }
+        }
 
-            self.parent_stack.push(depth);
+        if let Some(node) = visited.iter().position(|&v| !v) {
+            return Err(BvhError::UnreachableNode(node));
+        }
 
-            // build the left child
-            depth = self.build_recursive(left_index, split_index, depth + 1); 
+        for (i, &references) in primitive_references.iter().enumerate() {
+            match references {
+                0 => return Err(BvhError::UnreachablePrimitive(i)),
+                1 => {}
+                _ => return Err(BvhError::DuplicatePrimitive(i)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // gather the statistics of the built tree. The surface area heuristic cost uses the
+    // default cost constants, so that trees built by different split methods can be compared
+    pub fn stats(&self) -> BvhStats {
+        let params = SahParams::default();
+
+        let mut stats = BvhStats {
+            node_count: self.nodes.len(),
+            leaf_count: 0,
+            unbounded_count: self.unbounded.len(),
+            max_depth: 0,
+            average_depth: 0.0,
+            average_primitives_per_leaf: 0.0,
+            sah_cost: 0.0,
+            leaf_size_histogram: Vec::new(),
+        };
+
+        if self.nodes.is_empty() {
+            return stats;
+        }
+
+        let root_area = self.nodes[0].aabb.surface_area().max(Float::MIN_POSITIVE);
+        let mut depth_sum = 0;
+        let mut primitive_sum = 0;
+
+        // walk down the tree from the root keeping track of the depth of every node
+        let mut nodes_to_visit: Stack<(usize, usize)> = Stack::new();
+        nodes_to_visit.push((0, 0));
+
+        while let Some((current, depth)) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+
+            // probability of a ray through the root to pass through the node as well
+            let hit_probability = node.aabb.surface_area() / root_area;
 
-            let top = *(self.parent_stack.peek().unwrap());
+            if node.is_leaf() {
+                let size = node.num_primitives();
+
+                stats.leaf_count += 1;
+                stats.max_depth = stats.max_depth.max(depth);
+                stats.sah_cost += hit_probability * params.intersection_cost * size as Float;
+
+                depth_sum += depth;
+                primitive_sum += size;
+
+                if stats.leaf_size_histogram.len() <= size {
+                    stats.leaf_size_histogram.resize(size + 1, 0);
+                }
+                stats.leaf_size_histogram[size] += 1;
+            } else {
+                stats.sah_cost += hit_probability * params.traversal_cost;
+
+                nodes_to_visit.push((node.index(), depth + 1));
+                nodes_to_visit.push((current + 1, depth + 1));
+            }
+        }
+
+        stats.average_depth = depth_sum as Float / stats.leaf_count as Float;
+        stats.average_primitives_per_leaf = primitive_sum as Float / stats.leaf_count as Float;
+
+        stats
+    }
+
+    // build the nodes over the primitive infos with the split method of the bvh
+    fn build_nodes(&mut self) {
+        match self.split_method {
+            SplitMethod::HLBVH(params) => {
+                self.nodes = self.build_hlbvh(&params);
+            }
+            _ => {
+                // the primitives are partitioned by the builder threads while
+                // the bvh itself is only read for the build settings
+                let mut primitives = std::mem::take(&mut self.primitives);
+
+                // a binary tree over n primitives has at most 2n - 1 nodes
+                let mut nodes = Vec::with_capacity(2 * primitives.len() - 1);
+
+                // build the bvh tree recursively
+                self.build_recursive(&mut primitives, 0, &mut nodes);
+
+                self.primitives = primitives;
+                self.nodes = nodes;
+            }
+        }
+    }
+
+    // build the subtree over the given primitives, which start at offset in the list of all
+    // the primitives, and append its nodes in depth first order. The subtrees of large nodes
+    // are built in parallel into their own node lists and then appended one after the other
+    fn build_recursive(&self, primitives: &mut [BVHPrimitiveInfo], offset: usize, nodes: &mut Vec<BvhNode>) {
+
+        // get the number of primitives in the subtree
+        let num_primitives = primitives.len();
+        let parallel = num_primitives >= PARALLEL_BUILD_THRESHOLD;
+
+        // get the bounding box of the primitives
+        let aabb = if parallel {
+            primitives.par_iter()
+                .map(|info| info.bounding_box())
+                .reduce(Aabb::empty, |a, b| a.include(&b))
+        } else {
+            primitives.iter().fold(Aabb::empty(), |a, info| a.include(&info.bounding_box()))
+        };
+
+        // initiate a bvhnode with the expanded/merged bounding box
+        // and push it into the nodes list
+        let node_index = nodes.len();
+        nodes.push(BvhNode::new(aabb));
+
+        // if the number of primitives is less than the maximum number of primitives per node
+        // then make the node a leaf node. With the surface area heuristic such a node only
+        // becomes a leaf if splitting it further is not cheaper, which is decided below
+        let cost_driven = matches!(self.split_method, SplitMethod::SAH(_));
+
+        if num_primitives == 1 || (num_primitives <= self.max_primitives_per_node && !cost_driven) {
+            nodes[node_index].make_leaf(offset, num_primitives);
+            return;
+        }
+
+        // get the largest axis of the bounding box of the primitives
+        let bounds = nodes[node_index].bounding_box();
+        let largest_axis = bounds.largest_axis().to_usize();
+
+        if bounds.size()[largest_axis] < Float::EPSILON && num_primitives <= MAX_LEAF_PRIMITIVES {
+            nodes[node_index].make_leaf(offset, num_primitives);
+            return;
+        }
 
-            self.parent_stack.pop();
+        let by_centroid = |a: &BVHPrimitiveInfo, b: &BVHPrimitiveInfo| {
+            a.centroid()[largest_axis].total_cmp(&b.centroid()[largest_axis])
+        };
+
+        // get the split index relative to the first primitive and the axis the primitives are partitioned along
+        let split = match self.split_method {
+            SplitMethod::Middle => {
+                // sort the primitives along the largest axis
+                if parallel {
+                    primitives.par_sort_by(by_centroid);
+                } else {
+                    primitives.sort_by(by_centroid);
+                }
+
+                // get the middle index of the sorted primitives
+                Some((num_primitives / 2, largest_axis))
+            }
+            SplitMethod::EqualCounts => {
+                let split_index = num_primitives / 2;
+
+                // only place the median centroid along the largest axis at the split index,
+                // the primitives in front of it are smaller or equal and the ones after it are
+                // larger or equal, which is O(n) instead of the full sort
+                primitives.select_nth_unstable_by(split_index, by_centroid);
+
+                Some((split_index, largest_axis))
+            }
+            SplitMethod::HLBVH(_) => unreachable!("the hlbvh is built from the morton codes"),
+            SplitMethod::SAH(params) => {
+                // None if splitting is not cheaper than intersecting all the primitives
+                self.split_sah(primitives, &bounds, &params)
+            }
+        };
+
+        let (split_index, split_axis) = match split {
+            Some(split) => split,
+            None if num_primitives <= MAX_LEAF_PRIMITIVES => {
+                nodes[node_index].make_leaf(offset, num_primitives);
+                return;
+            }
+            // too many primitives for a single leaf, split them in the middle
+            None => (num_primitives / 2, largest_axis),
+        };
+
+        let (left, right) = primitives.split_at_mut(split_index);
+
+        if parallel {
+            // build both children at once, each into a node list of its own
+            let (left_nodes, right_nodes) = rayon::join(
+                || {
+                    let mut left_nodes = Vec::with_capacity(2 * left.len() - 1);
+                    self.build_recursive(left, offset, &mut left_nodes);
+                    left_nodes
+                },
+                || {
+                    let mut right_nodes = Vec::with_capacity(2 * right.len() - 1);
+                    self.build_recursive(right, offset + split_index, &mut right_nodes);
+                    right_nodes
+                },
+            );
+
+            append_subtree(nodes, left_nodes);
+
+            // the right child is stored after the subtree of the left child
+            let right_child = nodes.len();
+            nodes[node_index].make_interior(right_child, split_axis);
+
+            append_subtree(nodes, right_nodes);
+        } else {
+            // build the left child
+            self.build_recursive(left, offset, nodes);
 
             // initiate the node as the right child of the current/parent node
-            self.nodes[top].makeInterior(depth + 1, largest_axis);
+            let right_child = nodes.len();
+            nodes[node_index].make_interior(right_child, split_axis);
+
+            self.build_recursive(right, offset + split_index, nodes);
+        }
+    }
+
+    // partition the given primitives with the binned surface area heuristic. Returns the
+    // split index relative to the first primitive and the split axis, or None if the node
+    // should rather be a leaf
+    fn split_sah(&self, primitives: &mut [BVHPrimitiveInfo], bounds: &Aabb, params: &SahParams) -> Option<(usize, usize)> {
+        let num_primitives = primitives.len();
+
+        let split = SahSplit::find(primitives.iter().map(|info| (info.bounding_box(), info.centroid())), params.bins)?;
+
+        let split_cost = params.traversal_cost +
+            params.intersection_cost * split.cost / bounds.surface_area().max(Float::MIN_POSITIVE);
+        let leaf_cost = params.intersection_cost * num_primitives as Float;
+
+        // nodes with too many primitives are split even if it is not cheaper
+        if split_cost >= leaf_cost && num_primitives <= self.max_primitives_per_node {
+            return None;
+        }
+
+        // move the primitives of the bins left to the split in front
+        let split_index = partition(primitives, |info| split.is_left(info.centroid()));
+
+        Some((split_index, split.axis))
+    }
+
+    // build the bvh from the morton codes of the primitive centroids. The primitives are
+    // sorted along the morton curve and grouped into treelets by the leading bits of their
+    // codes. The treelets are built in parallel and then joined by the upper levels
+    fn build_hlbvh(&mut self, params: &HlbvhParams) -> Vec<BvhNode> {
+        if self.primitives.is_empty() {
+            return Vec::new();
+        }
 
-            depth = self.build_recursive(split_index, right_index, depth + 1);       
+        // the morton codes are relative to the bounds of all the centroids in the scene
+        let centroid_bounds = self.primitives.par_iter()
+            .map(|info| Aabb::empty().grow(&info.centroid()))
+            .reduce(Aabb::empty, |a, b| a.include(&b));
 
+        let origin = centroid_bounds.min();
+        let extent = centroid_bounds.size().max(Vec3::splat(Float::MIN_POSITIVE));
+
+        let mut morton_primitives: Vec<MortonPrimitive> = self.primitives.par_iter()
+            .enumerate()
+            .map(|(i, info)| MortonPrimitive {
+                primitive_index: i,
+                morton_code: encode_morton3((info.centroid() - origin) / extent),
+            })
+            .collect();
+
+        radix_sort(&mut morton_primitives);
+
+        // reorder the primitives along the morton curve
+        self.primitives = morton_primitives.par_iter()
+            .map(|mp| self.primitives[mp.primitive_index].clone())
+            .collect();
+
+        let morton_codes: Vec<u32> = morton_primitives.iter().map(|mp| mp.morton_code).collect();
+
+        // every treelet covers the consecutive primitives sharing the leading treelet bits
+        let prefix_shift = MORTON_BITS - params.treelet_bits.min(MORTON_BITS);
+        let mut treelet_ranges = Vec::new();
+        let mut start = 0;
+
+        for end in 1..=morton_codes.len() {
+            if end == morton_codes.len() || morton_codes[start] >> prefix_shift != morton_codes[end] >> prefix_shift {
+                treelet_ranges.push((start, end));
+                start = end;
+            }
         }
 
-        depth
+        // the treelets are independent of each other and built in parallel
+        let mut treelets: Vec<Treelet> = treelet_ranges.par_iter()
+            .map(|&(start, end)| {
+                let mut nodes = Vec::with_capacity(2 * (end - start) - 1);
+                self.emit_lbvh(&morton_codes, start, end, prefix_shift as i32 - 1, &mut nodes);
+
+                Treelet {
+                    morton_prefix: morton_codes[start] >> prefix_shift,
+                    bounds: nodes[0].bounding_box(),
+                    nodes,
+                }
+            })
+            .collect();
+
+        let num_nodes = treelets.iter().map(|treelet| treelet.nodes.len()).sum::<usize>() + treelets.len() - 1;
+        let mut nodes = Vec::with_capacity(num_nodes);
+
+        Self::build_upper_levels(&mut treelets, prefix_shift, params.upper_sah.as_ref(), &mut nodes);
+
+        nodes
     }
+
+    // emit the nodes of a linear bvh over the sorted primitives in between start and end.
+    // The primitives are split at the highest morton code bit, starting from the given bit,
+    // they disagree on
+    fn emit_lbvh(&self, morton_codes: &[u32], start: usize, end: usize, bit: i32, nodes: &mut Vec<BvhNode>) {
+        let num_primitives = end - start;
+
+        let mut bounds = Aabb::empty();
+        for info in &self.primitives[start..end] {
+            bounds.include_mut(&info.bounding_box());
+        }
+
+        let node_index = nodes.len();
+        nodes.push(BvhNode::new(bounds));
+
+        // the codes are sorted, so the highest bit any two codes disagree on
+        // is the highest bit the first and the last code disagree on
+        let mut bit = bit;
+        while bit >= 0 && (morton_codes[start] >> bit) & 1 == (morton_codes[end - 1] >> bit) & 1 {
+            bit -= 1;
+        }
+
+        if num_primitives <= self.max_primitives_per_node || (bit < 0 && num_primitives <= MAX_LEAF_PRIMITIVES) {
+            nodes[node_index].make_leaf(start, num_primitives);
+            return;
+        }
+
+        let (split_index, split_axis) = if bit < 0 {
+            // too many primitives with the same code for a single leaf, split them in the middle
+            (start + num_primitives / 2, 0)
+        } else {
+            // the first primitive with the bit set starts the right child,
+            // the bit 3 * i + axis of a morton code is the bit i along the axis
+            let split_index = start + morton_codes[start..end].partition_point(|code| (code >> bit) & 1 == 0);
+            (split_index, bit as usize % 3)
+        };
+
+        self.emit_lbvh(morton_codes, start, split_index, bit - 1, nodes);
+
+        let right_child = nodes.len();
+        nodes[node_index].make_interior(right_child, split_axis);
+
+        self.emit_lbvh(morton_codes, split_index, end, bit - 1, nodes);
+    }
+
+    // join the treelets into a single tree, either with the surface area heuristic
+    // or by the leading morton code bits of the treelets
+    fn build_upper_levels(treelets: &mut [Treelet], prefix_shift: u32, sah: Option<&SahParams>, nodes: &mut Vec<BvhNode>) {
+        if treelets.len() == 1 {
+            append_subtree(nodes, std::mem::take(&mut treelets[0].nodes));
+            return;
+        }
+
+        let mut bounds = Aabb::empty();
+
+        for treelet in treelets.iter() {
+            bounds.include_mut(&treelet.bounds);
+        }
+
+        let node_index = nodes.len();
+        nodes.push(BvhNode::new(bounds));
+
+        let sah_split = sah.and_then(|params| {
+            let split = SahSplit::find(treelets.iter().map(|treelet| (treelet.bounds.clone(), treelet.bounds.center())), params.bins)?;
+            Some((partition(treelets, |treelet| split.is_left(treelet.bounds.center())), split.axis))
+        });
+
+        // split at the highest bit the morton prefixes of the treelets disagree on,
+        // if the surface area heuristic is not used or can not separate the treelets
+        let (split_index, split_axis) = sah_split.unwrap_or_else(|| {
+            treelets.sort_by_key(|treelet| treelet.morton_prefix);
+
+            let first = treelets[0].morton_prefix;
+            let last = treelets[treelets.len() - 1].morton_prefix;
+            let bit = 31 - (first ^ last).leading_zeros();
+
+            let split_index = treelets.partition_point(|treelet| (treelet.morton_prefix >> bit) & 1 == 0);
+
+            (split_index, ((bit + prefix_shift) % 3) as usize)
+        });
+
+        Self::build_upper_levels(&mut treelets[..split_index], prefix_shift, sah, nodes);
+
+        let right_child = nodes.len();
+        nodes[node_index].make_interior(right_child, split_axis);
+
+        Self::build_upper_levels(&mut treelets[split_index..], prefix_shift, sah, nodes);
+    }
+}
+
+// append the nodes of a subtree built on its own and move its
+// right child indices to the place of the subtree in the tree
+fn append_subtree(nodes: &mut Vec<BvhNode>, subtree: Vec<BvhNode>) {
+    let offset = nodes.len() as u32;
+
+    nodes.extend(subtree.into_iter().map(|mut node| {
+        if !node.is_leaf() {
+            node.index += offset;
+        }
+        node
+    }));
+}
+
+// a subtree over the primitives sharing the leading bits of their morton codes
+struct Treelet {
+    morton_prefix: u32, // the leading morton code bits shared by the primitives
+    bounds: Aabb, // bounding box of the root of the treelet
+    nodes: Vec<BvhNode>, // nodes with right child indices relative to the treelet root
+}
+
+// the cheapest split found by the binned surface area heuristic
+struct SahSplit {
+    axis: usize, // axis the bins are laid along
+    axis_min: Float, // start of the bins along the axis
+    extent: Float, // length of the bins together along the axis
+    bins: usize, // number of bins
+    best_bin: usize, // the bins up to and including the best bin form the left side
+    cost: Float, // area weighted number of primitives on both sides of the split
+}
+
+impl SahSplit {
+    // find the cheapest split of the given (bounds, centroid) pairs.
+    // Returns None if the centroids can not be separated
+    fn find<I>(items: I, bins: usize) -> Option<SahSplit>
+    where
+        I: Iterator<Item = (Aabb, Point3)> + Clone,
+    {
+        // the bins are laid along the largest axis of the bounds of the centroids
+        let mut centroid_bounds = Aabb::empty();
+        let mut num_items = 0;
+
+        for (_, centroid) in items.clone() {
+            centroid_bounds.grow_mut(&centroid);
+            num_items += 1;
+        }
+
+        let axis = centroid_bounds.largest_axis().to_usize();
+        let extent = centroid_bounds.size()[axis];
+
+        // all the centroids coincide and no split can separate them
+        if num_items < 2 || extent < Float::EPSILON {
+            return None;
+        }
+
+        let mut split = SahSplit {
+            axis,
+            axis_min: centroid_bounds.min()[axis],
+            extent,
+            bins: bins.max(2),
+            best_bin: 0,
+            cost: Float::INFINITY,
+        };
+
+        // count the items and merge their bounds per bin
+        let mut bin_counts = vec![0usize; split.bins];
+        let mut bin_bounds = vec![Aabb::empty(); split.bins];
+
+        for (bounds, centroid) in items {
+            let bin = split.bin_of(centroid);
+            bin_counts[bin] += 1;
+            bin_bounds[bin].include_mut(&bounds);
+        }
+
+        // sweep from the right to get the area weighted count of the items
+        // on the right hand side of the split after every bin
+        let mut right_costs: Vec<Float> = vec![0.0; split.bins - 1];
+        let mut right_bounds = Aabb::empty();
+        let mut right_count = 0;
+
+        for bin in (1..split.bins).rev() {
+            right_bounds.include_mut(&bin_bounds[bin]);
+            right_count += bin_counts[bin];
+
+            if right_count > 0 {
+                right_costs[bin - 1] = right_count as Float * right_bounds.surface_area();
+            }
+        }
+
+        // sweep from the left and find the cheapest split
+        let mut left_bounds = Aabb::empty();
+        let mut left_count = 0;
+
+        for bin in 0..split.bins - 1 {
+            left_bounds.include_mut(&bin_bounds[bin]);
+            left_count += bin_counts[bin];
+
+            // a split with an empty side is no split at all
+            if left_count == 0 || left_count == num_items {
+                continue;
+            }
+
+            let cost = left_count as Float * left_bounds.surface_area() + right_costs[bin];
+
+            if cost < split.cost {
+                split.best_bin = bin;
+                split.cost = cost;
+            }
+        }
+
+        Some(split)
+    }
+
+    fn bin_of(&self, centroid: Point3) -> usize {
+        let offset = (centroid[self.axis] - self.axis_min) / self.extent;
+        ((offset * self.bins as Float) as usize).min(self.bins - 1)
+    }
+
+    // true if the centroid lies on the left hand side of the split
+    fn is_left(&self, centroid: Point3) -> bool {
+        self.bin_of(centroid) <= self.best_bin
+    }
+}
+
+// move the items satisfying the predicate in front of the others
+// and return the number of such items
+fn partition<T>(items: &mut [T], is_left: impl Fn(&T) -> bool) -> usize {
+    let mut split_index = 0;
+
+    for i in 0..items.len() {
+        if is_left(&items[i]) {
+            items.swap(i, split_index);
+            split_index += 1;
+        }
+    }
+
+    split_index
 }
 
 impl Accelerator for Bvh {
-    fn build(&mut self, world: &World) {
+    fn build(&mut self, world: World) {
 
         // find bounding box and centroids of all intersectable objects in world
-        // and store them in a vector of BVHPrimitiveInfo       
-        let primitives: Vec<_> = world.par_iter()
+        let infos: Vec<Option<BVHPrimitiveInfo>> = world.par_iter()
             .enumerate()
-            .filter_map(|(i, object)| {
-                if let Some(aabb) = object.bounding_box() {
-                    Some(Box::new(BVHPrimitiveInfo::new(i, aabb, (*object).centroid())))
-                } else {
-                    None
-                }
+            .map(|(i, object)| {
+                object.bounding_box().map(|aabb| BVHPrimitiveInfo::new(i, aabb, object.centroid()))
             })
             .collect();
 
-        self.primitives = primitives;
+        // the bounded objects are stored in a vector of BVHPrimitiveInfo, the ones
+        // without a bounding box can not be placed in the tree and are kept aside
+        self.primitives = Vec::with_capacity(infos.len());
+        self.unbounded = Vec::new();
+
+        for (i, info) in infos.into_iter().enumerate() {
+            match info {
+                Some(info) => self.primitives.push(info),
+                None => self.unbounded.push(i),
+            }
+        }
+
+        // the node indices are packed into 32 bits
+        assert!(2 * self.primitives.len() < u32::MAX as usize, "too many primitives for the bvh");
+
+        self.nodes.clear();
+
+        // there is no tree to build without any bounded primitive
+        if !self.primitives.is_empty() {
+            self.build_nodes();
+        }
+
+        // store the objects in the order of the primitives in the leaves, so that every
+        // leaf refers to a contiguous slice of the world. The unbounded objects follow them
+        let mut objects: Vec<Option<Box<dyn Hit>>> = world.into_iter().map(Some).collect();
+
+        self.world_order = self.primitives.iter()
+            .map(|info| info.primitive_index)
+            .chain(self.unbounded.iter().copied())
+            .collect();
+
+        self.world = self.world_order.iter().map(|&i| objects[i].take().unwrap()).collect();
+        self.unbounded = (self.primitives.len()..self.world.len()).collect();
+
+        // the primitive infos are only needed while building
+        self.primitives = Vec::new();
+
+        // the refitted trees are compared against the freshly built one
+        self.build_sah_cost = self.stats().sah_cost;
+    }
+
+    // the bounding box of the root covers all the objects, unless some of them have no bounding box
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.nodes.is_empty() || !self.unbounded.is_empty() {
+            return None;
+        }
+
+        Some(self.nodes[0].bounding_box())
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+
+        let mut closest_hit = intersect_unbounded(&self.world, &self.unbounded, ray, t_min, t_max);
+        let mut closest_so_far = closest_hit.as_ref().map_or(t_max, |rec| rec.t);
+
+        if self.nodes.is_empty() {
+            return closest_hit;
+        }
+
+        // the sign of the ray direction along the split axis decides
+        // which child is in front and should be visited first
+        let direction = ray.direction();
+        let dir_is_neg = [direction.x < 0.0, direction.y < 0.0, direction.z < 0.0];
+
+        // stack of the node indices still to be visited, starting at the root
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+        nodes_to_visit.push(0);
+
+        while let Some(current) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+
+            // cull the whole subtree if the ray misses the node or the node
+            // lies behind the closest intersection found so far
+            if !node.aabb.hit(ray, t_min, closest_so_far) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let leaf_primitives = &self.world[node.index()..node.index() + node.num_primitives()];
+
+                for object in leaf_primitives {
+                    if let Some(rec) = object.hit(ray, t_min, closest_so_far) {
+                        closest_so_far = rec.t;
+                        closest_hit = Some(rec);
+                    }
+                }
+            } else if dir_is_neg[node.split_axis()] {
+                // the right child is in front, push it last so that it is popped first
+                nodes_to_visit.push(current + 1);
+                nodes_to_visit.push(node.index());
+            } else {
+                // the left child placed right after the parent is in front
+                nodes_to_visit.push(node.index());
+                nodes_to_visit.push(current + 1);
+            }
+        }
+
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        if occluded_unbounded(&self.world, &self.unbounded, ray, t_min, t_max) {
+            return true;
+        }
+
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        // the range of the ray never shrinks, the first intersection found ends the traversal
+        let direction = ray.direction();
+        let dir_is_neg = [direction.x < 0.0, direction.y < 0.0, direction.z < 0.0];
+
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+        nodes_to_visit.push(0);
+
+        while let Some(current) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+
+            if !node.aabb.hit(ray, t_min, t_max) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let leaf_primitives = &self.world[node.index()..node.index() + node.num_primitives()];
+
+                if leaf_primitives.iter().any(|object| object.occluded(ray, t_min, t_max)) {
+                    return true;
+                }
+            } else if dir_is_neg[node.split_axis()] {
+                nodes_to_visit.push(current + 1);
+                nodes_to_visit.push(node.index());
+            } else {
+                nodes_to_visit.push(node.index());
+                nodes_to_visit.push(current + 1);
+            }
+        }
+
+        false
+    }
+
+    fn intersect_packet(&self, packet: &RayPacket, t_min: Float, t_max: Float) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+
+        let (mut closest_hits, mut closest_so_far) = intersect_packet_unbounded(&self.world, &self.unbounded, packet, t_min, t_max);
+
+        if self.nodes.is_empty() {
+            return closest_hits;
+        }
+
+        // the rays of a packet are coherent, so the direction of the first
+        // one decides the order the children are visited in for all of them
+        let dir_is_neg = [0, 1, 2].map(|axis| packet.direction(axis)[0] < 0.0);
+
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+        nodes_to_visit.push(0);
+
+        while let Some(current) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+
+            // the subtree is culled only if every ray of the packet misses the node,
+            // the rays that miss it are masked out while visiting the leaves
+            let (active, _) = packet.hit_aabb(&node.aabb, t_min, closest_so_far);
+
+            if active == 0 {
+                continue;
+            }
+
+            if node.is_leaf() {
+                for object in &self.world[node.index()..node.index() + node.num_primitives()] {
+                    let hits = object.hit_packet(packet, t_min, closest_so_far, active);
+                    merge_packet_hits(&mut closest_hits, &mut closest_so_far, hits);
+                }
+            } else if dir_is_neg[node.split_axis()] {
+                nodes_to_visit.push(current + 1);
+                nodes_to_visit.push(node.index());
+            } else {
+                nodes_to_visit.push(node.index());
+                nodes_to_visit.push(current + 1);
+            }
+        }
+
+        closest_hits
+    }
+
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::tests::assert_matches_world;
+    use crate::hit::Hit;
+    use crate::material::Lambertian;
+    use crate::primitives::sphere::Sphere;
+    use crate::primitives::triangle::Triangle;
+    use crate::utils::util::{Color, Vec3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // a deterministic scene of small spheres scattered in a cube
+    pub(crate) fn sphere_world(count: usize) -> World {
+        random_sphere_world(count, 42)
+    }
+
+    fn random_sphere_world(count: usize, seed: u64) -> World {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut world = World::with_capacity(count);
+
+        for _ in 0..count {
+            let center = Point3::new(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            );
+            let radius = rng.gen_range(0.1..0.8);
+            world.push(Box::new(Sphere::new(center, radius, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+        }
+
+        world
+    }
+
+    // an infinite plane through the origin facing up, it has no bounding box
+    struct Plane {
+        mat: Lambertian,
+    }
+
+    impl Hit for Plane {
+        fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+            let t = -r.origin().y / r.direction().y;
+
+            if !t.is_finite() || t < t_min || t > t_max {
+                return None;
+            }
+
+            Some(HitRecord::new(r, t, Vec3::new(0.0, 1.0, 0.0), &self.mat))
+        }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            None
+        }
+
+        fn centroid(&self) -> Point3 {
+            Point3::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    fn plane() -> Box<Plane> {
+        Box::new(Plane { mat: Lambertian::new(Color::new(0.5, 0.5, 0.5)) })
+    }
+
+    fn split_methods() -> Vec<SplitMethod> {
+        vec![
+            SplitMethod::Middle,
+            SplitMethod::EqualCounts,
+            SplitMethod::SAH(SahParams::default()),
+            SplitMethod::HLBVH(HlbvhParams::default()),
+        ]
+    }
+
+    #[test]
+    fn intersect_before_build() {
+        let bvh = Bvh::new(None, None);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(bvh.intersect(&ray, 0.001, Float::INFINITY).is_none());
+    }
+
+    #[test]
+    fn intersect_single_sphere() {
+        let world: World = vec![
+            Box::new(Sphere::new(Point3::new(0.0, 0.0, -5.0), 1.0, Lambertian::new(Color::new(0.5, 0.5, 0.5)))),
+        ];
+
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(world);
+
+        let hit_ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = bvh.intersect(&hit_ray, 0.001, Float::INFINITY).unwrap();
+        assert!((rec.t - 4.0).abs() < 0.0001);
+
+        let miss_ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!(bvh.intersect(&miss_ray, 0.001, Float::INFINITY).is_none());
+
+        // the sphere lies beyond t_max
+        assert!(bvh.intersect(&hit_ray, 0.001, 3.0).is_none());
+    }
+
+    #[test]
+    fn intersect_matches_linear_world() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::Middle));
+        bvh.build(sphere_world(500));
+
+        assert_matches_world(&bvh, &sphere_world(500));
+    }
+
+    #[test]
+    fn sah_intersect_matches_linear_world() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        bvh.build(sphere_world(500));
+
+        assert_matches_world(&bvh, &sphere_world(500));
+    }
+
+    #[test]
+    fn equal_counts_intersect_matches_linear_world() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::EqualCounts));
+        bvh.build(sphere_world(500));
+
+        assert_matches_world(&bvh, &sphere_world(500));
+    }
+
+    #[test]
+    fn equal_counts_splits_at_median() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::EqualCounts));
+        bvh.build(sphere_world(101));
+
+        let root = &bvh.nodes[0];
+        assert!(!root.is_leaf());
+
+        // every centroid on the left of the median is not larger than the ones on the right
+        let axis = root.split_axis();
+        let left_max = bvh.world[..50].iter().map(|p| p.centroid()[axis]).fold(Float::NEG_INFINITY, Float::max);
+        let right_min = bvh.world[50..].iter().map(|p| p.centroid()[axis]).fold(Float::INFINITY, Float::min);
+        assert!(left_max <= right_min);
+    }
+
+    #[test]
+    fn hlbvh_intersect_matches_linear_world() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::HLBVH(HlbvhParams::default())));
+        bvh.build(sphere_world(500));
+
+        assert_matches_world(&bvh, &sphere_world(500));
+    }
+
+    #[test]
+    fn hlbvh_morton_upper_levels_match_linear_world() {
+        let params = HlbvhParams {
+            treelet_bits: 6,
+            upper_sah: None,
+        };
+
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::HLBVH(params)));
+        bvh.build(sphere_world(500));
+
+        assert_matches_world(&bvh, &sphere_world(500));
+    }
+
+    #[test]
+    fn hlbvh_tree_is_complete() {
+        let mut bvh = Bvh::new(Some(1), Some(SplitMethod::HLBVH(HlbvhParams::default())));
+        bvh.build(sphere_world(300));
+
+        // a binary tree with a single primitive per leaf has 2n - 1 nodes
+        assert_eq!(bvh.nodes.len(), 2 * 300 - 1);
+
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            if node.is_leaf() {
+                assert_eq!(node.num_primitives(), 1);
+                assert!(node.index() < 300);
+            } else {
+                // the right child is stored after the left subtree
+                assert!(node.index() > i + 1 && node.index() < bvh.nodes.len());
+            }
+        }
+
+        assert_eq!(bvh.validate(), Ok(()));
+    }
+
+    #[test]
+    fn sah_separates_clusters() {
+        let mut world = World::new();
+
+        // two clusters of spheres far apart along the x axis
+        for i in 0..8 {
+            let offset = i as Float * 0.5;
+            world.push(Box::new(Sphere::new(Point3::new(-50.0 + offset, 0.0, 0.0), 0.2, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+            world.push(Box::new(Sphere::new(Point3::new(50.0 + offset, 0.0, 0.0), 0.2, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+        }
+
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        bvh.build(world);
+
+        let root = &bvh.nodes[0];
+        assert!(!root.is_leaf());
+        assert_eq!(root.split_axis(), 0);
+
+        // the children of the root hold one cluster each
+        let left = &bvh.nodes[1];
+        let right = &bvh.nodes[root.index()];
+        assert!(left.bounding_box().max().x < 0.0);
+        assert!(right.bounding_box().min().x > 0.0);
+    }
+
+    #[test]
+    fn sah_keeps_leaf_when_split_is_not_cheaper() {
+        // heavily overlapping spheres gain nothing from being split
+        let mut world = World::new();
+        for i in 0..4 {
+            let offset = i as Float * 0.01;
+            world.push(Box::new(Sphere::new(Point3::new(offset, 0.0, 0.0), 1.0, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+        }
+
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        bvh.build(world);
+
+        assert_eq!(bvh.nodes.len(), 1);
+        assert!(bvh.nodes[0].is_leaf());
+        assert_eq!(bvh.nodes[0].num_primitives(), 4);
+    }
+
+    #[test]
+    fn build_empty_world() {
+        for split_method in split_methods() {
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(World::new());
+
+            assert!(bvh.nodes.is_empty());
+            assert_eq!(bvh.validate(), Ok(()));
+
+            let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+            assert!(bvh.intersect(&ray, 0.001, Float::INFINITY).is_none());
+        }
+    }
+
+    #[test]
+    fn build_single_primitive() {
+        for split_method in split_methods() {
+            let mut bvh = Bvh::new(Some(0), Some(split_method));
+            bvh.build(sphere_world(1));
 
-        // store the primitives in the bvh        
-        self.nodes.resize(2 * self.primitives.len() - 1, Box::new(BvhNode::new(Aabb::empty())));
+            assert_eq!(bvh.nodes.len(), 1);
+            assert!(bvh.nodes[0].is_leaf());
+            assert_eq!(bvh.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn build_coincident_primitives() {
+        for split_method in split_methods() {
+            let world: World = (0..10)
+                .map(|_| Box::new(Sphere::new(Point3::new(1.0, 2.0, 3.0), 0.5, Lambertian::new(Color::new(0.5, 0.5, 0.5)))) as Box<dyn Hit>)
+                .collect();
+
+            let mut bvh = Bvh::new(Some(2), Some(split_method));
+            bvh.build(world);
+
+            assert_eq!(bvh.validate(), Ok(()));
+
+            let ray = Ray::new(Point3::new(1.0, 2.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+            let rec = bvh.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+            assert!((rec.t - 6.5).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn build_unbounded_world() {
+        let world: World = vec![plane()];
+
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(world);
+
+        assert!(bvh.nodes.is_empty());
+        assert_eq!(bvh.unbounded, vec![0]);
+        assert_eq!(bvh.validate(), Ok(()));
+
+        let ray = Ray::new(Point3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let rec = bvh.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+        assert!((rec.t - 5.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn unbounded_primitives_match_linear_world() {
+        for split_method in split_methods() {
+            let mut world = sphere_world(200);
+            world.insert(50, plane());
+
+            let mut expected = sphere_world(200);
+            expected.insert(50, plane());
 
-        // build the bvh tree recursively
-        let depth = self.build_recursive(0, self.primitives.len(), 0);
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(world);
 
-        println!("BVH node size before cleanup: {}", self.nodes.len());
+            // the unbounded objects are moved behind the ones in the leaves
+            assert_eq!(bvh.unbounded, vec![200]);
+            assert_eq!(bvh.validate(), Ok(()));
+            assert_matches_world(&bvh, &expected);
+        }
+    }
 
-        // remove empty nodes
-        self.nodes.retain(|node| !node.aabb.is_empty());
+    // a deterministic scene of small triangles scattered in a cube
+    pub(crate) fn triangle_world(count: usize) -> World {
+        let mut rng = StdRng::seed_from_u64(43);
 
-        println!("BVH node size after cleanup: {}", self.nodes.len());
+        (0..count)
+            .map(|_| {
+                let v0 = Point3::new(rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0));
+                let v1 = v0 + Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                let v2 = v0 + Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                Box::new(Triangle::new([v0, v1, v2], Lambertian::new(Color::new(0.5, 0.5, 0.5)))) as Box<dyn Hit>
+            })
+            .collect()
     }
 
-    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        // Implement the intersect method here.
-        // Add your code here.
-        None
+    // check the packets of coherent rays against the same rays traced one by one
+    pub(crate) fn assert_packets_match_single_rays<A: Accelerator>(accelerator: &A) {
+        let mut rng = StdRng::seed_from_u64(9);
+
+        for _ in 0..500 {
+            let origin = Point3::new(rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0), 15.0);
+            let target = Point3::new(rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0), 0.0);
+
+            let rays: [Ray; PACKET_SIZE] = std::array::from_fn(|_| {
+                let jitter = Vec3::new(rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5), 0.0);
+                Ray::new(origin, target + jitter - origin)
+            });
+            let packet = RayPacket::new(rays);
+
+            let hits = accelerator.intersect_packet(&packet, 0.001, Float::INFINITY);
+
+            for (lane, hit) in hits.iter().enumerate() {
+                match (accelerator.intersect(packet.ray(lane), 0.001, Float::INFINITY), hit) {
+                    (Some(expected), Some(actual)) => assert!((expected.t - actual.t).abs() < 0.0001),
+                    (None, None) => {}
+                    _ => panic!("packet and single ray disagree on a hit"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn intersect_packet_matches_intersect() {
+        for split_method in split_methods() {
+            let mut world = triangle_world(300);
+            world.extend(sphere_world(100));
+            world.push(plane());
+
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(world);
+
+            assert_packets_match_single_rays(&bvh);
+        }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn parallel_build_matches_linear_world() {
+        // enough primitives for the top levels to be built in parallel
+        let count = 4 * PARALLEL_BUILD_THRESHOLD;
+
+        for split_method in split_methods() {
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(sphere_world(count));
+
+            assert_eq!(bvh.validate(), Ok(()));
+            assert_matches_world(&bvh, &sphere_world(count));
+        }
+    }
+
+    // the spheres of sphere_world moved by the given offset
+    fn moved_sphere_world(count: usize, offset: Vec3) -> World {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        (0..count)
+            .map(|_| {
+                let center = Point3::new(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                );
+                let radius = rng.gen_range(0.1..0.8);
+                Box::new(Sphere::new(center + offset, radius, Lambertian::new(Color::new(0.5, 0.5, 0.5)))) as Box<dyn Hit>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn refit_keeps_topology_of_moved_objects() {
+        for split_method in split_methods() {
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(sphere_world(300));
+
+            let offset = Vec3::new(1.0, -2.0, 0.5);
+            let num_nodes = bvh.nodes.len();
+
+            // moving all the objects alike does not degrade the tree
+            assert!(!bvh.refit(moved_sphere_world(300, offset)));
+            assert_eq!(bvh.nodes.len(), num_nodes);
+            assert_eq!(bvh.validate(), Ok(()));
+            assert!((bvh.nodes[0].bounding_box().min() - Hit::bounding_box(&bvh.world).unwrap().min()).length() < 0.0001);
+
+            assert_matches_world(&bvh, &moved_sphere_world(300, offset));
+        }
+    }
+
+    #[test]
+    fn refit_rebuilds_degraded_tree() {
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        bvh.build(sphere_world(300));
+
+        // scattering the objects all over the scene leaves the old topology useless
+        assert!(bvh.refit(random_sphere_world(300, 1)));
+        assert_eq!(bvh.validate(), Ok(()));
+        assert_matches_world(&bvh, &random_sphere_world(300, 1));
+
+        // without a threshold the degraded tree is kept and still correct
+        let mut bvh = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        bvh.set_rebuild_threshold(Float::INFINITY);
+        bvh.build(sphere_world(300));
+
+        assert!(!bvh.refit(random_sphere_world(300, 1)));
+        assert_eq!(bvh.validate(), Ok(()));
+        assert_matches_world(&bvh, &random_sphere_world(300, 1));
+    }
+
+    #[test]
+    fn refit_rebuilds_changed_world() {
+        let mut bvh = Bvh::new(Some(4), None);
+        bvh.build(sphere_world(300));
+
+        // a different number of objects
+        assert!(bvh.refit(sphere_world(200)));
+        assert_eq!(bvh.validate(), Ok(()));
+        assert_matches_world(&bvh, &sphere_world(200));
+
+        // an object that lost its bounding box
+        let mut world = sphere_world(199);
+        world.insert(20, plane());
+
+        let mut expected = sphere_world(199);
+        expected.insert(20, plane());
+
+        assert!(bvh.refit(world));
+        assert_eq!(bvh.unbounded, vec![199]);
+        assert_eq!(bvh.validate(), Ok(()));
+        assert_matches_world(&bvh, &expected);
+    }
+
+    #[test]
+    fn validate_split_methods() {
+        for split_method in split_methods() {
+            for max_primitives_per_node in [1, 4, 16] {
+                let mut bvh = Bvh::new(Some(max_primitives_per_node), Some(split_method));
+                bvh.build(sphere_world(333));
+
+                assert_eq!(bvh.validate(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn validate_detects_broken_trees() {
+        let mut bvh = Bvh::new(Some(1), None);
+        bvh.build(sphere_world(16));
+        assert_eq!(bvh.validate(), Ok(()));
+
+        // a right child index past the end of the node list
+        let mut broken = Bvh::new(Some(1), None);
+        broken.build(sphere_world(16));
+        broken.nodes[0].index = broken.nodes.len() as u32;
+        assert_eq!(broken.validate(), Err(BvhError::ChildOutOfRange { node: 0, child: broken.nodes.len() }));
+
+        // a child sticking out of its parent
+        let mut broken = Bvh::new(Some(1), None);
+        broken.build(sphere_world(16));
+        broken.nodes[1].aabb = broken.nodes[1].aabb.grow(&Point3::new(100.0, 100.0, 100.0));
+        assert_eq!(broken.validate(), Err(BvhError::ChildOutsideParent { node: 0, child: 1 }));
+
+        // a primitive referenced twice and another one not at all
+        let mut broken = Bvh::new(Some(1), None);
+        broken.build(sphere_world(16));
+        let leaves: Vec<usize> = (0..broken.nodes.len()).filter(|&i| broken.nodes[i].is_leaf()).collect();
+        broken.nodes[leaves[1]].index = broken.nodes[leaves[0]].index;
+        assert!(matches!(broken.validate(), Err(BvhError::UnreachablePrimitive(_)) | Err(BvhError::DuplicatePrimitive(_))));
+    }
+
+    #[test]
+    #[cfg(not(feature = "f64"))]
+    fn node_fits_in_32_bytes() {
+        assert_eq!(std::mem::size_of::<BvhNode>(), 32);
+        assert_eq!(std::mem::align_of::<BvhNode>(), 32);
+    }
+
+    #[test]
+    #[cfg(feature = "f64")]
+    fn node_fits_in_64_bytes() {
+        assert_eq!(std::mem::size_of::<BvhNode>(), 64);
+        assert_eq!(std::mem::align_of::<BvhNode>(), 32);
+    }
+
+    #[test]
+    fn leaves_reference_contiguous_world_slices() {
+        for split_method in split_methods() {
+            let mut bvh = Bvh::new(Some(4), Some(split_method));
+            bvh.build(sphere_world(200));
+
+            // every object of a leaf lies inside the bounding box of the leaf
+            for node in bvh.nodes.iter().filter(|node| node.is_leaf()) {
+                for object in &bvh.world[node.index()..node.index() + node.num_primitives()] {
+                    let aabb = object.bounding_box().unwrap();
+                    assert!(node.bounding_box().approx_contains_aabb_eps(&aabb, Float::EPSILON));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stats_of_empty_bvh() {
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(vec![plane()]);
+
+        let stats = bvh.stats();
+        assert_eq!(stats.node_count, 0);
+        assert_eq!(stats.leaf_count, 0);
+        assert_eq!(stats.unbounded_count, 1);
+        assert_eq!(stats.sah_cost, 0.0);
+    }
+
+    #[test]
+    fn stats_of_complete_tree() {
+        // 8 primitives with a single primitive per leaf form a complete tree of depth 3
+        let mut bvh = Bvh::new(Some(1), Some(SplitMethod::EqualCounts));
+        bvh.build(sphere_world(8));
+
+        let stats = bvh.stats();
+        assert_eq!(stats.node_count, 15);
+        assert_eq!(stats.leaf_count, 8);
+        assert_eq!(stats.max_depth, 3);
+        assert!((stats.average_depth - 3.0).abs() < 0.0001);
+        assert!((stats.average_primitives_per_leaf - 1.0).abs() < 0.0001);
+        assert_eq!(stats.leaf_size_histogram, vec![0, 8]);
+
+        // the root alone costs a traversal, the rest is weighted by the surface areas
+        assert!(stats.sah_cost > SahParams::default().traversal_cost);
+    }
+
+    #[test]
+    fn stats_compare_split_methods() {
+        let mut middle = Bvh::new(Some(4), Some(SplitMethod::Middle));
+        middle.build(sphere_world(1000));
+
+        let mut sah = Bvh::new(Some(4), Some(SplitMethod::SAH(SahParams::default())));
+        sah.build(sphere_world(1000));
+
+        let middle_stats = middle.stats();
+        let sah_stats = sah.stats();
+
+        assert_eq!(middle_stats.leaf_size_histogram.iter().enumerate().map(|(size, count)| size * count).sum::<usize>(), 1000);
+        assert_eq!(sah_stats.leaf_size_histogram.iter().enumerate().map(|(size, count)| size * count).sum::<usize>(), 1000);
+
+        // the surface area heuristic optimizes the very cost it is measured by
+        assert!(sah_stats.sah_cost <= middle_stats.sah_cost);
+    }
+}
@@ -0,0 +1,674 @@
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
+use crate::utils::aabb::Aabb;
+use crate::hit::{merge_packet_hits, HitRecord, World};
+use crate::accelerators::accelerator::{Accelerator, intersect_unbounded, occluded_unbounded, intersect_packet_unbounded};
+use crate::accelerators::bvh::{Bvh, BvhNode, SplitMethod};
+use crate::utils::stack::Stack;
+use crate::utils::util::{Float, Vec3, Vec4};
+
+// number of children of a wide node
+pub const WIDTH: usize = 4;
+
+// marks a child slot of a node that holds no child
+const EMPTY_CHILD: u32 = u32::MAX;
+
+/*
+    A node of the four wide bvh. The bounding boxes of the children are stored in
+    structure of arrays layout, so that a ray is tested against all four of them
+    at once with glam's SIMD vectors, SSE on x86_64 and plain scalar code on targets
+    without SIMD support.
+    A child is either another wide node or a leaf, which refers to a contiguous
+    slice of the world just like the leaves of the binary bvh.
+*/
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct Bvh4Node {
+    min_x: Vec4,
+    min_y: Vec4,
+    min_z: Vec4,
+    max_x: Vec4,
+    max_y: Vec4,
+    max_z: Vec4,
+    // if leaf child, index into the first primitive of the leaf
+    // if interior child, index into the child in the node list
+    children: [u32; WIDTH],
+    // number of primitives in a leaf child, 0 for interior children
+    primitives_per_child: [u16; WIDTH],
+}
+
+impl Bvh4Node {
+    // creates a node without children, the boxes of the empty
+    // slots are inverted so that every ray misses them
+    pub fn new() -> Self {
+        Self {
+            min_x: Vec4::INFINITY,
+            min_y: Vec4::INFINITY,
+            min_z: Vec4::INFINITY,
+            max_x: Vec4::NEG_INFINITY,
+            max_y: Vec4::NEG_INFINITY,
+            max_z: Vec4::NEG_INFINITY,
+            children: [EMPTY_CHILD; WIDTH],
+            primitives_per_child: [0; WIDTH],
+        }
+    }
+
+    fn set_bounding_box(&mut self, slot: usize, aabb: &Aabb) {
+        let (min, max) = (aabb.min(), aabb.max());
+
+        self.min_x[slot] = min.x;
+        self.min_y[slot] = min.y;
+        self.min_z[slot] = min.z;
+        self.max_x[slot] = max.x;
+        self.max_y[slot] = max.y;
+        self.max_z[slot] = max.z;
+    }
+
+    pub fn make_interior(&mut self, slot: usize, aabb: &Aabb, index: usize) {
+        self.set_bounding_box(slot, aabb);
+        self.children[slot] = index as u32;
+        self.primitives_per_child[slot] = 0;
+    }
+
+    pub fn make_leaf(&mut self, slot: usize, aabb: &Aabb, index: usize, num_primitives: usize) {
+        debug_assert!(num_primitives > 0 && num_primitives <= u16::MAX as usize);
+
+        self.set_bounding_box(slot, aabb);
+        self.children[slot] = index as u32;
+        self.primitives_per_child[slot] = num_primitives as u16;
+    }
+
+    pub fn is_empty(&self, slot: usize) -> bool {
+        self.children[slot] == EMPTY_CHILD
+    }
+
+    pub fn is_leaf(&self, slot: usize) -> bool {
+        self.primitives_per_child[slot] > 0
+    }
+
+    // get the AABB of the child in the given slot
+    pub fn bounding_box(&self, slot: usize) -> Aabb {
+        Aabb::new(
+            Vec3::new(self.min_x[slot], self.min_y[slot], self.min_z[slot]),
+            Vec3::new(self.max_x[slot], self.max_y[slot], self.max_z[slot]),
+        )
+    }
+
+    // index of the first primitive of a leaf or of the node of an interior child
+    pub fn index(&self, slot: usize) -> usize {
+        self.children[slot] as usize
+    }
+
+    pub fn num_primitives(&self, slot: usize) -> usize {
+        self.primitives_per_child[slot] as usize
+    }
+
+    // intersect the ray with the boxes of all four children at once. Returns a bit
+    // mask of the children that are hit in between t_min and t_max together with the
+    // distances at which the ray enters their boxes
+    pub fn hit(&self, ray: &Ray4, t_min: Float, t_max: Float) -> (u32, Vec4) {
+        // pick the near and far planes of every axis by the sign of the direction,
+        // which saves the per axis swap of the scalar slab test
+        let (near_x, far_x) = if ray.dir_is_neg[0] { (self.max_x, self.min_x) } else { (self.min_x, self.max_x) };
+        let (near_y, far_y) = if ray.dir_is_neg[1] { (self.max_y, self.min_y) } else { (self.min_y, self.max_y) };
+        let (near_z, far_z) = if ray.dir_is_neg[2] { (self.max_z, self.min_z) } else { (self.min_z, self.max_z) };
+
+        let t_near = ((near_x - ray.origin_x) * ray.inv_dir_x)
+            .max((near_y - ray.origin_y) * ray.inv_dir_y)
+            .max((near_z - ray.origin_z) * ray.inv_dir_z)
+            .max(Vec4::splat(t_min));
+
+        let t_far = ((far_x - ray.origin_x) * ray.inv_dir_x)
+            .min((far_y - ray.origin_y) * ray.inv_dir_y)
+            .min((far_z - ray.origin_z) * ray.inv_dir_z)
+            .min(Vec4::splat(t_max));
+
+        (t_near.cmple(t_far).bitmask(), t_near)
+    }
+
+    // intersect the rays of a packet with the boxes of all four children, every ray
+    // against the four boxes at once. Returns for every child the bit mask of the rays
+    // hitting it in between t_min and their own t_max, together with the distances at
+    // which the first of these rays enter the boxes
+    pub fn hit_packet(&self, rays: &[Ray4; PACKET_SIZE], t_min: Float, t_max: Vec4) -> ([u32; WIDTH], Vec4) {
+        let mut active = [0; WIDTH];
+        let mut t_enter = Vec4::INFINITY;
+
+        for (lane, ray) in rays.iter().enumerate() {
+            let (mask, t_near) = self.hit(ray, t_min, t_max[lane]);
+
+            for (slot, rays_hitting) in active.iter_mut().enumerate() {
+                if mask & (1 << slot) != 0 {
+                    *rays_hitting |= 1 << lane;
+                    t_enter[slot] = t_enter[slot].min(t_near[slot]);
+                }
+            }
+        }
+
+        (active, t_enter)
+    }
+}
+
+impl Default for Bvh4Node {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+    The ray broadcast into every lane of the SIMD vectors. The inverse direction
+    is computed once per ray instead of once per box and axis.
+*/
+pub struct Ray4 {
+    origin_x: Vec4,
+    origin_y: Vec4,
+    origin_z: Vec4,
+    inv_dir_x: Vec4,
+    inv_dir_y: Vec4,
+    inv_dir_z: Vec4,
+    dir_is_neg: [bool; 3],
+}
+
+impl Ray4 {
+    pub fn new(ray: &Ray) -> Self {
+        let origin = ray.origin();
+        let inv_dir = ray.direction().recip();
+
+        Self {
+            origin_x: Vec4::splat(origin.x),
+            origin_y: Vec4::splat(origin.y),
+            origin_z: Vec4::splat(origin.z),
+            inv_dir_x: Vec4::splat(inv_dir.x),
+            inv_dir_y: Vec4::splat(inv_dir.y),
+            inv_dir_z: Vec4::splat(inv_dir.z),
+            // the sign is taken from the inverse, as a negative zero component has a
+            // negative infinite inverse which swaps the near and far planes all the same
+            dir_is_neg: [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0],
+        }
+    }
+}
+
+/*
+    A four wide bvh collapsed from the binary bvh. Every wide node takes the place
+    of up to three levels of the binary tree, which cuts the number of nodes visited
+    by a ray and tests the child boxes in a single SIMD pass.
+*/
+pub struct Bvh4 {
+    world: World, // the intersectable objects in the order of the leaves
+    unbounded: Vec<usize>, // indices of the objects without a bounding box
+    nodes: Vec<Bvh4Node>,
+    max_primitives_per_node: Option<usize>,
+    split_method: Option<SplitMethod>,
+}
+
+impl Bvh4 {
+    // the parameters are handed to the binary bvh the wide one is collapsed from
+    pub fn new(max_primitives_per_node: Option<usize>,
+               split_method: Option<SplitMethod>) -> Self {
+        Self {
+            world: World::new(),
+            unbounded: Vec::new(),
+            nodes: Vec::new(),
+            max_primitives_per_node,
+            split_method,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // collapse the subtree of the binary bvh below the given node into a wide node
+    // and return the index of the new node in the node list
+    fn collapse(&mut self, binary_nodes: &[BvhNode], binary_index: usize) -> usize {
+        let binary_node = &binary_nodes[binary_index];
+
+        // the children of the binary node become the children of the wide node. A leaf
+        // as root of the binary tree is the only child of the root of the wide tree
+        let mut slots = if binary_node.is_leaf() {
+            vec![binary_index]
+        } else {
+            vec![binary_index + 1, binary_node.index()]
+        };
+
+        // pull the children of the interior slot with the largest surface
+        // area up into the wide node until all the slots are taken
+        while slots.len() < WIDTH {
+            let largest = slots.iter()
+                .enumerate()
+                .filter(|(_, &slot)| !binary_nodes[slot].is_leaf())
+                .max_by(|(_, &a), (_, &b)| {
+                    binary_nodes[a].bounding_box().surface_area()
+                        .total_cmp(&binary_nodes[b].bounding_box().surface_area())
+                })
+                .map(|(i, _)| i);
+
+            match largest {
+                Some(i) => {
+                    let expanded = slots[i];
+                    slots[i] = expanded + 1;
+                    slots.push(binary_nodes[expanded].index());
+                }
+                None => break,
+            }
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(Bvh4Node::new());
+
+        for (slot, &child) in slots.iter().enumerate() {
+            let child_node = &binary_nodes[child];
+            let aabb = child_node.bounding_box();
+
+            if child_node.is_leaf() {
+                self.nodes[node_index].make_leaf(slot, &aabb, child_node.index(), child_node.num_primitives());
+            } else {
+                let child_index = self.collapse(binary_nodes, child);
+                self.nodes[node_index].make_interior(slot, &aabb, child_index);
+            }
+        }
+
+        node_index
+    }
+}
+
+impl From<Bvh> for Bvh4 {
+    // collapse a built binary bvh into a wide one, which takes over the world
+    fn from(bvh: Bvh) -> Self {
+        let (world, unbounded, binary_nodes) = bvh.into_parts();
+
+        let mut bvh4 = Bvh4::new(None, None);
+        bvh4.world = world;
+        bvh4.unbounded = unbounded;
+
+        if !binary_nodes.is_empty() {
+            bvh4.collapse(&binary_nodes, 0);
+        }
+
+        bvh4
+    }
+}
+
+impl Accelerator for Bvh4 {
+    fn build(&mut self, world: World) {
+        let mut bvh = Bvh::new(self.max_primitives_per_node, self.split_method);
+        bvh.build(world);
+
+        let bvh4 = Bvh4::from(bvh);
+
+        self.world = bvh4.world;
+        self.unbounded = bvh4.unbounded;
+        self.nodes = bvh4.nodes;
+    }
+
+    // the boxes of the children of the root cover all the objects, unless some of them have no bounding box
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.nodes.is_empty() || !self.unbounded.is_empty() {
+            return None;
+        }
+
+        let root = &self.nodes[0];
+
+        (0..WIDTH)
+            .filter(|&slot| !root.is_empty(slot))
+            .map(|slot| root.bounding_box(slot))
+            .reduce(|a, b| a.include(&b))
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+
+        let mut closest_hit = intersect_unbounded(&self.world, &self.unbounded, ray, t_min, t_max);
+        let mut closest_so_far = closest_hit.as_ref().map_or(t_max, |rec| rec.t);
+
+        if self.nodes.is_empty() {
+            return closest_hit;
+        }
+
+        let ray4 = Ray4::new(ray);
+
+        // stack of the node indices still to be visited together with the
+        // distance at which the ray enters them, starting at the root
+        let mut nodes_to_visit: Stack<(usize, Float)> = Stack::new();
+        nodes_to_visit.push((0, t_min));
+
+        while let Some((current, t_enter)) = nodes_to_visit.pop() {
+            // the node lies behind the closest intersection found since it was pushed
+            if t_enter > closest_so_far {
+                continue;
+            }
+
+            let node = &self.nodes[current];
+            let (mut mask, t_near) = node.hit(&ray4, t_min, closest_so_far);
+
+            // order the children that are hit from far to near
+            let mut hits: [(usize, Float); WIDTH] = [(0, 0.0); WIDTH];
+            let mut num_hits = 0;
+
+            while mask != 0 {
+                let slot = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+
+                hits[num_hits] = (slot, t_near[slot]);
+                num_hits += 1;
+            }
+
+            let hits = &mut hits[..num_hits];
+            hits.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+
+            // push the interior children far to near so that the nearest is popped first,
+            // the leaves are intersected right away to shrink the ray as early as possible
+            for &(slot, t) in hits.iter() {
+                if !node.is_leaf(slot) {
+                    nodes_to_visit.push((node.index(slot), t));
+                }
+            }
+
+            for &(slot, t) in hits.iter().rev() {
+                if !node.is_leaf(slot) || t > closest_so_far {
+                    continue;
+                }
+
+                let leaf_primitives = &self.world[node.index(slot)..node.index(slot) + node.num_primitives(slot)];
+
+                for object in leaf_primitives {
+                    if let Some(rec) = object.hit(ray, t_min, closest_so_far) {
+                        closest_so_far = rec.t;
+                        closest_hit = Some(rec);
+                    }
+                }
+            }
+        }
+
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        if occluded_unbounded(&self.world, &self.unbounded, ray, t_min, t_max) {
+            return true;
+        }
+
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let ray4 = Ray4::new(ray);
+
+        // any intersection ends the traversal, so the children are visited in no particular order
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+        nodes_to_visit.push(0);
+
+        while let Some(current) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+            let (mut mask, _) = node.hit(&ray4, t_min, t_max);
+
+            while mask != 0 {
+                let slot = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+
+                if !node.is_leaf(slot) {
+                    nodes_to_visit.push(node.index(slot));
+                    continue;
+                }
+
+                let leaf_primitives = &self.world[node.index(slot)..node.index(slot) + node.num_primitives(slot)];
+
+                if leaf_primitives.iter().any(|object| object.occluded(ray, t_min, t_max)) {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    fn intersect_packet(&self, packet: &RayPacket, t_min: Float, t_max: Float) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+
+        let (mut closest_hits, mut closest_so_far) = intersect_packet_unbounded(&self.world, &self.unbounded, packet, t_min, t_max);
+
+        if self.nodes.is_empty() {
+            return closest_hits;
+        }
+
+        let rays: [Ray4; PACKET_SIZE] = std::array::from_fn(|lane| Ray4::new(packet.ray(lane)));
+
+        let mut nodes_to_visit: Stack<usize> = Stack::new();
+        nodes_to_visit.push(0);
+
+        while let Some(current) = nodes_to_visit.pop() {
+            let node = &self.nodes[current];
+
+            let (active, t_enter) = node.hit_packet(&rays, t_min, closest_so_far);
+
+            // the children hit by at least one ray of the packet, with the masks of the
+            // rays hitting them and the distance at which the first of those enters them
+            let mut hits: [(usize, u32, Float); WIDTH] = [(0, 0, 0.0); WIDTH];
+            let mut num_hits = 0;
+
+            for slot in (0..WIDTH).filter(|&slot| active[slot] != 0) {
+                hits[num_hits] = (slot, active[slot], t_enter[slot]);
+                num_hits += 1;
+            }
+
+            let hits = &mut hits[..num_hits];
+            hits.sort_unstable_by(|a, b| b.2.total_cmp(&a.2));
+
+            // push the interior children far to near, intersect the leaves near to far
+            for &(slot, _, _) in hits.iter() {
+                if !node.is_leaf(slot) {
+                    nodes_to_visit.push(node.index(slot));
+                }
+            }
+
+            for &(slot, active, _) in hits.iter().rev() {
+                if !node.is_leaf(slot) {
+                    continue;
+                }
+
+                for object in &self.world[node.index(slot)..node.index(slot) + node.num_primitives(slot)] {
+                    let hits = object.hit_packet(packet, t_min, closest_so_far, active);
+                    merge_packet_hits(&mut closest_hits, &mut closest_so_far, hits);
+                }
+            }
+        }
+
+        closest_hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::tests::assert_matches_world;
+    use crate::accelerators::bvh::{HlbvhParams, SahParams};
+    use crate::accelerators::bvh::tests::{assert_packets_match_single_rays, sphere_world, triangle_world};
+    use crate::hit::Hit;
+    use crate::material::Lambertian;
+    use crate::primitives::sphere::Sphere;
+    use crate::utils::util::{Color, Point3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_ray(rng: &mut StdRng) -> Ray {
+        let origin = Point3::new(
+            rng.gen_range(-15.0..15.0),
+            rng.gen_range(-15.0..15.0),
+            rng.gen_range(-15.0..15.0),
+        );
+        let direction = Vec3::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        );
+
+        Ray::new(origin, direction)
+    }
+
+    // the sizes hold for single precision boxes
+    #[test]
+    #[cfg(not(feature = "f64"))]
+    fn node_fits_in_two_cache_lines() {
+        assert_eq!(std::mem::size_of::<Bvh4Node>(), 128);
+    }
+
+    #[test]
+    fn simd_box_test_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(11);
+
+        let boxes: Vec<Aabb> = (0..WIDTH)
+            .map(|_| {
+                let min = Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0));
+                let size = Vec3::new(rng.gen_range(0.1..4.0), rng.gen_range(0.1..4.0), rng.gen_range(0.1..4.0));
+                Aabb::new(min, min + size)
+            })
+            .collect();
+
+        let mut node = Bvh4Node::new();
+        for (slot, aabb) in boxes.iter().enumerate() {
+            node.make_leaf(slot, aabb, slot, 1);
+        }
+
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng);
+            let (mask, _) = node.hit(&Ray4::new(&ray), 0.001, Float::INFINITY);
+
+            for (slot, aabb) in boxes.iter().enumerate() {
+                assert_eq!(mask & (1 << slot) != 0, aabb.hit(&ray, 0.001, Float::INFINITY));
+            }
+        }
+    }
+
+    #[test]
+    fn simd_packet_box_test_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(12);
+
+        let mut node = Bvh4Node::new();
+        for slot in 0..WIDTH - 1 {
+            let min = Point3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0));
+            let size = Vec3::new(rng.gen_range(0.1..4.0), rng.gen_range(0.1..4.0), rng.gen_range(0.1..4.0));
+            node.make_leaf(slot, &Aabb::new(min, min + size), slot, 1);
+        }
+
+        for _ in 0..1000 {
+            let packet = RayPacket::new(std::array::from_fn(|_| random_ray(&mut rng)));
+            let rays: [Ray4; PACKET_SIZE] = std::array::from_fn(|lane| Ray4::new(packet.ray(lane)));
+            let t_max = Vec4::new(5.0, 10.0, 20.0, Float::INFINITY);
+
+            let (active, t_enter) = node.hit_packet(&rays, 0.001, t_max);
+
+            for slot in 0..WIDTH - 1 {
+                let (expected, t_near) = packet.hit_aabb(&node.bounding_box(slot), 0.001, t_max);
+                assert_eq!(active[slot], expected);
+
+                // the first of the rays hitting the box enters it
+                let first = (0..PACKET_SIZE)
+                    .filter(|&lane| expected & (1 << lane) != 0)
+                    .map(|lane| t_near[lane])
+                    .fold(Float::INFINITY, Float::min);
+                assert!(expected == 0 || (t_enter[slot] - first).abs() < 0.0001);
+            }
+
+            // the empty slot is never hit
+            assert_eq!(active[WIDTH - 1], 0);
+        }
+    }
+
+    #[test]
+    fn empty_slots_are_never_hit() {
+        let mut node = Bvh4Node::new();
+        node.make_leaf(0, &Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), 0, 1);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let (mask, t_near) = node.hit(&Ray4::new(&ray), 0.001, Float::INFINITY);
+
+        assert_eq!(mask, 0b1);
+        assert!((t_near[0] - 4.0).abs() < 0.0001);
+        assert!(node.is_empty(1) && node.is_empty(2) && node.is_empty(3));
+    }
+
+    #[test]
+    fn intersect_matches_linear_world() {
+        let split_methods = [
+            SplitMethod::Middle,
+            SplitMethod::EqualCounts,
+            SplitMethod::SAH(SahParams::default()),
+            SplitMethod::HLBVH(HlbvhParams::default()),
+        ];
+
+        for split_method in split_methods {
+            let mut bvh4 = Bvh4::new(Some(2), Some(split_method));
+            bvh4.build(sphere_world(500));
+
+            assert_matches_world(&bvh4, &sphere_world(500));
+        }
+    }
+
+    #[test]
+    fn intersect_packet_matches_intersect() {
+        let mut world = triangle_world(300);
+        world.extend(sphere_world(100));
+
+        let mut bvh4 = Bvh4::new(Some(2), Some(SplitMethod::SAH(SahParams::default())));
+        bvh4.build(world);
+
+        assert_packets_match_single_rays(&bvh4);
+    }
+
+    #[test]
+    fn negative_zero_directions_hit() {
+        // a row of spheres along x, the ray down -z starts inside the x and y slabs of
+        // the boxes with the negative zero components of -Vec3::Z
+        let world: World = (0..20)
+            .map(|i| Box::new(Sphere::new(Point3::new(i as Float - 10.0, 0.0, 0.0), 0.4, Lambertian::new(Color::ONE))) as Box<dyn Hit>)
+            .collect();
+
+        let mut bvh4 = Bvh4::new(Some(2), None);
+        bvh4.build(world);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 10.0), -Vec3::Z);
+        assert!(ray.direction().x.is_sign_negative());
+
+        let rec = bvh4.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+        assert!((rec.t - 9.6).abs() < 0.0001);
+        assert!(bvh4.occluded(&ray, 0.001, Float::INFINITY));
+
+        let packet = RayPacket::new(std::array::from_fn(|_| Ray::new(ray.origin(), ray.direction())));
+        let hits = bvh4.intersect_packet(&packet, 0.001, Float::INFINITY);
+        assert!(hits.iter().all(|hit| hit.as_ref().is_some_and(|rec| (rec.t - 9.6).abs() < 0.0001)));
+    }
+
+    #[test]
+    fn collapse_reduces_node_count() {
+        let mut bvh = Bvh::new(Some(1), None);
+        bvh.build(sphere_world(256));
+
+        let bvh4 = Bvh4::from(bvh);
+
+        // the 255 interior nodes of the binary tree are merged in groups of up to three
+        assert!(bvh4.node_count() >= 255 / 3);
+        assert!(bvh4.node_count() < 255 / 2);
+
+        // every primitive is referenced by exactly one leaf
+        let mut references = vec![0; bvh4.world.len()];
+        for node in &bvh4.nodes {
+            for slot in (0..WIDTH).filter(|&slot| !node.is_empty(slot) && node.is_leaf(slot)) {
+                for reference in &mut references[node.index(slot)..node.index(slot) + node.num_primitives(slot)] {
+                    *reference += 1;
+                }
+            }
+        }
+        assert!(references.iter().all(|&r| r == 1));
+    }
+
+    #[test]
+    fn single_leaf_and_empty_world() {
+        let mut bvh4 = Bvh4::new(Some(4), None);
+        bvh4.build(sphere_world(3));
+        assert_eq!(bvh4.node_count(), 1);
+
+        let mut bvh4 = Bvh4::new(Some(4), None);
+        bvh4.build(World::new());
+        assert_eq!(bvh4.node_count(), 0);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(bvh4.intersect(&ray, 0.001, Float::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::ray::Ray;
+use crate::utils::aabb::Aabb;
+use crate::utils::util::{Float, Point3, Vec3};
+use crate::hit::{HitRecord, World};
+use crate::accelerators::accelerator::{Accelerator, intersect_unbounded, occluded_unbounded};
+use rayon::prelude::*;
+
+// parameters of the uniform grid
+#[derive(Clone, Copy)]
+pub struct GridParams {
+    // number of voxels along the longest axis is the density times the
+    // cube root of the number of primitives, the other axes get cubic voxels
+    pub density: Float,
+    pub max_resolution: usize, // maximum number of voxels along an axis
+}
+
+impl Default for GridParams {
+    fn default() -> Self {
+        Self {
+            density: 3.0,
+            max_resolution: 256,
+        }
+    }
+}
+
+/*
+    A uniform grid over the bounded objects of the world. Every primitive is bucketed
+    into all the voxels its bounding box overlaps and the rays walk through the voxels
+    along their way with a 3D digital differential analyzer. The primitives of the voxels
+    are stored one voxel after the other in a single list, which is built by sorting
+    the (voxel, primitive) pairs in parallel.
+*/
+pub struct Grid {
+    world: World, // the intersectable objects the grid is built over
+    unbounded: Vec<usize>, // indices of the objects without a bounding box
+    bounds: Aabb, // bounding box of all the bounded objects
+    resolution: [usize; 3], // number of voxels along every axis
+    voxel_size: Vec3,
+    inv_voxel_size: Vec3, // 0 along axes with an extent of 0
+    voxel_starts: Vec<usize>, // index of the first primitive of every voxel, followed by the total count
+    voxel_primitives: Vec<u32>, // indices into the world of the primitives of the voxels
+    params: GridParams,
+}
+
+impl Grid {
+    pub fn new(params: Option<GridParams>) -> Self {
+        Self {
+            world: World::new(),
+            unbounded: Vec::new(),
+            bounds: Aabb::empty(),
+            resolution: [0; 3],
+            voxel_size: Vec3::ZERO,
+            inv_voxel_size: Vec3::ZERO,
+            voxel_starts: Vec::new(),
+            voxel_primitives: Vec::new(),
+            params: params.unwrap_or_default(),
+        }
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    // the voxel coordinate of the point along the axis, clamped into the grid
+    fn position_to_voxel(&self, p: &Point3, axis: usize) -> usize {
+        let voxel = ((p[axis] - self.bounds.min()[axis]) * self.inv_voxel_size[axis]) as isize;
+        voxel.clamp(0, self.resolution[axis] as isize - 1) as usize
+    }
+
+    // the position of the lower boundary of the voxel along the axis
+    fn voxel_to_position(&self, voxel: usize, axis: usize) -> Float {
+        self.bounds.min()[axis] + voxel as Float * self.voxel_size[axis]
+    }
+
+    fn voxel_index(&self, voxel: [usize; 3]) -> usize {
+        (voxel[2] * self.resolution[1] + voxel[1]) * self.resolution[0] + voxel[0]
+    }
+
+    // the primitives overlapping the voxel
+    fn voxel(&self, index: usize) -> &[u32] {
+        let start = self.voxel_starts[index];
+        let end = self.voxel_starts[index + 1];
+        &self.voxel_primitives[start..end]
+    }
+
+    // walk the voxels pierced by the ray in between t_enter and t_exit with a 3D-DDA, front to
+    // back. Every voxel is handed to visit together with the distance at which the ray leaves
+    // it, the walk stops as soon as visit returns true
+    fn walk<F>(&self, ray: &Ray, t_enter: Float, t_exit: Float, mut visit: F)
+    where
+        F: FnMut(&[u32], Float) -> bool,
+    {
+        let direction = ray.direction();
+        let entry = ray.at(t_enter);
+
+        // the voxel the ray enters the grid in, the distance to the next voxel boundary
+        // along every axis, the distance in between the boundaries and the step direction
+        let mut voxel = [0usize; 3];
+        let mut next_crossing = [Float::INFINITY; 3];
+        let mut delta: [Float; 3] = [0.0; 3];
+        let mut step = [0isize; 3];
+
+        for axis in 0..3 {
+            voxel[axis] = self.position_to_voxel(&entry, axis);
+
+            if direction[axis] > 0.0 {
+                next_crossing[axis] = t_enter + (self.voxel_to_position(voxel[axis] + 1, axis) - entry[axis]) / direction[axis];
+                delta[axis] = self.voxel_size[axis] / direction[axis];
+                step[axis] = 1;
+            } else if direction[axis] < 0.0 {
+                next_crossing[axis] = t_enter + (self.voxel_to_position(voxel[axis], axis) - entry[axis]) / direction[axis];
+                delta[axis] = -self.voxel_size[axis] / direction[axis];
+                step[axis] = -1;
+            }
+        }
+
+        loop {
+            // the axis whose voxel boundary the ray crosses first
+            let axis = (0..3).min_by(|&a, &b| next_crossing[a].total_cmp(&next_crossing[b])).unwrap();
+
+            if visit(self.voxel(self.voxel_index(voxel)), next_crossing[axis]) || t_exit < next_crossing[axis] {
+                break;
+            }
+
+            let next = voxel[axis] as isize + step[axis];
+
+            if next < 0 || next >= self.resolution[axis] as isize {
+                break;
+            }
+
+            voxel[axis] = next as usize;
+            next_crossing[axis] += delta[axis];
+        }
+    }
+}
+
+impl Accelerator for Grid {
+    fn build(&mut self, world: World) {
+
+        // the bounding boxes of all the intersectable objects in the world
+        let primitive_bounds: Vec<Option<Aabb>> = world.par_iter()
+            .map(|object| object.bounding_box())
+            .collect();
+
+        // the objects without a bounding box can not be placed in the grid
+        self.unbounded = (0..world.len()).filter(|&i| primitive_bounds[i].is_none()).collect();
+        self.world = world;
+        self.voxel_starts = Vec::new();
+        self.voxel_primitives = Vec::new();
+
+        assert!(self.world.len() < u32::MAX as usize, "too many primitives for the grid");
+
+        let num_primitives = self.world.len() - self.unbounded.len();
+
+        // there is no grid to build without any bounded primitive
+        if num_primitives == 0 {
+            self.bounds = Aabb::empty();
+            self.resolution = [0; 3];
+            return;
+        }
+
+        self.bounds = primitive_bounds.par_iter()
+            .flatten()
+            .cloned()
+            .reduce(Aabb::empty, |a, b| a.include(&b));
+
+        // cubic voxels with the number of voxels along the longest axis
+        // growing with the cube root of the number of primitives
+        let size = self.bounds.size();
+        let max_extent = size.max_element();
+        let voxels_per_unit = if max_extent > 0.0 {
+            self.params.density * (num_primitives as Float).cbrt() / max_extent
+        } else {
+            0.0
+        };
+
+        for axis in 0..3 {
+            self.resolution[axis] = ((size[axis] * voxels_per_unit).round() as usize).clamp(1, self.params.max_resolution.max(1));
+            self.voxel_size[axis] = size[axis] / self.resolution[axis] as Float;
+            self.inv_voxel_size[axis] = if self.voxel_size[axis] > 0.0 { 1.0 / self.voxel_size[axis] } else { 0.0 };
+        }
+
+        // pair every primitive with all the voxels its bounding box overlaps. The voxels and
+        // the pairs can outnumber u32 even when the primitives do not
+        let this = &*self;
+        let mut pairs: Vec<(usize, u32)> = primitive_bounds.par_iter()
+            .enumerate()
+            .filter_map(|(i, aabb)| aabb.as_ref().map(|aabb| (i, aabb)))
+            .flat_map_iter(|(i, aabb)| {
+                let min = [0, 1, 2].map(|axis| this.position_to_voxel(&aabb.min(), axis));
+                let max = [0, 1, 2].map(|axis| this.position_to_voxel(&aabb.max(), axis));
+
+                (min[2]..=max[2]).flat_map(move |z| {
+                    (min[1]..=max[1]).flat_map(move |y| {
+                        (min[0]..=max[0]).map(move |x| (this.voxel_index([x, y, z]), i as u32))
+                    })
+                })
+            })
+            .collect();
+
+        // sorting the pairs by voxel places the primitives of every voxel next to each other
+        pairs.par_sort_unstable();
+
+        let num_voxels = self.resolution.iter().product::<usize>();
+        let mut voxel_starts = vec![0usize; num_voxels + 1];
+
+        for &(voxel, _) in &pairs {
+            voxel_starts[voxel + 1] += 1;
+        }
+
+        for voxel in 0..num_voxels {
+            voxel_starts[voxel + 1] += voxel_starts[voxel];
+        }
+
+        self.voxel_starts = voxel_starts;
+        self.voxel_primitives = pairs.into_iter().map(|(_, primitive)| primitive).collect();
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.voxel_starts.is_empty() || !self.unbounded.is_empty() {
+            return None;
+        }
+
+        Some(self.bounds.clone())
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+
+        let mut closest_hit = intersect_unbounded(&self.world, &self.unbounded, ray, t_min, t_max);
+        let mut closest_so_far = closest_hit.as_ref().map_or(t_max, |rec| rec.t);
+
+        if self.voxel_starts.is_empty() {
+            return closest_hit;
+        }
+
+        // clip the ray to the bounds of the grid
+        let (t_enter, t_exit) = match self.bounds.hit_range(ray, t_min, closest_so_far) {
+            Some(range) => range,
+            None => return closest_hit,
+        };
+
+        self.walk(ray, t_enter, t_exit, |primitives, next_crossing| {
+            for &i in primitives {
+                if let Some(rec) = self.world[i as usize].hit(ray, t_min, closest_so_far) {
+                    closest_so_far = rec.t;
+                    closest_hit = Some(rec);
+                }
+            }
+
+            // a hit in front of the next voxel can not be beaten by the voxels behind it
+            closest_so_far < next_crossing
+        });
+
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        if occluded_unbounded(&self.world, &self.unbounded, ray, t_min, t_max) {
+            return true;
+        }
+
+        if self.voxel_starts.is_empty() {
+            return false;
+        }
+
+        let (t_enter, t_exit) = match self.bounds.hit_range(ray, t_min, t_max) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        // the walk stops in the first voxel holding an intersection
+        let mut occluded = false;
+
+        self.walk(ray, t_enter, t_exit, |primitives, _| {
+            occluded = primitives.iter().any(|&i| self.world[i as usize].occluded(ray, t_min, t_max));
+            occluded
+        });
+
+        occluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::tests::assert_matches_world;
+    use crate::accelerators::bvh::tests::{sphere_world, triangle_world};
+    use crate::hit::Hit;
+    use crate::material::Lambertian;
+    use crate::primitives::sphere::Sphere;
+    use crate::utils::util::Color;
+
+    // a block of equally sized particles, as in a fluid simulation
+    fn particle_world() -> World {
+        let mut world = World::new();
+
+        for x in 0..20 {
+            for y in 0..20 {
+                for z in 0..20 {
+                    let center = Point3::new(x as Float, y as Float, z as Float) * 0.5 - Vec3::splat(5.0);
+                    world.push(Box::new(Sphere::new(center, 0.2, Lambertian::new(Color::new(0.2, 0.3, 0.8)))));
+                }
+            }
+        }
+
+        world
+    }
+
+    #[test]
+    fn intersect_matches_linear_world() {
+        let mut grid = Grid::new(None);
+        grid.build(particle_world());
+
+        assert_eq!(grid.resolution(), [60, 60, 60]);
+        assert_matches_world(&grid, &particle_world());
+
+        let mut world = triangle_world(300);
+        world.extend(sphere_world(300));
+
+        let mut grid = Grid::new(None);
+        grid.build(world);
+
+        let mut world = triangle_world(300);
+        world.extend(sphere_world(300));
+
+        assert_matches_world(&grid, &world);
+    }
+
+    #[test]
+    fn primitives_are_bucketed_into_overlapping_voxels() {
+        let mut grid = Grid::new(None);
+        grid.build(sphere_world(100));
+
+        for z in 0..grid.resolution[2] {
+            for y in 0..grid.resolution[1] {
+                for x in 0..grid.resolution[0] {
+                    let min = grid.bounds.min() + Vec3::new(x as Float, y as Float, z as Float) * grid.voxel_size;
+                    let voxel_bounds = Aabb::new(min, min + grid.voxel_size);
+
+                    // every primitive of the voxel overlaps it
+                    for &i in grid.voxel(grid.voxel_index([x, y, z])) {
+                        let aabb = grid.world[i as usize].bounding_box().unwrap();
+                        let overlap = aabb.min().cmple(voxel_bounds.max() + Vec3::splat(0.0001)).all() &&
+                                      voxel_bounds.min().cmple(aabb.max() + Vec3::splat(0.0001)).all();
+                        assert!(overlap);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flat_and_empty_worlds() {
+        // all the particles in a plane leave a single voxel along the y axis
+        let world: World = (0..100)
+            .map(|i| {
+                let center = Point3::new((i % 10) as Float, 0.0, (i / 10) as Float);
+                Box::new(Sphere::new(center, 0.1, Lambertian::new(Color::new(0.5, 0.5, 0.5)))) as Box<dyn Hit>
+            })
+            .collect();
+
+        let mut grid = Grid::new(None);
+        grid.build(world);
+        assert_eq!(grid.resolution()[1], 1);
+
+        let ray = Ray::new(Point3::new(3.0, 5.0, 4.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!((grid.intersect(&ray, 0.001, Float::INFINITY).unwrap().t - 4.9).abs() < 0.0001);
+
+        let ray = Ray::new(Point3::new(3.5, 5.0, 4.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(grid.intersect(&ray, 0.001, Float::INFINITY).is_none());
+
+        let mut grid = Grid::new(None);
+        grid.build(World::new());
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(grid.intersect(&ray, 0.001, Float::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,545 @@
+use crate::ray::Ray;
+use crate::utils::aabb::Aabb;
+use crate::hit::{HitRecord, World};
+use crate::accelerators::accelerator::{Accelerator, intersect_unbounded, occluded_unbounded};
+use crate::utils::stack::Stack;
+use rayon::prelude::*;
+use crate::utils::util::Float;
+
+// parameters of the surface area heuristic kd-tree
+#[derive(Clone, Copy)]
+pub struct KdTreeParams {
+    pub intersection_cost: Float, // cost of intersecting a single primitive
+    pub traversal_cost: Float, // cost of traversing an interior node
+    pub empty_bonus: Float, // share of the cost saved by splits with an empty side, in between 0 and 1
+    pub max_primitives: usize, // nodes with at most this many primitives become leaves
+    pub max_depth: Option<usize>, // maximum depth of the tree, derived from the number of primitives if None
+}
+
+impl Default for KdTreeParams {
+    fn default() -> Self {
+        // the traversal is assumed to be 1/80 as expensive as a primitive intersection
+        Self {
+            intersection_cost: 80.0,
+            traversal_cost: 1.0,
+            empty_bonus: 0.5,
+            max_primitives: 1,
+            max_depth: None,
+        }
+    }
+}
+
+// the split axis of a node marking it as a leaf
+const LEAF: u8 = 3;
+
+/*
+    The nodes are stored in a single contiguous list in depth first order. The child
+    below the split plane is placed right after its parent, the child above it is
+    referred to by index. A primitive straddling the split plane is referred to by
+    the leaves on both sides, so the leaves index into a list of primitive indices.
+*/
+#[derive(Clone)]
+pub struct KdNode {
+    split: Float, // position of the split plane along the split axis
+    // if leaf node, index into the first primitive index of the leaf
+    // if interior node, index into the child above the split plane
+    index: u32,
+    num_primitives: u32, // number of primitives in a leaf
+    axis: u8, // axis along which the node is split, LEAF for leaf nodes
+}
+
+impl KdNode {
+    fn leaf(index: usize, num_primitives: usize) -> Self {
+        Self {
+            split: 0.0,
+            index: index as u32,
+            num_primitives: num_primitives as u32,
+            axis: LEAF,
+        }
+    }
+
+    fn interior(axis: usize, split: Float) -> Self {
+        Self {
+            split,
+            index: u32::MAX,
+            num_primitives: 0,
+            axis: axis as u8,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.axis == LEAF
+    }
+
+    pub fn split(&self) -> Float {
+        self.split
+    }
+
+    pub fn split_axis(&self) -> usize {
+        self.axis as usize
+    }
+
+    // index of the first primitive index of a leaf or of the above child of an interior node
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn num_primitives(&self) -> usize {
+        self.num_primitives as usize
+    }
+}
+
+// an edge of the bounding box of a primitive along the axis a split is searched on
+#[derive(Clone, Copy)]
+struct BoundEdge {
+    t: Float, // position of the edge along the axis
+    primitive: usize, // index of the primitive in the world
+    start: bool, // true for the lower edge of the bounding box
+}
+
+/*
+    A kd-tree over the bounded objects of the world with the split planes chosen by the
+    surface area heuristic among the edges of the bounding boxes of the primitives, as
+    described in Physically Based Rendering. Splits cutting off empty space get a bonus.
+*/
+pub struct KdTree {
+    world: World, // the intersectable objects the tree is built over
+    unbounded: Vec<usize>, // indices of the objects without a bounding box
+    bounds: Aabb, // bounding box of all the bounded objects
+    nodes: Vec<KdNode>,
+    primitive_indices: Vec<usize>, // indices into the world of the primitives of the leaves
+    params: KdTreeParams,
+}
+
+impl KdTree {
+    pub fn new(params: Option<KdTreeParams>) -> Self {
+        Self {
+            world: World::new(),
+            unbounded: Vec::new(),
+            bounds: Aabb::empty(),
+            nodes: Vec::new(),
+            primitive_indices: Vec::new(),
+            params: params.unwrap_or_default(),
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn build_recursive(&mut self,
+                       node_bounds: &Aabb,
+                       primitive_bounds: &[Option<Aabb>],
+                       primitives: &[usize],
+                       depth: usize,
+                       mut bad_refines: usize) {
+        let node_index = self.nodes.len();
+        let num_primitives = primitives.len();
+
+        // make a leaf if there are few enough primitives or the tree is deep enough
+        if num_primitives <= self.params.max_primitives || depth == 0 {
+            self.make_leaf(primitives);
+            return;
+        }
+
+        let size = node_bounds.size();
+        let inv_total_area = 1.0 / node_bounds.surface_area().max(Float::MIN_POSITIVE);
+        let leaf_cost = self.params.intersection_cost * num_primitives as Float;
+
+        let mut best_axis = None;
+        let mut best_offset = 0;
+        let mut best_cost = Float::INFINITY;
+        let mut edges = Vec::with_capacity(2 * num_primitives);
+
+        // search the largest axis first and fall back to the other ones
+        // if no edge of the primitives lies inside the node along it
+        let mut axis = node_bounds.largest_axis().to_usize();
+
+        for _ in 0..3 {
+            edges.clear();
+
+            for &primitive in primitives {
+                let aabb = primitive_bounds[primitive].as_ref().unwrap();
+                edges.push(BoundEdge { t: aabb.min()[axis], primitive, start: true });
+                edges.push(BoundEdge { t: aabb.max()[axis], primitive, start: false });
+            }
+
+            // the start edges go first at the same position
+            edges.sort_by(|a, b| a.t.total_cmp(&b.t).then(b.start.cmp(&a.start)));
+
+            // sweep over the edges and compute the cost of splitting at each of them
+            let (other0, other1) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (axis_min, axis_max) = (node_bounds.min()[axis], node_bounds.max()[axis]);
+
+            let mut num_below = 0;
+            let mut num_above = num_primitives;
+
+            for (i, edge) in edges.iter().enumerate() {
+                if !edge.start {
+                    num_above -= 1;
+                }
+
+                if edge.t > axis_min && edge.t < axis_max {
+                    let cap_area = size[other0] * size[other1];
+                    let side_length = size[other0] + size[other1];
+
+                    let below_area = 2.0 * (cap_area + (edge.t - axis_min) * side_length);
+                    let above_area = 2.0 * (cap_area + (axis_max - edge.t) * side_length);
+
+                    let p_below = below_area * inv_total_area;
+                    let p_above = above_area * inv_total_area;
+
+                    let bonus = if num_below == 0 || num_above == 0 { self.params.empty_bonus } else { 0.0 };
+
+                    let cost = self.params.traversal_cost + self.params.intersection_cost * (1.0 - bonus) *
+                        (p_below * num_below as Float + p_above * num_above as Float);
+
+                    if cost < best_cost {
+                        best_axis = Some(axis);
+                        best_offset = i;
+                        best_cost = cost;
+                    }
+                }
+
+                if edge.start {
+                    num_below += 1;
+                }
+            }
+
+            if best_axis.is_some() {
+                break;
+            }
+
+            axis = (axis + 1) % 3;
+        }
+
+        // allow a few splits that are more expensive than a leaf on the way down,
+        // as further splits below them may still pay off
+        if best_cost > leaf_cost {
+            bad_refines += 1;
+        }
+
+        let axis = match best_axis {
+            Some(axis) if !((best_cost > 4.0 * leaf_cost && num_primitives < 16) || bad_refines == 3) => axis,
+            _ => {
+                self.make_leaf(primitives);
+                return;
+            }
+        };
+
+        // the primitives starting below the split go below it and the ones ending above it
+        // go above it, a primitive straddling the split plane goes to both sides
+        let below: Vec<usize> = edges[..best_offset].iter()
+            .filter(|edge| edge.start)
+            .map(|edge| edge.primitive)
+            .collect();
+
+        let above: Vec<usize> = edges[best_offset + 1..].iter()
+            .filter(|edge| !edge.start)
+            .map(|edge| edge.primitive)
+            .collect();
+
+        let split = edges[best_offset].t;
+
+        let mut below_max = node_bounds.max();
+        below_max[axis] = split;
+        let mut above_min = node_bounds.min();
+        above_min[axis] = split;
+
+        let below_bounds = Aabb::new(node_bounds.min(), below_max);
+        let above_bounds = Aabb::new(above_min, node_bounds.max());
+
+        self.nodes.push(KdNode::interior(axis, split));
+
+        // the child below is placed right after the parent
+        self.build_recursive(&below_bounds, primitive_bounds, &below, depth - 1, bad_refines);
+
+        let above_child = self.nodes.len();
+        self.nodes[node_index].index = above_child as u32;
+
+        self.build_recursive(&above_bounds, primitive_bounds, &above, depth - 1, bad_refines);
+    }
+
+    fn make_leaf(&mut self, primitives: &[usize]) {
+        self.nodes.push(KdNode::leaf(self.primitive_indices.len(), primitives.len()));
+        self.primitive_indices.extend_from_slice(primitives);
+    }
+}
+
+impl Accelerator for KdTree {
+    fn build(&mut self, world: World) {
+
+        // the bounding boxes of all the intersectable objects in the world
+        let primitive_bounds: Vec<Option<Aabb>> = world.par_iter()
+            .map(|object| object.bounding_box())
+            .collect();
+
+        let primitives: Vec<usize> = (0..world.len()).filter(|&i| primitive_bounds[i].is_some()).collect();
+
+        // the objects without a bounding box can not be placed in the tree
+        self.unbounded = (0..world.len()).filter(|&i| primitive_bounds[i].is_none()).collect();
+        self.world = world;
+        self.nodes = Vec::new();
+        self.primitive_indices = Vec::new();
+
+        self.bounds = primitives.iter()
+            .fold(Aabb::empty(), |a, &i| a.include(primitive_bounds[i].as_ref().unwrap()));
+
+        // there is no tree to build without any bounded primitive
+        if primitives.is_empty() {
+            return;
+        }
+
+        let max_depth = self.params.max_depth
+            .unwrap_or_else(|| (8.0 + 1.3 * (primitives.len() as Float).log2()).round() as usize);
+
+        let bounds = self.bounds.clone();
+        self.build_recursive(&bounds, &primitive_bounds, &primitives, max_depth, 0);
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.nodes.is_empty() || !self.unbounded.is_empty() {
+            return None;
+        }
+
+        Some(self.bounds.clone())
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+
+        let mut closest_hit = intersect_unbounded(&self.world, &self.unbounded, ray, t_min, t_max);
+        let mut closest_so_far = closest_hit.as_ref().map_or(t_max, |rec| rec.t);
+
+        if self.nodes.is_empty() {
+            return closest_hit;
+        }
+
+        // clip the ray to the bounds of the tree
+        let (mut node_t_min, mut node_t_max) = match self.bounds.hit_range(ray, t_min, closest_so_far) {
+            Some(range) => range,
+            None => return closest_hit,
+        };
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = direction.recip();
+
+        // stack of the nodes still to be visited, the far children
+        // together with the parametric range of the ray inside them
+        let mut nodes_to_visit: Stack<(usize, Float, Float)> = Stack::new();
+        let mut current = 0;
+
+        loop {
+            // every node still to be visited lies behind the closest intersection
+            if closest_so_far < node_t_min {
+                break;
+            }
+
+            let node = &self.nodes[current];
+
+            if node.is_leaf() {
+                let leaf_primitives = &self.primitive_indices[node.index()..node.index() + node.num_primitives()];
+
+                for &i in leaf_primitives {
+                    if let Some(rec) = self.world[i].hit(ray, t_min, closest_so_far) {
+                        closest_so_far = rec.t;
+                        closest_hit = Some(rec);
+                    }
+                }
+
+                match nodes_to_visit.pop() {
+                    Some((next, next_t_min, next_t_max)) => {
+                        current = next;
+                        node_t_min = next_t_min;
+                        node_t_max = next_t_max;
+                    }
+                    None => break,
+                }
+            } else {
+                let axis = node.split_axis();
+                let t_plane = (node.split() - origin[axis]) * inv_direction[axis];
+
+                // the child on the side of the ray origin is visited first
+                let below_first = origin[axis] < node.split() ||
+                    (origin[axis] == node.split() && direction[axis] <= 0.0);
+
+                let (first, second) = if below_first {
+                    (current + 1, node.index())
+                } else {
+                    (node.index(), current + 1)
+                };
+
+                if t_plane > node_t_max || t_plane <= 0.0 {
+                    // the ray only passes through the near child
+                    current = first;
+                } else if t_plane < node_t_min {
+                    // the ray only passes through the far child
+                    current = second;
+                } else {
+                    nodes_to_visit.push((second, t_plane, node_t_max));
+                    current = first;
+                    node_t_max = t_plane;
+                }
+            }
+        }
+
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        if occluded_unbounded(&self.world, &self.unbounded, ray, t_min, t_max) {
+            return true;
+        }
+
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let (mut node_t_min, mut node_t_max) = match self.bounds.hit_range(ray, t_min, t_max) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = direction.recip();
+
+        // the same traversal as for the closest hit, which ends at the first intersection found
+        let mut nodes_to_visit: Stack<(usize, Float, Float)> = Stack::new();
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+
+            if node.is_leaf() {
+                let leaf_primitives = &self.primitive_indices[node.index()..node.index() + node.num_primitives()];
+
+                if leaf_primitives.iter().any(|&i| self.world[i].occluded(ray, t_min, t_max)) {
+                    return true;
+                }
+
+                match nodes_to_visit.pop() {
+                    Some((next, next_t_min, next_t_max)) => {
+                        current = next;
+                        node_t_min = next_t_min;
+                        node_t_max = next_t_max;
+                    }
+                    None => return false,
+                }
+            } else {
+                let axis = node.split_axis();
+                let t_plane = (node.split() - origin[axis]) * inv_direction[axis];
+
+                let below_first = origin[axis] < node.split() ||
+                    (origin[axis] == node.split() && direction[axis] <= 0.0);
+
+                let (first, second) = if below_first {
+                    (current + 1, node.index())
+                } else {
+                    (node.index(), current + 1)
+                };
+
+                if t_plane > node_t_max || t_plane <= 0.0 {
+                    current = first;
+                } else if t_plane < node_t_min {
+                    current = second;
+                } else {
+                    nodes_to_visit.push((second, t_plane, node_t_max));
+                    current = first;
+                    node_t_max = t_plane;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::tests::assert_matches_world;
+    use crate::accelerators::bvh::tests::{sphere_world, triangle_world};
+    use crate::material::Lambertian;
+    use crate::primitives::sphere::Sphere;
+    use crate::utils::util::{Color, Point3, Vec3};
+
+    fn mixed_world() -> World {
+        let mut world = triangle_world(300);
+        world.extend(sphere_world(300));
+        world
+    }
+
+    #[test]
+    fn intersect_matches_linear_world() {
+        let mut tree = KdTree::new(None);
+        tree.build(mixed_world());
+
+        assert!(tree.node_count() > 1);
+        assert_matches_world(&tree, &mixed_world());
+    }
+
+    #[test]
+    fn intersect_with_shallow_tree() {
+        let params = KdTreeParams {
+            max_primitives: 8,
+            max_depth: Some(4),
+            ..KdTreeParams::default()
+        };
+
+        let mut tree = KdTree::new(Some(params));
+        tree.build(mixed_world());
+
+        assert_matches_world(&tree, &mixed_world());
+    }
+
+    #[test]
+    fn empty_space_is_cut_off() {
+        // a single sphere in a corner of a large scene
+        let mut world = sphere_world(1);
+        world.push(Box::new(Sphere::new(Point3::new(100.0, 100.0, 100.0), 1.0, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+
+        let mut tree = KdTree::new(None);
+        tree.build(world);
+
+        // the split between the spheres leaves a leaf with one sphere on each side
+        let root = &tree.nodes[0];
+        assert!(!root.is_leaf());
+        assert!(tree.nodes[1].is_leaf() && tree.nodes[root.index()].is_leaf());
+        assert_eq!(tree.nodes[1].num_primitives(), 1);
+        assert_eq!(tree.nodes[root.index()].num_primitives(), 1);
+    }
+
+    #[test]
+    fn straddling_primitives_are_found_from_both_sides() {
+        let mut world = World::new();
+        for i in 0..20 {
+            world.push(Box::new(Sphere::new(Point3::new(i as Float, 0.0, 0.0), 0.4, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+        }
+        // a large sphere overlapping all the others
+        world.push(Box::new(Sphere::new(Point3::new(10.0, 0.0, 5.0), 4.0, Lambertian::new(Color::new(0.5, 0.5, 0.5)))));
+
+        let mut tree = KdTree::new(None);
+        tree.build(world);
+
+        // the large sphere is referred to by more than one leaf
+        assert!(tree.primitive_indices.iter().filter(|&&i| i == 20).count() > 1);
+
+        for x in [7.0, 10.0, 13.0] {
+            let ray = Ray::new(Point3::new(x, 0.0, 20.0), Vec3::new(0.0, 0.0, -1.0));
+            let rec = tree.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+
+            let expected = 20.0 - 5.0 - (16.0 - (x - 10.0) * (x - 10.0)).sqrt();
+            assert!((rec.t - expected).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn build_empty_world() {
+        let mut tree = KdTree::new(None);
+        tree.build(World::new());
+
+        assert_eq!(tree.node_count(), 0);
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(tree.intersect(&ray, 0.001, Float::INFINITY).is_none());
+    }
+}
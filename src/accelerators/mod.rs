@@ -1,2 +1,5 @@
 pub mod accelerator;
-pub mod bvh;
\ No newline at end of file
+pub mod bvh;
+pub mod bvh4;
+pub mod kdtree;
+pub mod grid;
\ No newline at end of file
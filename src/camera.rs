@@ -1,6 +1,5 @@
-use glam::Vec3;
+use crate::utils::util::{Float, Point3, Util, Vec3, consts};
 use crate::ray::Ray;
-use crate::utils::util::{Point3, Util};
 
 /*
     Develop the camera incrementaly
@@ -23,7 +22,7 @@ pub struct Camera {
     vertical: Vec3,
     cu: Vec3,
     cv: Vec3,
-    lens_radius: f32,
+    lens_radius: Float,
 }
 
 impl Camera {
@@ -31,13 +30,13 @@ impl Camera {
         lookfrom: Point3,
         lookat: Point3,
         vup: Vec3,
-        vfov: f32,
-        aspect_ratio: f32,
-        aperture: f32,
-        focus_dist: f32,
+        vfov: Float,
+        aspect_ratio: Float,
+        aperture: Float,
+        focus_dist: Float,
     ) -> Self {
         // Vertical field-of-view in degrees
-        let theta = std::f32::consts::PI / 180.0 * vfov;
+        let theta = consts::PI / 180.0 * vfov;
         let viewport_height = 2.0 * (theta / 2.0).tan();
         let viewport_width = aspect_ratio * viewport_height;
 
@@ -61,7 +60,7 @@ impl Camera {
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
+    pub fn get_ray(&self, s: Float, t: Float) -> Ray {
         let rd = self.lens_radius * Util::random_in_unit_disk();
         let offset = self.cu * rd.x + self.cv * rd.y;
         Ray::new(
@@ -70,3 +69,33 @@ impl Camera {
         )
     }
 }
+
+/*
+    The parameters of a camera as a scene file describes it. The camera itself is only
+    created once the aspect ratio of the image is known, unless the scene fixes one.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraSettings {
+    pub lookfrom: Point3,
+    pub lookat: Point3,
+    pub vup: Vec3,
+    pub vfov: Float, // vertical field of view in degrees
+    pub aspect_ratio: Option<Float>,
+    pub aperture: Float,
+    pub focus_dist: Float,
+}
+
+impl CameraSettings {
+    // the aspect ratio of the scene takes precedence over the given one
+    pub fn camera(&self, aspect_ratio: Float) -> Camera {
+        Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            self.aspect_ratio.unwrap_or(aspect_ratio),
+            self.aperture,
+            self.focus_dist,
+        )
+    }
+}
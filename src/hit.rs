@@ -1,20 +1,47 @@
-use glam::Vec3;
+use crate::utils::util::{Float, Point3, Vec2, Vec3, Vec4};
 
 use crate::material::Scatter;
-use crate::ray::Ray;
-use crate::utils::util::Point3;
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
 use crate::utils::aabb::Aabb;
 
 pub struct HitRecord<'a> {
     pub p: Point3,
-    pub normal: Vec3,
+    pub normal: Vec3, // the geometric normal, facing against the ray
+    pub shading_normal: Vec3, // the normal the materials shade with, on the side of the geometric normal
+    pub dpdu: Vec3, // the tangents of the surface along the texture coordinates
+    pub dpdv: Vec3,
+    pub uv: Vec2, // the texture coordinates
+    pub barycentrics: Vec3, // the weights of the vertices of a triangle
     pub mat: &'a dyn Scatter,
-    pub t: f32,
+    pub t: Float,
     pub front_face: bool,
 }
 
 
 impl<'a> HitRecord<'a> {
+    // the record of a hit at t with a flat surface, objects
+    // with texture coordinates or smooth normals add them
+    pub fn new(r: &Ray, t: Float, outward_normal: Vec3, mat: &'a dyn Scatter) -> Self {
+        let (dpdu, dpdv) = outward_normal.any_orthonormal_pair();
+
+        let mut rec = HitRecord {
+            p: r.at(t),
+            normal: outward_normal,
+            shading_normal: outward_normal,
+            dpdu,
+            dpdv,
+            uv: Vec2::ZERO,
+            barycentrics: Vec3::ZERO,
+            mat,
+            t,
+            front_face: false,
+        };
+
+        rec.set_face_normal(r, outward_normal);
+
+        rec
+    }
+
     pub fn set_face_normal(&mut self, r: &Ray, outward_normal: Vec3) {
         self.front_face = r.direction().dot(outward_normal) < 0.0;
         self.normal = if self.front_face {
@@ -22,9 +49,34 @@ impl<'a> HitRecord<'a> {
         } else {
             (-1.0) * outward_normal
         };
+        self.shading_normal = self.normal;
+    }
+
+    // the shading normal is flipped onto the side of the geometric normal
+    pub fn set_shading_normal(&mut self, shading_normal: Vec3) {
+        self.shading_normal = if shading_normal.dot(self.normal) < 0.0 {
+            (-1.0) * shading_normal
+        } else {
+            shading_normal
+        };
     }
 } 
 
+// keep the hits of a packet that are closer than the ones found so far, closest
+// holds the distance of the hit kept for every ray of the packet
+pub fn merge_packet_hits<'a>(hits: &mut [Option<HitRecord<'a>>; PACKET_SIZE],
+                             closest: &mut Vec4,
+                             candidates: [Option<HitRecord<'a>>; PACKET_SIZE]) {
+    for (lane, candidate) in candidates.into_iter().enumerate() {
+        if let Some(rec) = candidate {
+            if rec.t <= closest[lane] {
+                closest[lane] = rec.t;
+                hits[lane] = Some(rec);
+            }
+        }
+    }
+}
+
 /*
     the following vector is of type World - is a trait object
     it is a stand-in for any type inside a Box that implements
@@ -35,7 +87,7 @@ impl<'a> HitRecord<'a> {
 pub type World = Vec<Box<dyn Hit>>;
 
 impl Hit for World {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
         let mut tmp_rec = None;
         let mut closest_so_far = t_max;
 
@@ -48,6 +100,10 @@ impl Hit for World {
 
         tmp_rec
     }
+
+    fn occluded(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.iter().any(|object| object.occluded(r, t_min, t_max))
+    }
     
     fn bounding_box(&self) -> Option<Aabb> {
         if self.is_empty() {
@@ -80,16 +136,38 @@ impl Hit for World {
             count += 1;
         }
 
-        centroid / count as f32
+        centroid / count as Float
     }
 }
 
 
 /*
     Sync marker triats are used to mark types that are safe to share between threads.
+    Send is required as well, so that an accelerator owning the objects can be
+    shared through an Arc, for instance by the instances of a mesh.
 */
-pub trait Hit : Sync {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+pub trait Hit : Send + Sync {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+
+    // check if the ray intersects the object anywhere in between t_min and t_max, as asked
+    // for by shadow rays. The default looks for the closest hit, objects override it to
+    // skip the hit record and the material
+    fn occluded(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.hit(r, t_min, t_max).is_some()
+    }
+
+    // intersect the rays of a packet, whose bits are set in the active mask. Every ray
+    // has its own t_max, the closest intersection found so far. The default tests the
+    // rays one by one, objects with a vectorized intersection override it
+    fn hit_packet(&self, packet: &RayPacket, t_min: Float, t_max: Vec4, active: u32) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+        std::array::from_fn(|lane| {
+            if active & (1 << lane) != 0 {
+                self.hit(packet.ray(lane), t_min, t_max[lane])
+            } else {
+                None
+            }
+        })
+    }
 
     fn bounding_box(&self) -> Option<Aabb>;
 
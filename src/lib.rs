@@ -0,0 +1,14 @@
+//lib.rs - is the crate root file of the library crate with the same name as the package.
+// The binary crate in main.rs brings the modules declared here into scope through the
+// path_tracer:: path, so that the renderer can be driven by other binaries and tests as well.
+
+pub mod camera;
+pub mod hit;
+pub mod light;
+pub mod material;
+pub mod ray;
+pub mod utils;
+pub mod tracer;
+pub mod primitives;
+pub mod accelerators;
+pub mod loaders;
\ No newline at end of file
@@ -0,0 +1,32 @@
+use crate::utils::util::{Color, Float, Point3, Vec3};
+
+/*
+    The kinds of the punctual lights, infinitely small lights that emit from a point
+    or from infinitely far away along a direction. A spot light emits a cone, with the
+    full intensity inside the inner angle falling off to nothing at the outer angle.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot {
+        inner_cone_angle: Float, // radians
+        outer_cone_angle: Float, // radians
+    },
+}
+
+/*
+    A punctual light of a scene. The color is linear and scaled by the intensity,
+    in candela for the point and spot lights and in lux for the directional lights.
+    The renderer lights the scenes with the sky only, the lights are kept for the
+    scenes that describe them until direct lighting samples them.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct PunctualLight {
+    pub kind: LightKind,
+    pub position: Point3, // ignored by directional lights
+    pub direction: Vec3, // the direction the light shines in, ignored by point lights
+    pub color: Color,
+    pub intensity: Float,
+    pub range: Option<Float>, // the distance beyond which the light has no effect
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::gltf::camera::Projection;
+use ::gltf::khr_lights_punctual::Kind;
+use ::gltf::mesh::Mode;
+use ::gltf::{buffer, Gltf, Node};
+
+use crate::accelerators::accelerator::Accelerator;
+use crate::accelerators::bvh::Bvh;
+use crate::camera::{Camera, CameraSettings};
+use crate::hit::{Hit, World};
+use crate::light::{LightKind, PunctualLight};
+use crate::loaders::LoadError;
+use crate::material::{Dielectric, Lambertian, Metal, SharedMaterial};
+use crate::primitives::instance::Instance;
+use crate::primitives::mesh::TriangleMesh;
+use crate::utils::util::{Affine3, Color, Float, Mat4, Point3, Vec2, Vec3};
+
+// the index of refraction of transmissive materials without one, as given by the specification
+const DEFAULT_INDEX_OF_REFRACTION: Float = 1.5;
+
+pub type GltfMesh = TriangleMesh<SharedMaterial>;
+
+/*
+    The content of a glTF file: the meshes of the default scene placed into the world,
+    together with the cameras and the punctual lights found in its node hierarchy.
+*/
+pub struct GltfScene {
+    pub world: World,
+    pub cameras: Vec<CameraSettings>,
+    pub lights: Vec<PunctualLight>,
+}
+
+impl GltfScene {
+    // the first camera of the scene, for an image of the given aspect ratio
+    // unless the camera fixes its own
+    pub fn camera(&self, aspect_ratio: Float) -> Option<Camera> {
+        self.cameras.first().map(|settings| settings.camera(aspect_ratio))
+    }
+}
+
+/*
+    Loads a glTF 2.0 file, either a .gltf with its buffers in separate or embedded
+    files or a binary .glb. The nodes of the default scene are walked down the
+    hierarchy, accumulating their transforms:
+        - every glTF mesh is built once in object space into a bvh, and every node
+          referring to it places an instance of the bvh into the world
+        - the perspective cameras map onto the camera of the renderer, looking down
+          the -z axis of their node with the +y axis up. Orthographic cameras have
+          no counterpart and are skipped
+        - the punctual lights of the KHR_lights_punctual extension are placed at
+          their nodes, shining down the -z axis
+    The metallic-roughness materials are mapped onto the materials of the renderer:
+        - transmissive materials become Dielectric with their index of refraction
+        - metallic materials become Metal with the base color, the roughness sets the fuzz
+        - the other materials are Lambertian with the base color
+    Only the factors of the materials are read, the textures are ignored.
+*/
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<GltfScene, LoadError> {
+    let path = path.as_ref();
+
+    let Gltf { document, blob } = Gltf::open(path).map_err(|err| convert_error(path, err))?;
+    let buffers = ::gltf::import_buffers(&document, path.parent(), blob).map_err(|err| convert_error(path, err))?;
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| LoadError::Parse(format!("{} has no scene", path.display())))?;
+
+    let mut loader = Loader {
+        buffers: &buffers,
+        materials: HashMap::new(),
+        meshes: HashMap::new(),
+        scene: GltfScene {
+            world: World::new(),
+            cameras: Vec::new(),
+            lights: Vec::new(),
+        },
+    };
+
+    for node in scene.nodes() {
+        loader.visit(&node, Mat4::IDENTITY)?;
+    }
+
+    Ok(loader.scene)
+}
+
+// the gltf crate reports the files it fails to read as io errors too
+fn convert_error(path: &Path, err: ::gltf::Error) -> LoadError {
+    match err {
+        ::gltf::Error::Io(err) => LoadError::Io(err),
+        err => LoadError::Parse(format!("{}: {}", path.display(), err)),
+    }
+}
+
+struct Loader<'a> {
+    buffers: &'a [buffer::Data],
+    materials: HashMap<Option<usize>, SharedMaterial>, // by the index of the glTF material, None for the default one
+    meshes: HashMap<usize, Option<Arc<Bvh>>>, // by the index of the glTF mesh, None for meshes without triangles
+    scene: GltfScene,
+}
+
+impl Loader<'_> {
+    fn visit(&mut self, node: &Node, parent: Mat4) -> Result<(), LoadError> {
+        let matrix = node.transform().matrix().map(|column| column.map(|v| v as Float));
+        let transform = parent * Mat4::from_cols_array_2d(&matrix);
+
+        if let Some(mesh) = node.mesh() {
+            let affine = Affine3::from_mat4(transform);
+
+            // a node scaled down to nothing has no visible geometry and no inverse for the instance
+            if affine.matrix3.determinant() != 0.0 {
+                if let Some(bvh) = self.mesh(&mesh)? {
+                    self.scene.world.push(Box::new(Instance::new(bvh, affine)) as Box<dyn Hit>);
+                }
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            if let Projection::Perspective(perspective) = camera.projection() {
+                self.scene.cameras.push(CameraSettings {
+                    lookfrom: transform.transform_point3(Point3::ZERO),
+                    lookat: transform.transform_point3(-Vec3::Z),
+                    vup: transform.transform_vector3(Vec3::Y),
+                    vfov: (perspective.yfov() as Float).to_degrees(),
+                    aspect_ratio: perspective.aspect_ratio().map(|a| a as Float),
+                    aperture: 0.0,
+                    focus_dist: 1.0,
+                });
+            }
+        }
+
+        if let Some(light) = node.light() {
+            let kind = match light.kind() {
+                Kind::Directional => LightKind::Directional,
+                Kind::Point => LightKind::Point,
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => LightKind::Spot {
+                    inner_cone_angle: inner_cone_angle as Float,
+                    outer_cone_angle: outer_cone_angle as Float,
+                },
+            };
+
+            self.scene.lights.push(PunctualLight {
+                kind,
+                position: transform.transform_point3(Point3::ZERO),
+                direction: transform.transform_vector3(-Vec3::Z).normalize(),
+                color: Color::from_array(light.color().map(|c| c as Float)),
+                intensity: light.intensity() as Float,
+                range: light.range().map(|r| r as Float),
+            });
+        }
+
+        for child in node.children() {
+            self.visit(&child, transform)?;
+        }
+
+        Ok(())
+    }
+
+    // the bvh over the triangles of all the primitives of the mesh, built on the first visit
+    fn mesh(&mut self, mesh: &::gltf::Mesh) -> Result<Option<Arc<Bvh>>, LoadError> {
+        if let Some(bvh) = self.meshes.get(&mesh.index()) {
+            return Ok(bvh.clone());
+        }
+
+        let mut triangles = World::new();
+
+        for primitive in mesh.primitives() {
+            // points and lines have no surface to hit
+            if !matches!(primitive.mode(), Mode::Triangles | Mode::TriangleStrip | Mode::TriangleFan) {
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| self.buffers.get(buffer.index()).map(|data| &data[..]));
+
+            let positions: Vec<Point3> = match reader.read_positions() {
+                Some(positions) => positions.map(|p| Point3::from_array(p.map(|v| v as Float))).collect(),
+                None => continue,
+            };
+
+            let normals: Option<Vec<Vec3>> = reader
+                .read_normals()
+                .map(|normals| normals.map(|n| Vec3::from_array(n.map(|v| v as Float))).collect());
+
+            let uvs: Option<Vec<Vec2>> = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().map(|uv| Vec2::from_array(uv.map(|v| v as Float))).collect());
+
+            // the primitives without indices list their vertices in order
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            if indices.iter().any(|&i| i as usize >= positions.len()) {
+                return Err(LoadError::Parse(format!("mesh {} refers to a vertex that does not exist", mesh.index())));
+            }
+
+            if normals.as_ref().is_some_and(|normals| normals.len() != positions.len()) ||
+                uvs.as_ref().is_some_and(|uvs| uvs.len() != positions.len()) {
+                return Err(LoadError::Parse(format!("mesh {} has attributes of different lengths", mesh.index())));
+            }
+
+            let indices = triangulate(primitive.mode(), &indices);
+            let material = self.material(&primitive.material());
+
+            triangles.extend(Arc::new(GltfMesh::new(positions, indices, normals, uvs, material)).triangles());
+        }
+
+        let bvh = (!triangles.is_empty()).then(|| {
+            let mut bvh = Bvh::new(None, None);
+            bvh.build(triangles);
+            Arc::new(bvh)
+        });
+
+        self.meshes.insert(mesh.index(), bvh.clone());
+        Ok(bvh)
+    }
+
+    fn material(&mut self, material: &::gltf::Material) -> SharedMaterial {
+        Arc::clone(self.materials.entry(material.index()).or_insert_with(|| convert_material(material)))
+    }
+}
+
+// the triangles listed by the indices of a primitive, keeping the winding of the strips and the fans
+fn triangulate(mode: Mode, indices: &[u32]) -> Vec<[u32; 3]> {
+    match mode {
+        Mode::Triangles => indices.chunks_exact(3).map(|triangle| [triangle[0], triangle[1], triangle[2]]).collect(),
+        // every other triangle of a strip has its first two vertices swapped
+        Mode::TriangleStrip => indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, triangle)| if i % 2 == 0 { [triangle[0], triangle[1], triangle[2]] } else { [triangle[0], triangle[2], triangle[1]] })
+            .collect(),
+        Mode::TriangleFan => indices
+            .get(1..)
+            .unwrap_or_default()
+            .windows(2)
+            .map(|edge| [edge[0], edge[1], indices[0]])
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn convert_material(material: &::gltf::Material) -> SharedMaterial {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor().map(|c| c as Float);
+    let base_color = Color::new(r, g, b);
+
+    let transmission = material.transmission().map_or(0.0, |t| t.transmission_factor());
+    if transmission >= 0.5 {
+        let index_of_refraction = material.ior().map_or(DEFAULT_INDEX_OF_REFRACTION, |ior| ior as Float);
+        return Arc::new(Dielectric::new(index_of_refraction));
+    }
+
+    if pbr.metallic_factor() >= 0.5 {
+        return Arc::new(Metal::new(base_color, (pbr.roughness_factor() as Float).clamp(0.0, 1.0)));
+    }
+
+    Arc::new(Lambertian::new(base_color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::RAY_EPSILON;
+    use crate::loaders::tests::{assert_reports_missing_and_malformed, TestDirectory};
+    use crate::ray::Ray;
+
+    // a quad of two triangles in the z = 0 plane, the four positions followed by the six indices
+    fn quad_buffer() -> Vec<u8> {
+        let positions: [[f32; 3]; 4] = [[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0], [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0]];
+        let indices: [u16; 6] = [0, 1, 2, 0, 2, 3];
+
+        let mut buffer: Vec<u8> = positions.iter().flatten().flat_map(|v| v.to_le_bytes()).collect();
+        buffer.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+        buffer
+    }
+
+    /*
+        One red quad at z = -5, a copy of the quad under a parent node which moves it to
+        x = 4 and scales it by 2 before the child moves it to z = -1, so it spans [2, 6] x [-2, 2]
+        at z = -2, a camera at z = 10 and a point light above the origin.
+    */
+    fn document(buffer_uri: Option<&str>) -> String {
+        let uri = buffer_uri.map_or(String::new(), |uri| format!(r#", "uri": "{}""#, uri));
+
+        format!(r#"{{
+    "asset": {{ "version": "2.0" }},
+    "extensionsUsed": ["KHR_lights_punctual"],
+    "extensions": {{
+        "KHR_lights_punctual": {{ "lights": [{{ "type": "point", "color": [1.0, 0.5, 0.25], "intensity": 20.0 }}] }}
+    }},
+    "scene": 0,
+    "scenes": [{{ "nodes": [0, 1, 3, 4] }}],
+    "nodes": [
+        {{ "mesh": 0, "translation": [0.0, 0.0, -5.0] }},
+        {{ "translation": [4.0, 0.0, 0.0], "scale": [2.0, 2.0, 2.0], "children": [2] }},
+        {{ "mesh": 0, "translation": [0.0, 0.0, -1.0] }},
+        {{ "camera": 0, "translation": [0.0, 0.0, 10.0] }},
+        {{ "translation": [0.0, 5.0, 0.0], "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }} }}
+    ],
+    "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.5, "znear": 0.1, "aspectRatio": 1.5 }} }}],
+    "materials": [{{ "pbrMetallicRoughness": {{ "baseColorFactor": [0.8, 0.2, 0.2, 1.0], "metallicFactor": 0.0 }} }}],
+    "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }}] }}],
+    "accessors": [
+        {{ "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-1.0, -1.0, 0.0], "max": [1.0, 1.0, 0.0] }},
+        {{ "bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR" }}
+    ],
+    "bufferViews": [
+        {{ "buffer": 0, "byteOffset": 0, "byteLength": 48, "target": 34962 }},
+        {{ "buffer": 0, "byteOffset": 48, "byteLength": 12, "target": 34963 }}
+    ],
+    "buffers": [{{ "byteLength": 60{} }}]
+}}"#, uri)
+    }
+
+    // the binary container with the json chunk and the buffer chunk, both padded to four bytes
+    fn glb(json: &str, buffer: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut buffer = buffer.to_vec();
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+        let length = 12 + 8 + json.len() + 8 + buffer.len();
+
+        let mut glb = Vec::new();
+        glb.extend(b"glTF");
+        glb.extend(2u32.to_le_bytes());
+        glb.extend((length as u32).to_le_bytes());
+        glb.extend((json.len() as u32).to_le_bytes());
+        glb.extend(b"JSON");
+        glb.extend(json);
+        glb.extend((buffer.len() as u32).to_le_bytes());
+        glb.extend(b"BIN\0");
+        glb.extend(buffer);
+        glb
+    }
+
+    fn assert_scene(scene: &GltfScene) {
+        // two instances of the one mesh
+        assert_eq!(scene.world.len(), 2);
+
+        let down_z = |x: Float, y: Float| Ray::new(Point3::new(x, y, 10.0), Vec3::new(0.0, 0.0, -1.0));
+
+        let rec = scene.world.hit(&down_z(0.5, 0.5), RAY_EPSILON, Float::INFINITY).unwrap();
+        assert!((rec.t - 15.0).abs() < 0.0001);
+
+        // the transforms of the parent and the child are combined
+        let rec = scene.world.hit(&down_z(5.5, -1.5), RAY_EPSILON, Float::INFINITY).unwrap();
+        assert!((rec.t - 12.0).abs() < 0.0001);
+        assert!(scene.world.hit(&down_z(6.5, 0.0), RAY_EPSILON, Float::INFINITY).is_none());
+
+        // the quad is red and diffuse
+        let (attenuation, _) = rec.mat.scatter(&down_z(5.5, -1.5), &rec).unwrap();
+        assert!((attenuation - Color::new(0.8, 0.2, 0.2)).length() < 0.0001);
+
+        assert_eq!(scene.cameras.len(), 1);
+        let camera = &scene.cameras[0];
+        assert_eq!(camera.lookfrom, Point3::new(0.0, 0.0, 10.0));
+        assert_eq!(camera.lookat, Point3::new(0.0, 0.0, 9.0));
+        assert_eq!(camera.vup, Vec3::Y);
+        assert!((camera.vfov - (0.5 as Float).to_degrees()).abs() < 0.0001);
+        assert_eq!(camera.aspect_ratio, Some(1.5));
+        assert!(scene.camera(2.0).is_some());
+
+        assert_eq!(scene.lights.len(), 1);
+        let light = &scene.lights[0];
+        assert_eq!(light.kind, LightKind::Point);
+        assert_eq!(light.position, Point3::new(0.0, 5.0, 0.0));
+        assert_eq!(light.color, Color::new(1.0, 0.5, 0.25));
+        assert_eq!(light.intensity, 20.0);
+    }
+
+    #[test]
+    fn loads_gltf_with_external_buffer() {
+        let directory = TestDirectory::new("gltf_external");
+        directory.write("quad.bin", quad_buffer());
+
+        assert_scene(&load_gltf(directory.write("scene.gltf", document(Some("quad.bin")))).unwrap());
+    }
+
+    #[test]
+    fn loads_binary_glb() {
+        let directory = TestDirectory::new("gltf_binary");
+
+        assert_scene(&load_gltf(directory.write("scene.glb", glb(&document(None), &quad_buffer()))).unwrap());
+    }
+
+    #[test]
+    fn triangulates_strips_and_fans() {
+        assert_eq!(triangulate(Mode::TriangleStrip, &[0, 1, 2, 3, 4]), vec![[0, 1, 2], [1, 3, 2], [2, 3, 4]]);
+        assert_eq!(triangulate(Mode::TriangleFan, &[0, 1, 2, 3]), vec![[1, 2, 0], [2, 3, 0]]);
+        assert!(triangulate(Mode::TriangleStrip, &[0, 1]).is_empty());
+        assert!(triangulate(Mode::TriangleFan, &[]).is_empty());
+
+        // the four corners of the quad in order, as a fan without indices
+        let directory = TestDirectory::new("gltf_fan");
+        directory.write("quad.bin", quad_buffer());
+        let fan = document(Some("quad.bin")).replace(r#""indices": 1, "material": 0"#, r#""mode": 6, "material": 0"#);
+
+        assert_scene(&load_gltf(directory.write("scene.gltf", fan)).unwrap());
+    }
+
+    #[test]
+    fn reports_missing_and_malformed_files() {
+        assert_reports_missing_and_malformed(load_gltf, "broken.gltf", "{ \"asset\": ");
+
+        // the buffer the document refers to is missing
+        let directory = TestDirectory::new("gltf_buffer");
+        assert!(load_gltf(directory.write("scene.gltf", document(Some("missing.bin")))).is_err());
+    }
+}
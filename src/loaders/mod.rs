@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub mod gltf;
+pub mod obj;
+pub mod pbrt;
+pub mod ply;
+pub mod scene;
+
+// errors of the loaders reading the scenes and the assets from files
+#[derive(Debug)]
+pub enum LoadError {
+    // the file could not be read
+    Io(io::Error),
+    // the content of the file is malformed, with a description of the problem
+    Parse(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "failed to read the file: {}", err),
+            LoadError::Parse(message) => write!(f, "failed to parse the file: {}", message),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            LoadError::Parse(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::LoadError;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // the directories of the tests running in parallel are told apart by a counter
+    static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+    // a temporary directory of its own for the files of a test. It is removed again when it
+    // is dropped, also when an assertion of the test fails, so that no files are left behind
+    pub(crate) struct TestDirectory {
+        path: PathBuf,
+    }
+
+    impl TestDirectory {
+        pub(crate) fn new(name: &str) -> Self {
+            let count = NEXT_DIRECTORY.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("path_tracer_{}_{}_{}", name, std::process::id(), count));
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+
+        // the path of the file in the directory
+        pub(crate) fn join<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+            self.path.join(file)
+        }
+
+        // write the file into the directory and return its path
+        pub(crate) fn write<C: AsRef<[u8]>>(&self, file: &str, contents: C) -> PathBuf {
+            let path = self.join(file);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    // a file that does not exist is an io error of the loader, a malformed one a parse error
+    pub(crate) fn assert_reports_missing_and_malformed<T, F>(load: F, file: &str, malformed: &str)
+    where
+        F: Fn(PathBuf) -> Result<T, LoadError>,
+    {
+        let directory = TestDirectory::new("missing_and_malformed");
+
+        match load(directory.join(file)) {
+            Err(LoadError::Io(_)) => {}
+            Err(err) => panic!("the missing {} is not an io error: {}", file, err),
+            Ok(_) => panic!("the missing {} is loaded", file),
+        }
+
+        match load(directory.write(file, malformed)) {
+            Err(LoadError::Parse(_)) => {}
+            Err(err) => panic!("the malformed {} is not a parse error: {}", file, err),
+            Ok(_) => panic!("the malformed {} is loaded", file),
+        }
+    }
+}
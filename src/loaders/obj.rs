@@ -0,0 +1,241 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::loaders::LoadError;
+use crate::material::{Dielectric, Lambertian, Metal, SharedMaterial};
+use crate::primitives::mesh::TriangleMesh;
+use crate::utils::util::{Color, Float, Point3, Vec2, Vec3};
+
+// the color of the faces without a material
+const DEFAULT_COLOR: Color = Color::new(0.5, 0.5, 0.5);
+
+// the index of refraction of glass without one
+const DEFAULT_INDEX_OF_REFRACTION: Float = 1.5;
+
+// the illumination models of the MTL format with reflection
+// and with refraction, as described in the specification
+const REFLECTIVE_ILLUMINATION_MODELS: [u8; 3] = [3, 5, 8];
+const TRANSPARENT_ILLUMINATION_MODELS: [u8; 4] = [4, 6, 7, 9];
+
+pub type ObjMesh = TriangleMesh<SharedMaterial>;
+
+/*
+    Loads a Wavefront OBJ file together with the MTL material libraries it refers to,
+    which are looked up next to it. Every group of faces sharing a material becomes a
+    triangle mesh, the polygons are triangulated. The materials are mapped onto the
+    materials of the renderer:
+        - transparent materials, with a dissolve d below 1 or one of the glass
+          illumination models, become Dielectric with the index of refraction Ni
+        - reflective materials, with one of the reflection illumination models, become
+          Metal with the specular color Ks. The specular exponent Ns sets its fuzz
+        - the other materials are Lambertian with the diffuse color Kd
+*/
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Vec<Arc<ObjMesh>>, LoadError> {
+    let path = path.as_ref();
+    let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let options = tobj::LoadOptions {
+        triangulate: true,
+        single_index: true,
+        ..Default::default()
+    };
+
+    let (models, materials) = tobj::load_obj_buf(&mut reader, &options, |mtl_path| {
+        tobj::load_mtl(directory.join(mtl_path))
+    }).map_err(|err| LoadError::Parse(format!("{}: {}", path.display(), err)))?;
+
+    let materials = materials.map_err(|err| LoadError::Parse(format!("material library of {}: {}", path.display(), err)))?;
+
+    // the meshes refer to the shared materials
+    let materials: Vec<SharedMaterial> = materials.iter().map(convert_material).collect();
+    let default_material: SharedMaterial = Arc::new(Lambertian::new(DEFAULT_COLOR));
+
+    let meshes = models
+        .into_iter()
+        .filter(|model| !model.mesh.indices.is_empty())
+        .map(|model| {
+            let material = match model.mesh.material_id {
+                Some(id) => materials.get(id).cloned().ok_or_else(|| {
+                    LoadError::Parse(format!("{} refers to the unknown material {}", model.name, id))
+                })?,
+                None => Arc::clone(&default_material),
+            };
+
+            Ok(Arc::new(convert_mesh(&model.mesh, material)))
+        })
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    Ok(meshes)
+}
+
+// the values read by tobj are in the precision of the renderer already, the f64 feature switches both
+fn convert_mesh(mesh: &tobj::Mesh, material: SharedMaterial) -> ObjMesh {
+    let positions: Vec<Point3> = mesh.positions
+        .chunks_exact(3)
+        .map(|p| Point3::new(p[0], p[1], p[2]))
+        .collect();
+
+    // the normals and the texture coordinates are only kept if every vertex has them
+    let normals = (mesh.normals.len() == mesh.positions.len()).then(|| {
+        mesh.normals
+            .chunks_exact(3)
+            .map(|n| Vec3::new(n[0], n[1], n[2]))
+            .collect()
+    });
+
+    let uvs = (mesh.texcoords.len() / 2 == positions.len() && !positions.is_empty()).then(|| {
+        mesh.texcoords
+            .chunks_exact(2)
+            .map(|uv| Vec2::new(uv[0], uv[1]))
+            .collect()
+    });
+
+    let indices = mesh.indices
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect();
+
+    TriangleMesh::new(positions, indices, normals, uvs, material)
+}
+
+fn convert_material(material: &tobj::Material) -> SharedMaterial {
+    let illumination_model = material.illumination_model.unwrap_or(2);
+    let transparent = material.dissolve.is_some_and(|d| d < 1.0) ||
+        TRANSPARENT_ILLUMINATION_MODELS.contains(&illumination_model);
+
+    if transparent {
+        let index_of_refraction = material.optical_density.unwrap_or(DEFAULT_INDEX_OF_REFRACTION);
+        return Arc::new(Dielectric::new(index_of_refraction));
+    }
+
+    if REFLECTIVE_ILLUMINATION_MODELS.contains(&illumination_model) {
+        let albedo = material.specular.or(material.diffuse).map_or(DEFAULT_COLOR, Color::from_array);
+
+        // the higher the specular exponent, the sharper the reflection. The fuzz
+        // follows the roughness of a Phong lobe with the exponent
+        let fuzz = material.shininess.map_or(0.0, |ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt());
+
+        return Arc::new(Metal::new(albedo, fuzz.min(1.0)));
+    }
+
+    Arc::new(Lambertian::new(material.diffuse.map_or(DEFAULT_COLOR, Color::from_array)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::Hit;
+    use crate::loaders::tests::{assert_reports_missing_and_malformed, TestDirectory};
+    use crate::ray::Ray;
+
+    const MATERIALS: &str = "
+newmtl red
+Kd 0.8 0.1 0.1
+illum 2
+
+newmtl mirror
+Kd 0.1 0.1 0.1
+Ks 0.9 0.9 0.7
+Ns 1000
+illum 3
+
+newmtl glass
+Kd 1 1 1
+Ni 1.33
+d 0.2
+";
+
+    // a quad in the z = 0 plane and a triangle behind it, with three materials
+    const QUADS: &str = "
+mtllib scene.mtl
+
+v -1 -1 0
+v 1 -1 0
+v 1 1 0
+v -1 1 0
+v -1 -1 -2
+v 1 -1 -2
+v 0 1 -2
+
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+
+vn 0 0 1
+
+o quad
+usemtl red
+f 1/1/1 2/2/1 3/3/1 4/4/1
+
+o back
+usemtl mirror
+f 5 6 7
+usemtl glass
+f 7 6 5
+";
+
+    // the attenuation of the material of the triangle hit by a ray straight down the z axis
+    fn attenuation(mesh: &Arc<ObjMesh>) -> Color {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+
+        let triangle = (0..mesh.num_triangles())
+            .map(|i| mesh.triangle(i))
+            .find(|triangle| triangle.occluded(&ray, 0.001, Float::INFINITY))
+            .unwrap();
+        let rec = triangle.hit(&ray, 0.001, Float::INFINITY).unwrap();
+
+        // a fuzzy reflection may be absorbed, the attenuation is all that is compared
+        (0..100).find_map(|_| rec.mat.scatter(&ray, &rec)).unwrap().0
+    }
+
+    #[test]
+    fn loads_meshes_and_materials() {
+        let directory = TestDirectory::new("obj_scene");
+        directory.write("scene.mtl", MATERIALS);
+
+        let meshes = load_obj(directory.write("scene.obj", QUADS)).unwrap();
+        assert_eq!(meshes.len(), 3);
+
+        // the quad is triangulated and keeps its normals and texture coordinates
+        let quad = &meshes[0];
+        assert_eq!(quad.num_triangles(), 2);
+        assert_eq!(quad.positions().len(), 4);
+        assert!(quad.normals().is_some());
+        assert_eq!(quad.uvs().unwrap()[2], Vec2::new(1.0, 1.0));
+        assert!((attenuation(quad) - Color::new(0.8, 0.1, 0.1)).length() < 0.0001);
+
+        // the faces without normals and texture coordinates leave them out
+        let mirror = &meshes[1];
+        assert_eq!(mirror.num_triangles(), 1);
+        assert!(mirror.normals().is_none());
+        assert!(mirror.uvs().is_none());
+        assert!((attenuation(mirror) - Color::new(0.9, 0.9, 0.7)).length() < 0.0001);
+
+        // the dielectric does not absorb any light
+        assert!((attenuation(&meshes[2]) - Color::ONE).length() < 0.0001);
+    }
+
+    #[test]
+    fn faces_without_a_material_are_diffuse() {
+        let directory = TestDirectory::new("obj_plain");
+
+        let meshes = load_obj(directory.write("plain.obj", "v -1 -1 0\nv 1 -1 0\nv 0 1 0\nf 1 2 3\n")).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(attenuation(&meshes[0]), DEFAULT_COLOR);
+    }
+
+    #[test]
+    fn reports_missing_and_malformed_files() {
+        // the face refers to a vertex that does not exist
+        assert_reports_missing_and_malformed(load_obj, "broken.obj", "v 0 0 0\nv 1 0 0\nf 1 2 3\n");
+
+        // the material library is missing
+        let directory = TestDirectory::new("obj_library");
+        assert!(load_obj(directory.write("library.obj", "mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n")).is_err());
+    }
+}
@@ -0,0 +1,1088 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::accelerators::accelerator::Accelerator;
+use crate::accelerators::bvh::Bvh;
+use crate::camera::CameraSettings;
+use crate::hit::World;
+use crate::light::{LightKind, PunctualLight};
+use crate::loaders::ply::load_ply;
+use crate::loaders::scene::{ImageSettings, Scene};
+use crate::loaders::LoadError;
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal, SharedMaterial};
+use crate::primitives::instance::Instance;
+use crate::primitives::mesh::TriangleMesh;
+use crate::primitives::sphere::Sphere;
+use crate::tracer::Background;
+use crate::utils::util::{Affine3, Color, Float, Mat4, Point3, Vec2, Vec3};
+
+// the defaults of pbrt for the settings missing from a scene
+const DEFAULT_RESOLUTION: (u32, u32) = (1280, 720);
+const DEFAULT_PIXEL_SAMPLES: u32 = 16;
+const DEFAULT_MAX_DEPTH: u32 = 5;
+const DEFAULT_FOV: Float = 90.0;
+const FAR_FOCUS: Float = 1.0e3;
+const DEFAULT_FILENAME: &str = "pbrt.png";
+const DEFAULT_REFLECTANCE: Color = Color::new(0.5, 0.5, 0.5);
+const DEFAULT_INDEX_OF_REFRACTION: Float = 1.5;
+
+// the formats the tracer can save the image in, the others are saved as png
+const IMAGE_EXTENSIONS: [&str; 8] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff", "tga", "ppm"];
+
+// the reflectance of the metals pbrt names the spectra of, copper being the default conductor
+const COPPER: Color = Color::new(0.955, 0.638, 0.538);
+const METALS: [(&str, Color); 4] = [
+    ("Cu", COPPER),
+    ("Au", Color::new(1.0, 0.782, 0.344)),
+    ("Ag", Color::new(0.972, 0.960, 0.915)),
+    ("Al", Color::new(0.913, 0.922, 0.924)),
+];
+
+/*
+    Loads a scene in the format of pbrt-v3 or pbrt-v4. A practical subset is supported:
+        - the transforms Identity, Translate, Scale, Rotate, LookAt, Transform,
+          ConcatTransform, CoordinateSystem and CoordSysTransform
+        - the blocks AttributeBegin/End and TransformBegin/End, and Include/Import
+        - the perspective Camera, the resolution and the file name of the Film, the
+          pixel samples of the Sampler and the maximum depth of the Integrator
+        - the shapes sphere, trianglemesh and plymesh
+        - the materials diffuse (matte), conductor (metal) and dielectric (glass), by
+          name as well with MakeNamedMaterial and NamedMaterial. The material mirror is
+          approximated by them, and plastic, coateddiffuse, substrate and uber by a diffuse one
+        - the point, spot, distant and infinite LightSource and the diffuse AreaLightSource
+    The colors are read from rgb values, the spectra are averaged to grey and the
+    blackbody emitters are white. Textures, media and the other settings are ignored,
+    and the other shapes, materials and lights and object instancing are reported as
+    errors, rather than rendering the scene differently without notice.
+
+    Like pbrt, the scenes are black beyond their objects unless they have an infinite
+    LightSource. Its radiance L lights them from every direction, and an environment map
+    is approximated by the sky of the tracer.
+
+    pbrt works in a left-handed space. The scene is mirrored along x into the right-handed
+    space of the renderer, so that the images show the scene the way pbrt renders it.
+*/
+pub fn load_pbrt<P: AsRef<Path>>(path: P) -> Result<Scene, LoadError> {
+    let mut loader = Loader::new();
+    loader.parse_file(path.as_ref())?;
+    Ok(loader.finish())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Identifier(String),
+    String(String),
+    Number(f64),
+    OpenBracket,
+    CloseBracket,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, (usize, usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let (mut line, mut column) = (1, 1);
+
+    while let Some(&c) = chars.peek() {
+        let (start_line, start_column) = (line, column);
+
+        // every character taken moves the position along
+        let mut take = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let c = chars.next();
+            if c == Some('\n') {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+            c
+        };
+
+        let kind = match c {
+            c if c.is_whitespace() => {
+                take(&mut chars);
+                continue;
+            }
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    take(&mut chars);
+                }
+                continue;
+            }
+            '[' => {
+                take(&mut chars);
+                TokenKind::OpenBracket
+            }
+            ']' => {
+                take(&mut chars);
+                TokenKind::CloseBracket
+            }
+            '"' => {
+                take(&mut chars);
+                let mut string = String::new();
+                loop {
+                    match take(&mut chars) {
+                        Some('"') => break,
+                        Some('\n') | None => return Err((start_line, start_column, "unterminated string".to_string())),
+                        Some(c) => string.push(c),
+                    }
+                }
+                TokenKind::String(string)
+            }
+            _ => {
+                let mut word = String::new();
+                while chars.peek().is_some_and(|&c| !c.is_whitespace() && !matches!(c, '[' | ']' | '"' | '#')) {
+                    word.extend(take(&mut chars));
+                }
+
+                if word.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                    TokenKind::Identifier(word)
+                } else {
+                    TokenKind::Number(word.parse().map_err(|_| (start_line, start_column, format!("invalid number {}", word)))?)
+                }
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            line: start_line,
+            column: start_column,
+        });
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+// a parameter of a directive, like "float radius" [ 2 ]
+#[derive(Debug, Clone, PartialEq)]
+struct Param {
+    ty: String,
+    name: String,
+    values: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Params(Vec<Param>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&Param> {
+        self.0.iter().find(|param| param.name == name)
+    }
+
+    fn numbers(&self, name: &str) -> Option<Vec<f64>> {
+        self.get(name).map(|param| {
+            param.values.iter().filter_map(|value| match value {
+                Value::Number(number) => Some(*number),
+                _ => None,
+            }).collect()
+        })
+    }
+
+    fn float(&self, name: &str, default: Float) -> Float {
+        self.numbers(name).and_then(|numbers| numbers.first().copied()).map_or(default, |number| number as Float)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|param| match param.values.first() {
+            Some(Value::String(string)) => Some(string.as_str()),
+            _ => None,
+        })
+    }
+
+    fn bool(&self, name: &str, default: bool) -> bool {
+        match self.get(name).and_then(|param| param.values.first()) {
+            Some(Value::Bool(value)) => *value,
+            Some(Value::String(value)) => value == "true",
+            _ => default,
+        }
+    }
+
+    fn point(&self, name: &str, default: Point3) -> Point3 {
+        match self.numbers(name).as_deref() {
+            Some(&[x, y, z, ..]) => Point3::new(x as Float, y as Float, z as Float),
+            _ => default,
+        }
+    }
+
+    // the points, the normals or the vectors of a mesh
+    fn vectors(&self, name: &str) -> Option<Vec<Vec3>> {
+        self.numbers(name).map(|numbers| {
+            numbers.chunks_exact(3).map(|v| Vec3::new(v[0] as Float, v[1] as Float, v[2] as Float)).collect()
+        })
+    }
+
+    // the color of an rgb parameter. The sampled spectra are averaged to grey and
+    // the blackbody emitters are white, the named spectra and the textures have none
+    fn color(&self, name: &str) -> Option<Color> {
+        let param = self.get(name)?;
+        let numbers = self.numbers(name)?;
+
+        match param.ty.as_str() {
+            "rgb" | "color" => match numbers.as_slice() {
+                &[r, g, b, ..] => Some(Color::new(r as Float, g as Float, b as Float)),
+                _ => None,
+            },
+            "spectrum" if numbers.len() >= 2 => {
+                let values: Vec<f64> = numbers.iter().skip(1).step_by(2).copied().collect();
+                Some(Color::splat((values.iter().sum::<f64>() / values.len() as f64) as Float))
+            }
+            // pbrt-v3 gives the scale of the blackbody after its temperature
+            "blackbody" => Some(Color::splat(numbers.get(1).copied().unwrap_or(1.0) as Float)),
+            _ => None,
+        }
+    }
+}
+
+// the state the attributes of the shapes are taken from
+#[derive(Clone)]
+struct GraphicsState {
+    transform: Mat4, // object space to the space of pbrt
+    material: Option<SharedMaterial>, // None for the interface material, whose shapes are invisible
+    area_light: Option<(Color, bool)>, // the emission and whether both sides emit
+    reverse_orientation: bool,
+}
+
+struct Loader {
+    state: GraphicsState,
+    attributes: Vec<GraphicsState>,
+    transforms: Vec<Mat4>,
+    coordinate_systems: HashMap<String, Mat4>,
+    named_materials: HashMap<String, Option<SharedMaterial>>,
+    camera: Option<(Mat4, Params)>, // the camera to pbrt space transform and the parameters of the camera
+    film: Params,
+    sampler: Params,
+    integrator: Params,
+    world: World,
+    lights: Vec<PunctualLight>,
+    background: Background,
+    open_files: Vec<PathBuf>, // the canonical paths of the files being parsed, the includes after the file including them
+}
+
+// the parser of the tokens of one file
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    path: &'a Path,
+}
+
+impl Parser<'_> {
+    fn error(&self, token: Option<&Token>, message: String) -> LoadError {
+        match token.or(self.tokens.last()) {
+            Some(token) => LoadError::Parse(format!("{}: line {}, column {}: {}", self.path.display(), token.line, token.column, message)),
+            None => LoadError::Parse(format!("{}: {}", self.path.display(), message)),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn number(&mut self) -> Result<f64, LoadError> {
+        match self.next() {
+            Some(Token { kind: TokenKind::Number(number), .. }) => Ok(number),
+            token => Err(self.error(token.as_ref(), "expected a number".to_string())),
+        }
+    }
+
+    fn numbers<const N: usize>(&mut self) -> Result<[Float; N], LoadError> {
+        let mut numbers = [0.0; N];
+        for number in &mut numbers {
+            *number = self.number()? as Float;
+        }
+        Ok(numbers)
+    }
+
+    // a list of numbers, in brackets or not
+    fn number_list(&mut self) -> Result<Vec<f64>, LoadError> {
+        let bracketed = matches!(self.peek(), Some(Token { kind: TokenKind::OpenBracket, .. }));
+        if bracketed {
+            self.next();
+        }
+
+        let mut numbers = Vec::new();
+        while let Some(Token { kind: TokenKind::Number(number), .. }) = self.peek() {
+            numbers.push(*number);
+            self.next();
+        }
+
+        if bracketed {
+            match self.next() {
+                Some(Token { kind: TokenKind::CloseBracket, .. }) => {}
+                token => return Err(self.error(token.as_ref(), "expected ]".to_string())),
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        match self.next() {
+            Some(Token { kind: TokenKind::String(string), .. }) => Ok(string),
+            token => Err(self.error(token.as_ref(), "expected a string".to_string())),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, LoadError> {
+        match self.next() {
+            Some(Token { kind: TokenKind::Number(number), .. }) => Ok(Value::Number(number)),
+            Some(Token { kind: TokenKind::String(string), .. }) => Ok(Value::String(string)),
+            Some(Token { kind: TokenKind::Identifier(word), .. }) if word == "true" || word == "false" => Ok(Value::Bool(word == "true")),
+            token => Err(self.error(token.as_ref(), "expected a value".to_string())),
+        }
+    }
+
+    // the parameters following a directive, each a string with the type and the name and its values
+    fn params(&mut self) -> Result<Params, LoadError> {
+        let mut params = Vec::new();
+
+        while let Some(Token { kind: TokenKind::String(declaration), .. }) = self.peek() {
+            let declaration = declaration.clone();
+            let token = self.next();
+
+            let (ty, name) = match declaration.split_whitespace().collect::<Vec<_>>().as_slice() {
+                &[ty, name] => (ty.to_string(), name.to_string()),
+                _ => return Err(self.error(token.as_ref(), format!("invalid parameter {}", declaration))),
+            };
+
+            let mut values = Vec::new();
+            if matches!(self.peek(), Some(Token { kind: TokenKind::OpenBracket, .. })) {
+                self.next();
+                while !matches!(self.peek(), Some(Token { kind: TokenKind::CloseBracket, .. })) {
+                    values.push(self.value()?);
+                }
+                self.next();
+            } else {
+                values.push(self.value()?);
+            }
+
+            params.push(Param { ty, name, values });
+        }
+
+        Ok(Params(params))
+    }
+}
+
+// mirrors the left-handed space of pbrt into the right-handed space of the renderer
+fn mirror() -> Mat4 {
+    Mat4::from_scale(Vec3::new(-1.0, 1.0, 1.0))
+}
+
+impl Loader {
+    fn new() -> Self {
+        Self {
+            state: GraphicsState {
+                transform: Mat4::IDENTITY,
+                material: Some(Arc::new(Lambertian::new(DEFAULT_REFLECTANCE))),
+                area_light: None,
+                reverse_orientation: false,
+            },
+            attributes: Vec::new(),
+            transforms: Vec::new(),
+            coordinate_systems: HashMap::new(),
+            named_materials: HashMap::new(),
+            camera: None,
+            film: Params::default(),
+            sampler: Params::default(),
+            integrator: Params::default(),
+            world: World::new(),
+            lights: Vec::new(),
+            background: Background::Color(Color::ZERO),
+            open_files: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<(), LoadError> {
+        let source = fs::read_to_string(path)?;
+        let tokens = tokenize(&source).map_err(|(line, column, message)| {
+            LoadError::Parse(format!("{}: line {}, column {}: {}", path.display(), line, column, message))
+        })?;
+
+        let mut parser = Parser { tokens, position: 0, path };
+        let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        self.open_files.push(fs::canonicalize(path)?);
+
+        while let Some(token) = parser.next() {
+            let directive = match &token.kind {
+                TokenKind::Identifier(directive) => directive.clone(),
+                _ => return Err(parser.error(Some(&token), "expected a directive".to_string())),
+            };
+
+            self.directive(&mut parser, &token, &directive, &directory)?;
+        }
+
+        self.open_files.pop();
+        Ok(())
+    }
+
+    fn directive(&mut self, parser: &mut Parser, token: &Token, directive: &str, directory: &Path) -> Result<(), LoadError> {
+        match directive {
+            "Identity" => self.state.transform = Mat4::IDENTITY,
+            "Translate" => {
+                let [x, y, z] = parser.numbers()?;
+                self.state.transform *= Mat4::from_translation(Vec3::new(x, y, z));
+            }
+            "Scale" => {
+                let [x, y, z] = parser.numbers()?;
+                self.state.transform *= Mat4::from_scale(Vec3::new(x, y, z));
+            }
+            "Rotate" => {
+                let [angle, x, y, z] = parser.numbers()?;
+                let axis = Vec3::new(x, y, z)
+                    .try_normalize()
+                    .ok_or_else(|| parser.error(Some(token), "the rotation axis is zero".to_string()))?;
+                self.state.transform *= Mat4::from_axis_angle(axis, angle.to_radians());
+            }
+            "LookAt" => {
+                let [ex, ey, ez, lx, ly, lz, ux, uy, uz] = parser.numbers()?;
+                let look_at = Mat4::look_at_lh(Point3::new(ex, ey, ez), Point3::new(lx, ly, lz), Vec3::new(ux, uy, uz));
+                self.state.transform *= look_at;
+            }
+            "Transform" | "ConcatTransform" => {
+                let numbers = parser.number_list()?;
+                let numbers: [f64; 16] = numbers
+                    .try_into()
+                    .map_err(|_| parser.error(Some(token), format!("{} expects 16 numbers", directive)))?;
+                let matrix = Mat4::from_cols_array(&numbers.map(|number| number as Float));
+
+                if directive == "Transform" {
+                    self.state.transform = matrix;
+                } else {
+                    self.state.transform *= matrix;
+                }
+            }
+            "CoordinateSystem" => {
+                let name = parser.string()?;
+                self.coordinate_systems.insert(name, self.state.transform);
+            }
+            "CoordSysTransform" => {
+                let name = parser.string()?;
+                self.state.transform = *self.coordinate_systems
+                    .get(&name)
+                    .ok_or_else(|| parser.error(Some(token), format!("unknown coordinate system {}", name)))?;
+            }
+            "WorldBegin" => {
+                self.state.transform = Mat4::IDENTITY;
+                self.coordinate_systems.insert("world".to_string(), Mat4::IDENTITY);
+            }
+            "WorldEnd" => {}
+            "AttributeBegin" => self.attributes.push(self.state.clone()),
+            "AttributeEnd" => {
+                self.state = self.attributes
+                    .pop()
+                    .ok_or_else(|| parser.error(Some(token), "AttributeEnd without AttributeBegin".to_string()))?;
+            }
+            "TransformBegin" => self.transforms.push(self.state.transform),
+            "TransformEnd" => {
+                self.state.transform = self.transforms
+                    .pop()
+                    .ok_or_else(|| parser.error(Some(token), "TransformEnd without TransformBegin".to_string()))?;
+            }
+            "ReverseOrientation" => self.state.reverse_orientation = !self.state.reverse_orientation,
+            "Camera" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                if ty != "perspective" {
+                    return Err(parser.error(Some(token), format!("unsupported camera {}", ty)));
+                }
+
+                let camera_to_world = self.state.transform.inverse();
+                self.coordinate_systems.insert("camera".to_string(), camera_to_world);
+                self.camera = Some((camera_to_world, params));
+            }
+            "Film" | "Sampler" | "Integrator" => {
+                parser.string()?;
+                let params = parser.params()?;
+                match directive {
+                    "Film" => self.film = params,
+                    "Sampler" => self.sampler = params,
+                    _ => self.integrator = params,
+                }
+            }
+            "Material" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                self.state.material = convert_material(&ty, &params).map_err(|message| parser.error(Some(token), message))?;
+            }
+            "MakeNamedMaterial" => {
+                let name = parser.string()?;
+                let params = parser.params()?;
+                let ty = params.string("type").unwrap_or("diffuse").to_string();
+                let material = convert_material(&ty, &params).map_err(|message| parser.error(Some(token), message))?;
+                self.named_materials.insert(name, material);
+            }
+            "NamedMaterial" => {
+                let name = parser.string()?;
+                self.state.material = self.named_materials
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| parser.error(Some(token), format!("unknown material {}", name)))?;
+            }
+            "AreaLightSource" => {
+                parser.string()?;
+                let params = parser.params()?;
+                let emission = params.color("L").unwrap_or(Color::ONE) * params.float("scale", 1.0);
+                self.state.area_light = Some((emission, params.bool("twosided", false)));
+            }
+            "LightSource" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                self.light(&ty, &params).map_err(|message| parser.error(Some(token), message))?;
+            }
+            "Shape" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                self.shape(&ty, &params, directory).map_err(|message| parser.error(Some(token), message))?;
+            }
+            "Include" | "Import" => {
+                let file = parser.string()?;
+                let path = directory.join(&file);
+
+                // a file including itself, directly or through other files, would never end
+                if fs::canonicalize(&path).is_ok_and(|path| self.open_files.contains(&path)) {
+                    return Err(parser.error(Some(token), format!("{} includes itself", file)));
+                }
+                self.parse_file(&path)?;
+            }
+            // the settings the renderer has no use for
+            "PixelFilter" | "Accelerator" | "ColorSpace" | "MakeNamedMedium" | "Attribute" => {
+                parser.string()?;
+                parser.params()?;
+            }
+            "Texture" => {
+                parser.string()?;
+                parser.string()?;
+                parser.string()?;
+                parser.params()?;
+            }
+            "Option" => {
+                parser.params()?;
+            }
+            "MediumInterface" => {
+                parser.string()?;
+                if matches!(parser.peek(), Some(Token { kind: TokenKind::String(_), .. })) {
+                    parser.string()?;
+                }
+            }
+            "ActiveTransform" => {
+                parser.next();
+            }
+            "TransformTimes" => {
+                parser.numbers::<2>()?;
+            }
+            _ => return Err(parser.error(Some(token), format!("unsupported directive {}", directive))),
+        }
+
+        Ok(())
+    }
+
+    // the transform of the current object space into the space of the renderer
+    fn object_to_world(&self) -> Mat4 {
+        mirror() * self.state.transform
+    }
+
+    // the material of the shapes, the emission of the area light if there is one
+    fn shape_material(&self) -> Option<SharedMaterial> {
+        match self.state.area_light {
+            Some((emission, two_sided)) => Some(Arc::new(DiffuseLight::new(emission, two_sided))),
+            None => self.state.material.clone(),
+        }
+    }
+
+    fn shape(&mut self, ty: &str, params: &Params, directory: &Path) -> Result<(), String> {
+        let material = self.shape_material();
+
+        match ty {
+            "sphere" => {
+                let Some(material) = material else { return Ok(()) };
+                let radius = params.float("radius", 1.0);
+
+                // the reversed spheres face inwards, like the hollow spheres with a negative radius
+                let radius = if self.state.reverse_orientation { -radius } else { radius };
+                self.add_sphere(radius, material);
+            }
+            "trianglemesh" => {
+                let positions = params.vectors("P").ok_or("the triangle mesh has no positions P")?;
+
+                let indices = match params.numbers("indices") {
+                    Some(indices) => indices,
+                    None if positions.len() == 3 => vec![0.0, 1.0, 2.0],
+                    None => return Err("the triangle mesh has no indices".to_string()),
+                };
+                if indices.len() % 3 != 0 || indices.iter().any(|&i| i < 0.0 || i.fract() != 0.0 || i as usize >= positions.len()) {
+                    return Err("the indices of the triangle mesh are invalid".to_string());
+                }
+                let indices = indices.chunks_exact(3).map(|t| [t[0] as u32, t[1] as u32, t[2] as u32]).collect();
+
+                let normals = params.vectors("N").filter(|normals| normals.len() == positions.len());
+                let uvs = params.numbers("uv").or_else(|| params.numbers("st")).and_then(|uvs| {
+                    (uvs.len() == 2 * positions.len()).then(|| {
+                        uvs.chunks_exact(2).map(|uv| Vec2::new(uv[0] as Float, uv[1] as Float)).collect()
+                    })
+                });
+
+                if let Some(material) = material {
+                    self.add_mesh(positions, indices, normals, uvs, material);
+                }
+            }
+            "plymesh" => {
+                let file = params.string("filename").ok_or("the ply mesh has no filename")?;
+                let mesh = load_ply(directory.join(file)).map_err(|err| err.to_string())?;
+
+                if let Some(material) = material {
+                    self.add_mesh(mesh.positions, mesh.indices, mesh.normals, mesh.uvs, material);
+                }
+            }
+            _ => return Err(format!("unsupported shape {}", ty)),
+        }
+
+        Ok(())
+    }
+
+    fn add_sphere(&mut self, radius: Float, material: SharedMaterial) {
+        let transform = Affine3::from_mat4(self.object_to_world());
+        let [x, y, z] = [transform.matrix3.x_axis, transform.matrix3.y_axis, transform.matrix3.z_axis].map(Vec3::from);
+
+        // a sphere rotated, translated and scaled uniformly is still a sphere,
+        // under any other transform it is an instance of the sphere in object space
+        let scale = x.length();
+        let similar = (y.length() - scale).abs() <= 1.0e-4 * scale && (z.length() - scale).abs() <= 1.0e-4 * scale &&
+            x.dot(y).abs() <= 1.0e-4 * scale * scale && y.dot(z).abs() <= 1.0e-4 * scale * scale && z.dot(x).abs() <= 1.0e-4 * scale * scale;
+
+        if similar {
+            self.world.push(Box::new(Sphere::new(transform.transform_point3(Point3::ZERO), radius * scale, material)));
+        } else if transform.matrix3.determinant() != 0.0 {
+            let mut bvh = Bvh::new(None, None);
+            bvh.build(vec![Box::new(Sphere::new(Point3::ZERO, radius, material))]);
+            self.world.push(Box::new(Instance::new(Arc::new(bvh), transform)));
+        }
+    }
+
+    // the vertices of the mesh are transformed into the space of the renderer
+    fn add_mesh(&mut self,
+                positions: Vec<Point3>,
+                mut indices: Vec<[u32; 3]>,
+                normals: Option<Vec<Vec3>>,
+                uvs: Option<Vec<Vec2>>,
+                material: SharedMaterial) {
+
+        let transform = Affine3::from_mat4(self.object_to_world());
+        let normal_matrix = transform.matrix3.inverse().transpose();
+
+        let positions: Vec<Point3> = positions.iter().map(|&p| transform.transform_point3(p)).collect();
+        let normals: Option<Vec<Vec3>> = normals.map(|normals| normals.iter().map(|&n| (normal_matrix * n).normalize()).collect());
+
+        // the triangles face the side their area lights emit to, which is the side of the normals
+        // of the vertices if there are any. Otherwise the winding of the triangles gives the side,
+        // which the mirroring transforms and the reversed orientation turn around
+        let flip = (transform.matrix3.determinant() < 0.0) != self.state.reverse_orientation;
+
+        for triangle in &mut indices {
+            let [a, b, c] = triangle.map(|i| positions[i as usize]);
+            let facing = match &normals {
+                Some(normals) => {
+                    let normal: Vec3 = triangle.iter().map(|&i| normals[i as usize]).sum();
+                    (b - a).cross(c - a).dot(normal) >= 0.0
+                }
+                None => !flip,
+            };
+
+            if !facing {
+                triangle.swap(1, 2);
+            }
+        }
+
+        let mesh = Arc::new(TriangleMesh::new(positions, indices, normals, uvs, material));
+        self.world.extend(mesh.triangles());
+    }
+
+    fn light(&mut self, ty: &str, params: &Params) -> Result<(), String> {
+        let transform = self.object_to_world();
+        let color = params.color("I").or_else(|| params.color("L")).unwrap_or(Color::ONE);
+        let intensity = params.float("scale", 1.0);
+
+        let from = params.point("from", Point3::ZERO);
+        let to = params.point("to", Point3::Z);
+        // the point lights shine in every direction, the others need one
+        let direction = transform.transform_vector3(to - from).try_normalize();
+        let aimed = || direction.ok_or_else(|| format!("the {} light points from where it points to", ty));
+
+        let (kind, direction) = match ty {
+            "point" => (LightKind::Point, direction.unwrap_or(Vec3::Z)),
+            "distant" => (LightKind::Directional, aimed()?),
+            "spot" => {
+                let cone_angle = params.float("coneangle", 30.0);
+                let cone_delta = params.float("conedelta", 5.0);
+                let kind = LightKind::Spot {
+                    inner_cone_angle: (cone_angle - cone_delta).max(0.0).to_radians(),
+                    outer_cone_angle: cone_angle.to_radians(),
+                };
+                (kind, aimed()?)
+            }
+            // the infinite lights add up, the sky stands in for the environment maps
+            "infinite" => {
+                self.background = match self.background {
+                    Background::Color(background) if params.string("filename").or(params.string("mapname")).is_none() => {
+                        Background::Color(background + color * intensity)
+                    }
+                    _ => Background::Sky,
+                };
+                return Ok(());
+            }
+            _ => return Err(format!("unsupported light {}", ty)),
+        };
+
+        self.lights.push(PunctualLight {
+            kind,
+            position: transform.transform_point3(from),
+            direction,
+            color,
+            intensity,
+            range: None,
+        });
+
+        Ok(())
+    }
+
+    fn finish(self) -> Scene {
+        let width = self.film.numbers("xresolution").and_then(|x| x.first().copied()).map_or(DEFAULT_RESOLUTION.0, |x| x as u32);
+        let height = self.film.numbers("yresolution").and_then(|y| y.first().copied()).map_or(DEFAULT_RESOLUTION.1, |y| y as u32);
+
+        let mut output = PathBuf::from(self.film.string("filename").unwrap_or(DEFAULT_FILENAME));
+        let extension = output.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+        if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            output.set_extension("png");
+        }
+
+        let image = ImageSettings {
+            width,
+            height,
+            samples_per_pixel: self.sampler.float("pixelsamples", DEFAULT_PIXEL_SAMPLES as Float) as u32,
+            max_depth: self.integrator.float("maxdepth", DEFAULT_MAX_DEPTH as Float) as u32,
+            background: self.background,
+            output,
+        };
+
+        // pbrt looks down the z axis of the camera space, with the y axis up
+        let (camera_to_world, params) = self.camera.unwrap_or((Mat4::IDENTITY, Params::default()));
+        let camera_to_world = mirror() * camera_to_world;
+
+        // the field of view spans the shorter side of the image
+        let fov = params.float("fov", DEFAULT_FOV);
+        let aspect_ratio = image.aspect_ratio();
+        let vfov = if aspect_ratio >= 1.0 {
+            fov
+        } else {
+            (2.0 * ((fov.to_radians() / 2.0).tan() / aspect_ratio).atan()).to_degrees()
+        };
+
+        // the rays of the camera span the distance in focus, which is kept short enough for the
+        // precision of the hits. A pinhole is in focus anywhere, and pbrt focuses a lens at infinity
+        let lens_radius = params.float("lensradius", 0.0);
+        let focus_dist = params.float("focaldistance", if lens_radius > 0.0 { FAR_FOCUS } else { 1.0 }).min(FAR_FOCUS);
+
+        let camera = CameraSettings {
+            lookfrom: camera_to_world.transform_point3(Point3::ZERO),
+            lookat: camera_to_world.transform_point3(Point3::Z),
+            vup: camera_to_world.transform_vector3(Vec3::Y),
+            vfov,
+            aspect_ratio: None,
+            aperture: 2.0 * lens_radius,
+            focus_dist,
+        };
+
+        Scene {
+            world: self.world,
+            camera,
+            lights: self.lights,
+            image,
+        }
+    }
+}
+
+fn convert_material(ty: &str, params: &Params) -> Result<Option<SharedMaterial>, String> {
+    let reflectance = |names: &[&str], default: Color| names.iter().find_map(|name| params.color(name)).unwrap_or(default);
+
+    let material: SharedMaterial = match ty {
+        "" | "none" | "interface" => return Ok(None),
+        "conductor" | "metal" => {
+            // the named spectra of the index of refraction tell the metal
+            let metal = params.string("eta").and_then(|eta| METALS.iter().find(|(name, _)| eta.contains(name)));
+            let albedo = params.color("reflectance").or(metal.map(|(_, color)| *color)).unwrap_or(COPPER);
+            let roughness = params.float("roughness", params.float("uroughness", 0.0));
+            Arc::new(Metal::new(albedo, roughness.clamp(0.0, 1.0)))
+        }
+        "mirror" => Arc::new(Metal::new(reflectance(&["Kr"], Color::splat(0.9)), 0.0)),
+        "dielectric" | "glass" | "thindielectric" => {
+            let eta = params.float("eta", params.float("index", DEFAULT_INDEX_OF_REFRACTION));
+            Arc::new(Dielectric::new(eta))
+        }
+        "diffuse" | "matte" | "plastic" | "coateddiffuse" | "substrate" | "uber" => {
+            Arc::new(Lambertian::new(reflectance(&["reflectance", "Kd"], DEFAULT_REFLECTANCE)))
+        }
+        _ => return Err(format!("unsupported material {}", ty)),
+    };
+
+    Ok(Some(material))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::RAY_EPSILON;
+    use crate::hit::Hit;
+    use crate::loaders::tests::{assert_reports_missing_and_malformed, TestDirectory};
+    use crate::ray::Ray;
+
+    fn load(name: &str, source: &str) -> Result<Scene, LoadError> {
+        let directory = TestDirectory::new(name);
+        load_pbrt(directory.write("scene.pbrt", source))
+    }
+
+    const SCENE: &str = r#"
+# a red sphere on a mirror floor lit by a quad light, seen from the front
+LookAt 0 1 -10   0 1 0   0 1 0
+Camera "perspective" "float fov" [ 30 ]
+Film "rgb" "integer xresolution" [ 400 ] "integer yresolution" [ 200 ] "string filename" "sphere.exr"
+Sampler "halton" "integer pixelsamples" 64
+Integrator "volpath" "integer maxdepth" [ 8 ]
+
+WorldBegin
+
+MakeNamedMaterial "floor" "string type" "conductor" "spectrum eta" "metal-Ag-eta" "float roughness" 0.1
+
+AttributeBegin
+  Material "diffuse" "rgb reflectance" [ 0.8 0.1 0.1 ]
+  Translate 2 1 0
+  Shape "sphere" "float radius" 1
+AttributeEnd
+
+AttributeBegin
+  NamedMaterial "floor"
+  Shape "trianglemesh" "point3 P" [ -10 0 -10  10 0 -10  10 0 10  -10 0 10 ]
+      "integer indices" [ 0 1 2  0 2 3 ]
+AttributeEnd
+
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [ 4 4 4 ]
+  Translate 0 5 0
+  Rotate 90 1 0 0
+  Shape "trianglemesh" "point3 P" [ -1 -1 0  1 -1 0  1 1 0  -1 1 0 ] "integer indices" [ 0 1 2  0 2 3 ]
+AttributeEnd
+
+LightSource "spot" "point3 from" [ 0 4 0 ] "point3 to" [ 0 0 0 ] "float coneangle" 20
+LightSource "infinite" "rgb L" [ 1 1 1 ]
+"#;
+
+    fn hit(scene: &Scene, origin: Point3, direction: Vec3) -> Option<(Point3, Color, Color)> {
+        let ray = Ray::new(origin, direction);
+        let rec = scene.world.hit(&ray, RAY_EPSILON, Float::INFINITY)?;
+        let attenuation = (0..100).find_map(|_| rec.mat.scatter(&ray, &rec)).map_or(Color::ZERO, |(attenuation, _)| attenuation);
+        Some((rec.p, attenuation, rec.mat.emitted(&rec)))
+    }
+
+    #[test]
+    fn loads_the_scene() {
+        let scene = load("pbrt_scene", SCENE).unwrap();
+
+        assert_eq!((scene.image.width, scene.image.height), (400, 200));
+        assert_eq!(scene.image.samples_per_pixel, 64);
+        assert_eq!(scene.image.max_depth, 8);
+        assert_eq!(scene.image.output, PathBuf::from("sphere.png"));
+
+        // the sphere is mirrored to x = -2, the floor and the light quad are two triangles each
+        assert_eq!(scene.world.len(), 5);
+        let (p, attenuation, _) = hit(&scene, Point3::new(-2.0, 1.0, -10.0), Vec3::Z).unwrap();
+        assert!((p - Point3::new(-2.0, 1.0, -1.0)).length() < 0.0001);
+        assert!((attenuation - Color::new(0.8, 0.1, 0.1)).length() < 0.0001);
+
+        // the floor is silver
+        let (p, attenuation, _) = hit(&scene, Point3::new(5.0, 3.0, 5.0), -Vec3::Y).unwrap();
+        assert!(p.y.abs() < 0.0001);
+        assert!((attenuation - Color::new(0.972, 0.960, 0.915)).length() < 0.0001);
+
+        // the quad light is turned to face down, it emits below and not above
+        let (p, _, emitted) = hit(&scene, Point3::new(0.5, 3.0, 0.5), Vec3::Y).unwrap();
+        assert!((p.y - 5.0).abs() < 0.0001);
+        assert_eq!(emitted, Color::splat(4.0));
+        let (_, _, emitted) = hit(&scene, Point3::new(0.5, 8.0, 0.5), -Vec3::Y).unwrap();
+        assert_eq!(emitted, Color::ZERO);
+
+        // the infinite light is the background, the spot light is kept
+        assert_eq!(scene.image.background, Background::Color(Color::ONE));
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.lights[0].direction, -Vec3::Y);
+        assert!(matches!(scene.lights[0].kind, LightKind::Spot { outer_cone_angle, .. } if (outer_cone_angle - (20.0 as Float).to_radians()).abs() < 0.0001));
+    }
+
+    #[test]
+    fn camera_looks_like_pbrt() {
+        let scene = load("pbrt_camera", SCENE).unwrap();
+        let camera = &scene.camera;
+
+        assert!((camera.lookfrom - Point3::new(0.0, 1.0, -10.0)).length() < 0.0001);
+        assert!((camera.lookat - camera.lookfrom).normalize().dot(Vec3::Z) > 0.9999);
+        assert!((camera.vup - Vec3::Y).length() < 0.0001);
+        assert_eq!(camera.vfov, 30.0);
+
+        // pbrt shows the sphere at x = 2 right of the center of the image, as the
+        // renderer does with the sphere mirrored to x = -2
+        let camera = camera.camera(scene.image.aspect_ratio());
+        let ray = camera.get_ray(0.7, 0.5);
+        let rec = scene.world.hit(&ray, RAY_EPSILON, Float::INFINITY).unwrap();
+        assert!(rec.p.x < -1.0);
+
+        // the field of view of a portrait image spans its width
+        let portrait = load("pbrt_portrait", &SCENE.replace("[ 400 ] \"integer yresolution\" [ 200 ]", "[ 200 ] \"integer yresolution\" [ 400 ]")).unwrap();
+        let expected = 2.0 * ((15.0 as Float).to_radians().tan() * 2.0).atan().to_degrees();
+        assert!((portrait.camera.vfov - expected).abs() < 0.0001);
+    }
+
+    #[test]
+    fn transforms_spheres_and_ply_meshes() {
+        let directory = TestDirectory::new("pbrt_shapes");
+        directory.write("triangle.ply", "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n");
+        let shapes = directory.write("shapes.pbrt", r#"
+WorldBegin
+TransformBegin
+  Translate 0 0 5
+  Scale 2 2 2
+  Shape "plymesh" "string filename" "triangle.ply"
+TransformEnd
+AttributeBegin
+  Scale 1 3 1
+  Shape "sphere"
+AttributeEnd
+"#);
+
+        let scene = load_pbrt(shapes).unwrap();
+
+        // the triangle is scaled by 2 and mirrored, the sphere is stretched into an instance
+        assert_eq!(scene.world.len(), 2);
+        assert!(hit(&scene, Point3::new(-0.5, 0.5, 2.0), Vec3::Z).is_some_and(|(p, _, _)| (p.z - 5.0).abs() < 0.0001));
+        assert!(hit(&scene, Point3::new(-1.5, 0.2, 2.0), Vec3::Z).is_some_and(|(p, _, _)| (p.z - 5.0).abs() < 0.0001));
+        assert!(hit(&scene, Point3::new(0.0, 2.5, -10.0), Vec3::Z).is_some_and(|(p, _, _)| p.z < 0.0 && p.z > -1.0));
+    }
+
+    #[test]
+    fn reversed_spheres_emit_inwards() {
+        let scene = load("pbrt_reversed", r#"
+WorldBegin
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [ 2 2 2 ]
+  ReverseOrientation
+  Shape "sphere" "float radius" 10
+AttributeEnd
+"#).unwrap();
+
+        // the reversed sphere is bounded like any other, the accelerators do not cull it
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(scene.world);
+
+        let ray = Ray::new(Point3::ZERO, Vec3::new(0.3, 0.2, 1.0));
+        let rec = bvh.intersect(&ray, RAY_EPSILON, Float::INFINITY).unwrap();
+        assert_eq!(rec.mat.emitted(&rec), Color::splat(2.0));
+
+        let outside = Ray::new(Point3::new(0.0, 0.0, 20.0), -Vec3::Z);
+        let rec = bvh.intersect(&outside, RAY_EPSILON, Float::INFINITY).unwrap();
+        assert_eq!(rec.mat.emitted(&rec), Color::ZERO);
+    }
+
+    #[test]
+    fn infinite_lights_light_the_background() {
+        let background = |lights: &str| load("pbrt_background", &format!("WorldBegin\n{}Shape \"sphere\"\n", lights)).unwrap().image.background;
+
+        assert_eq!(background(""), Background::Color(Color::ZERO));
+        assert_eq!(background("LightSource \"infinite\"\n"), Background::Color(Color::ONE));
+        assert_eq!(
+            background("LightSource \"infinite\" \"rgb L\" [ 0.5 0.2 0.1 ] \"float scale\" 2\nLightSource \"infinite\" \"rgb L\" [ 0 0.1 0.2 ]\n"),
+            Background::Color(Color::new(1.0, 0.5, 0.4))
+        );
+        assert_eq!(background("LightSource \"infinite\" \"string filename\" \"sky.exr\"\n"), Background::Sky);
+    }
+
+    #[test]
+    fn includes_files_once_at_a_time() {
+        let directory = TestDirectory::new("pbrt_include");
+        directory.write("sphere.pbrt", "Shape \"sphere\"\n");
+        let scene = directory.write("scene.pbrt", "WorldBegin\nInclude \"sphere.pbrt\"\nTranslate 0 0 5\nInclude \"sphere.pbrt\"\n");
+        assert_eq!(load_pbrt(scene).unwrap().world.len(), 2);
+
+        // the files including each other are reported where the cycle closes
+        directory.write("a.pbrt", "Shape \"sphere\"\nInclude \"b.pbrt\"\n");
+        directory.write("b.pbrt", "\n  Import \"a.pbrt\"\n");
+        match load_pbrt(directory.write("cycle.pbrt", "WorldBegin\nInclude \"a.pbrt\"\n")) {
+            Err(LoadError::Parse(message)) => assert!(message.ends_with("b.pbrt: line 2, column 3: a.pbrt includes itself"), "{}", message),
+            _ => panic!("the cycle is loaded"),
+        }
+
+        match load_pbrt(directory.write("itself.pbrt", "WorldBegin\nInclude \"itself.pbrt\"\n")) {
+            Err(LoadError::Parse(message)) => assert!(message.ends_with("line 2, column 1: itself.pbrt includes itself"), "{}", message),
+            _ => panic!("the file including itself is loaded"),
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_their_location() {
+        let error = |source: &str| match load("pbrt_errors", source) {
+            Err(LoadError::Parse(message)) => message,
+            _ => panic!("the scene is valid"),
+        };
+
+        let message = error("WorldBegin\nShape \"disk\" \"float radius\" 1\n");
+        assert!(message.ends_with("line 2, column 1: unsupported shape disk"), "{}", message);
+
+        let message = error("WorldBegin\n  NamedMaterial \"missing\"\n");
+        assert!(message.ends_with("line 2, column 3: unknown material missing"), "{}", message);
+
+        let message = error("WorldBegin\nShape \"trianglemesh\" \"point3 P\" [ 0 0 0  1 0 0  0 1 0 ] \"integer indices\" [ 0 1.5 2 ]\n");
+        assert!(message.ends_with("line 2, column 1: the indices of the triangle mesh are invalid"), "{}", message);
+
+        let message = error("Rotate 30 0 0 0\nWorldBegin\n");
+        assert!(message.ends_with("line 1, column 1: the rotation axis is zero"), "{}", message);
+
+        let message = error("WorldBegin\nLightSource \"spot\" \"point3 from\" [ 0 1 0 ] \"point3 to\" [ 0 1 0 ]\n");
+        assert!(message.ends_with("line 2, column 1: the spot light points from where it points to"), "{}", message);
+
+        // a point light shines in every direction, wherever it points to
+        assert!(load("pbrt_point", "WorldBegin\nLightSource \"point\" \"point3 from\" [ 0 0 1 ]\n").is_ok());
+
+        let message = error("WorldBegin\nLightSource \"goniometric\" \"string filename\" \"light.exr\"\n");
+        assert!(message.ends_with("line 2, column 1: unsupported light goniometric"), "{}", message);
+
+        let message = error("WorldBegin\nMakeNamedMaterial \"hair\" \"string type\" \"hair\"\n");
+        assert!(message.ends_with("line 2, column 1: unsupported material hair"), "{}", message);
+
+        let message = error("Translate 1 2\nWorldBegin\n");
+        assert!(message.ends_with("line 2, column 1: expected a number"), "{}", message);
+
+        let message = error("WorldBegin\nShape \"sphere\" \"float radius [ 1 ]\n");
+        assert!(message.contains("line 2") && message.contains("unterminated string"), "{}", message);
+
+        let message = error("WorldBegin\nObjectBegin \"tree\"\n");
+        assert!(message.contains("unsupported directive ObjectBegin"), "{}", message);
+
+        assert_reports_missing_and_malformed(load_pbrt, "broken.pbrt", "WorldBegin\nShape \"sphere\" \"float radius\"\n");
+    }
+}
@@ -0,0 +1,551 @@
+use std::fs;
+use std::path::Path;
+
+use crate::loaders::LoadError;
+use crate::material::Scatter;
+use crate::primitives::mesh::TriangleMesh;
+use crate::utils::util::{Color, Float, Point3, Vec2, Vec3};
+
+// the names the writers give to the texture coordinates of the vertices
+const U_NAMES: [&str; 3] = ["u", "s", "texture_u"];
+const V_NAMES: [&str; 3] = ["v", "t", "texture_v"];
+
+/*
+    The vertices and the faces of a PLY file, with the faces split into triangles.
+    The normals, the colors and the texture coordinates are present when every
+    vertex has them. The colors are in [0, 1], whichever type the file stores them in.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlyMesh {
+    pub positions: Vec<Point3>,
+    pub indices: Vec<[u32; 3]>,
+    pub normals: Option<Vec<Vec3>>,
+    pub colors: Option<Vec<Color>>,
+    pub uvs: Option<Vec<Vec2>>,
+}
+
+impl PlyMesh {
+    // the triangle mesh with the given material, whose triangles are fed to the accelerators.
+    // The materials have a single color, so the colors of the vertices are left out
+    pub fn into_mesh<M: Scatter>(self, material: M) -> TriangleMesh<M> {
+        TriangleMesh::new(self.positions, self.indices, self.normals, self.uvs, material)
+    }
+}
+
+/*
+    Loads a PLY file in the ascii or the binary little or big endian format. The vertex
+    element gives the positions x, y and z, the normals nx, ny and nz, the colors red,
+    green and blue and the texture coordinates u and v. The face element gives the
+    polygons as a list of vertex indices, which are split into fans of triangles.
+    Any other element and property, like the edges or the confidence of a scan, is skipped.
+*/
+pub fn load_ply<P: AsRef<Path>>(path: P) -> Result<PlyMesh, LoadError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+
+    parse(&bytes).map_err(|err| match err {
+        LoadError::Parse(message) => LoadError::Parse(format!("{}: {}", path.display(), message)),
+        err => err,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self, LoadError> {
+        match name {
+            "char" | "int8" => Ok(ScalarType::Int8),
+            "uchar" | "uint8" => Ok(ScalarType::UInt8),
+            "short" | "int16" => Ok(ScalarType::Int16),
+            "ushort" | "uint16" => Ok(ScalarType::UInt16),
+            "int" | "int32" => Ok(ScalarType::Int32),
+            "uint" | "uint32" => Ok(ScalarType::UInt32),
+            "float" | "float32" => Ok(ScalarType::Float32),
+            "double" | "float64" => Ok(ScalarType::Float64),
+            _ => Err(LoadError::Parse(format!("unknown property type {}", name))),
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    // the scale mapping the values onto [0, 1] for the colors,
+    // which are stored as integers of the full range or as floats
+    fn color_scale(self) -> f64 {
+        match self {
+            ScalarType::Int8 => i8::MAX as f64,
+            ScalarType::UInt8 => u8::MAX as f64,
+            ScalarType::Int16 => i16::MAX as f64,
+            ScalarType::UInt16 => u16::MAX as f64,
+            ScalarType::Int32 => i32::MAX as f64,
+            ScalarType::UInt32 => u32::MAX as f64,
+            ScalarType::Float32 | ScalarType::Float64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PropertyKind {
+    Scalar(ScalarType),
+    List {
+        count: ScalarType,
+        item: ScalarType,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Property {
+    name: String,
+    kind: PropertyKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn scalar(&self, name: &str) -> Option<usize> {
+        self.properties.iter().position(|p| p.name == name && matches!(p.kind, PropertyKind::Scalar(_)))
+    }
+
+    fn any_scalar(&self, names: &[&str]) -> Option<usize> {
+        names.iter().find_map(|name| self.scalar(name))
+    }
+
+    fn list(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name.as_str()) && matches!(p.kind, PropertyKind::List { .. }))
+    }
+}
+
+// the next line of the header and the offset following it
+fn header_line(bytes: &[u8], offset: usize) -> Result<(&str, usize), LoadError> {
+    let end = bytes[offset..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| LoadError::Parse("the header is not terminated by end_header".to_string()))?;
+
+    let line = std::str::from_utf8(&bytes[offset..offset + end])
+        .map_err(|_| LoadError::Parse("the header is not ascii".to_string()))?;
+
+    Ok((line.trim(), offset + end + 1))
+}
+
+// the format and the elements of the header, and the offset of the body following it
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, usize), LoadError> {
+    let (magic, mut offset) = header_line(bytes, 0)?;
+    if magic != "ply" {
+        return Err(LoadError::Parse("the file does not start with ply".to_string()));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+
+    // the header is ascii, one keyword per line up to end_header
+    loop {
+        let (line, next) = header_line(bytes, offset)?;
+        offset = next;
+
+        match line.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+            ["end_header"] => break,
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, "1.0"] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(LoadError::Parse(format!("unknown format {}", name))),
+                });
+            }
+            ["element", name, count] => {
+                let count = count.parse().map_err(|_| LoadError::Parse(format!("invalid count of the element {}", name)))?;
+                elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: Vec::new(),
+                });
+            }
+            ["property", "list", count, item, name] => {
+                let element = elements.last_mut().ok_or_else(|| LoadError::Parse(format!("property {} outside of an element", name)))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind: PropertyKind::List {
+                        count: ScalarType::parse(count)?,
+                        item: ScalarType::parse(item)?,
+                    },
+                });
+            }
+            ["property", ty, name] => {
+                let element = elements.last_mut().ok_or_else(|| LoadError::Parse(format!("property {} outside of an element", name)))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind: PropertyKind::Scalar(ScalarType::parse(ty)?),
+                });
+            }
+            _ => return Err(LoadError::Parse(format!("invalid header line {}", line))),
+        }
+    }
+
+    let format = format.ok_or_else(|| LoadError::Parse("the header has no format".to_string()))?;
+    Ok((format, elements, offset))
+}
+
+// reads the values of the body one by one, in the precision of the file
+struct Body<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Body<'_> {
+    // the number of records of the element the rest of the body can hold at most, so that
+    // the memory reserved for them is bounded by the file and not by the count of the header
+    fn capacity(&self, element: &Element) -> usize {
+        // every value takes at least a digit in ascii, a list at least its length
+        let record_size: usize = element.properties.iter().map(|property| {
+            match (self.format, &property.kind) {
+                (Format::Ascii, _) => 1,
+                (_, PropertyKind::Scalar(ty)) | (_, PropertyKind::List { count: ty, .. }) => ty.size(),
+            }
+        }).sum();
+
+        let remaining = self.bytes.len() - self.offset;
+        element.count.min(remaining / record_size.max(1))
+    }
+
+    fn read(&mut self, ty: ScalarType) -> Result<f64, LoadError> {
+        if self.format == Format::Ascii {
+            return self.read_token();
+        }
+
+        let size = ty.size();
+        let bytes = self.bytes
+            .get(self.offset..self.offset + size)
+            .ok_or_else(|| LoadError::Parse("the body ends early".to_string()))?;
+        self.offset += size;
+
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+
+        // the values are read as little endian, so the big endian ones are turned around first
+        if self.format == Format::BinaryBigEndian {
+            buffer[..size].reverse();
+        }
+
+        Ok(match ty {
+            ScalarType::Int8 => i8::from_le_bytes([buffer[0]]) as f64,
+            ScalarType::UInt8 => buffer[0] as f64,
+            ScalarType::Int16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::UInt16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::Int32 => i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            ScalarType::UInt32 => u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            ScalarType::Float32 => f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            ScalarType::Float64 => f64::from_le_bytes(buffer),
+        })
+    }
+
+    fn read_token(&mut self) -> Result<f64, LoadError> {
+        let rest = &self.bytes[self.offset..];
+        let start = rest
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .ok_or_else(|| LoadError::Parse("the body ends early".to_string()))?;
+        let length = rest[start..].iter().position(|b| b.is_ascii_whitespace()).unwrap_or(rest.len() - start);
+        self.offset += start + length;
+
+        let token = std::str::from_utf8(&rest[start..start + length]).unwrap_or("");
+        token.parse().map_err(|_| LoadError::Parse(format!("invalid value {}", token)))
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<PlyMesh, LoadError> {
+    let (format, elements, offset) = parse_header(bytes)?;
+    let mut body = Body { format, bytes, offset };
+
+    let mut mesh = PlyMesh {
+        positions: Vec::new(),
+        indices: Vec::new(),
+        normals: None,
+        colors: None,
+        uvs: None,
+    };
+
+    let mut values = Vec::new();
+    let mut lists: Vec<Vec<f64>> = Vec::new();
+
+    for element in &elements {
+        // the indices of the properties the mesh is made of, in the order of the axes
+        let position = [element.scalar("x"), element.scalar("y"), element.scalar("z")];
+        let normal = [element.scalar("nx"), element.scalar("ny"), element.scalar("nz")];
+        let color = [element.scalar("red"), element.scalar("green"), element.scalar("blue")];
+        let uv = [element.any_scalar(&U_NAMES), element.any_scalar(&V_NAMES)];
+        let face = element.list(&["vertex_indices", "vertex_index"]);
+
+        let is_vertex = element.name == "vertex";
+        let is_face = element.name == "face";
+
+        if is_vertex {
+            if position.iter().any(Option::is_none) {
+                return Err(LoadError::Parse("the vertices have no position".to_string()));
+            }
+            let capacity = body.capacity(element);
+            mesh.positions.reserve(capacity);
+            mesh.normals = normal.iter().all(Option::is_some).then(|| Vec::with_capacity(capacity));
+            mesh.colors = color.iter().all(Option::is_some).then(|| Vec::with_capacity(capacity));
+            mesh.uvs = uv.iter().all(Option::is_some).then(|| Vec::with_capacity(capacity));
+        }
+
+        if is_face && face.is_none() {
+            return Err(LoadError::Parse("the faces have no vertex indices".to_string()));
+        }
+
+        for _ in 0..element.count {
+            values.clear();
+            lists.clear();
+
+            for property in &element.properties {
+                match property.kind {
+                    PropertyKind::Scalar(ty) => {
+                        values.push(body.read(ty)?);
+                        lists.push(Vec::new());
+                    }
+                    PropertyKind::List { count, item } => {
+                        let length = body.read(count)?;
+                        if length < 0.0 || length.fract() != 0.0 {
+                            return Err(LoadError::Parse(format!("invalid length {} of the list {}", length, property.name)));
+                        }
+                        let list = (0..length as usize).map(|_| body.read(item)).collect::<Result<Vec<_>, _>>()?;
+                        values.push(0.0);
+                        lists.push(list);
+                    }
+                }
+            }
+
+            if is_vertex {
+                let get = |i: Option<usize>| values[i.unwrap()] as Float;
+                mesh.positions.push(Point3::new(get(position[0]), get(position[1]), get(position[2])));
+
+                if let Some(normals) = &mut mesh.normals {
+                    normals.push(Vec3::new(get(normal[0]), get(normal[1]), get(normal[2])));
+                }
+
+                if let Some(colors) = &mut mesh.colors {
+                    let channel = |i: Option<usize>| {
+                        let PropertyKind::Scalar(ty) = element.properties[i.unwrap()].kind else { unreachable!() };
+                        (values[i.unwrap()] / ty.color_scale()) as Float
+                    };
+                    colors.push(Color::new(channel(color[0]), channel(color[1]), channel(color[2])));
+                }
+
+                if let Some(uvs) = &mut mesh.uvs {
+                    uvs.push(Vec2::new(get(uv[0]), get(uv[1])));
+                }
+            } else if is_face {
+                let polygon = &lists[face.unwrap()];
+
+                if let Some(&index) = polygon.iter().find(|&&index| index < 0.0 || index.fract() != 0.0) {
+                    return Err(LoadError::Parse(format!("invalid vertex index {}", index)));
+                }
+
+                // the polygons are split into fans around their first vertex
+                for i in 2..polygon.len() {
+                    mesh.indices.push([polygon[0], polygon[i - 1], polygon[i]].map(|index| index as u32));
+                }
+            }
+        }
+    }
+
+    // the faces may come before the vertices, so the indices are checked once both are read
+    if let Some(index) = mesh.indices.iter().flatten().find(|&&i| i as usize >= mesh.positions.len()) {
+        return Err(LoadError::Parse(format!("the vertex index {} is out of range", index)));
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::{Accelerator, RAY_EPSILON};
+    use crate::accelerators::bvh::Bvh;
+    use crate::loaders::tests::{assert_reports_missing_and_malformed, TestDirectory};
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use std::sync::Arc;
+
+    // a unit square in the z = 0 plane and a triangle above it, with colored vertices
+    const ASCII: &str = "ply
+format ascii 1.0
+comment made by hand
+element vertex 5
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 2
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 1 255 0 0
+1 0 0 0 0 1 0 255 0
+1 1 0 0 0 1 0 0 255
+0 1 0 0 0 1 255 255 255
+0.5 0.5 1 0 0 1 0 0 0
+4 0 1 2 3
+3 0 1 4
+";
+
+    // the same mesh in a binary format, written value by value
+    fn binary(format: &str, write: fn(&mut Vec<u8>, &[u8])) -> Vec<u8> {
+        let header = ASCII.split("end_header\n").next().unwrap().replace("format ascii", &format!("format {}", format));
+        let mut bytes = format!("{}end_header\n", header).into_bytes();
+
+        let vertices: [[f32; 6]; 5] = [
+            [0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            [1.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            [0.5, 0.5, 1.0, 0.0, 0.0, 1.0],
+        ];
+        let colors: [[u8; 3]; 5] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255], [0, 0, 0]];
+
+        for (vertex, color) in vertices.iter().zip(colors) {
+            for value in vertex {
+                write(&mut bytes, &value.to_le_bytes());
+            }
+            bytes.extend(color);
+        }
+
+        for face in [vec![0i32, 1, 2, 3], vec![0, 1, 4]] {
+            bytes.push(face.len() as u8);
+            for index in face {
+                write(&mut bytes, &index.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    fn assert_mesh(mesh: &PlyMesh) {
+        assert_eq!(mesh.positions.len(), 5);
+        assert_eq!(mesh.positions[4], Point3::new(0.5, 0.5, 1.0));
+
+        // the quad is split into two triangles
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3], [0, 1, 4]]);
+
+        assert_eq!(mesh.normals.as_ref().unwrap()[2], Vec3::Z);
+        assert_eq!(mesh.colors.as_ref().unwrap()[1], Color::new(0.0, 1.0, 0.0));
+        assert!(mesh.uvs.is_none());
+    }
+
+    #[test]
+    fn parses_ascii_and_binary() {
+        assert_mesh(&parse(ASCII.as_bytes()).unwrap());
+        assert_mesh(&parse(&binary("binary_little_endian", |bytes, value| bytes.extend(value))).unwrap());
+        assert_mesh(&parse(&binary("binary_big_endian", |bytes, value| bytes.extend(value.iter().rev()))).unwrap());
+    }
+
+    #[test]
+    fn loads_triangles_for_the_bvh() {
+        let directory = TestDirectory::new("ply_mesh");
+        let mesh = Arc::new(load_ply(directory.write("mesh.ply", ASCII)).unwrap().into_mesh(Lambertian::new(Color::ONE)));
+
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(mesh.triangles());
+
+        // above the quad, beside the triangle standing on it
+        let ray = Ray::new(Point3::new(0.5, 0.75, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = bvh.intersect(&ray, RAY_EPSILON, Float::INFINITY).unwrap();
+        assert!((rec.t - 5.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn skips_unknown_elements_and_properties() {
+        let ply = "ply\r
+format ascii 1.0\r
+element vertex 3\r
+property double x\r
+property double y\r
+property double z\r
+property float confidence\r
+property float s\r
+property float t\r
+element edge 1\r
+property int vertex1\r
+property int vertex2\r
+element face 1\r
+property list uint8 uint32 vertex_index\r
+end_header\r
+0 0 0 0.5 0 0\r
+1 0 0 0.5 1 0\r
+0 1 0 0.5 0 1\r
+0 1\r
+3 2 1 0\r
+";
+        let mesh = parse(ply.as_bytes()).unwrap();
+        assert_eq!(mesh.indices, vec![[2, 1, 0]]);
+        assert_eq!(mesh.uvs.unwrap()[2], Vec2::new(0.0, 1.0));
+        assert!(mesh.normals.is_none());
+        assert!(mesh.colors.is_none());
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        assert_reports_missing_and_malformed(load_ply, "broken.ply", "obj\nformat ascii 1.0\nend_header\n");
+
+        let malformed = [
+            // not a ply file
+            "obj\nformat ascii 1.0\nend_header\n".to_string(),
+            // the header does not end
+            "ply\nformat ascii 1.0\nelement vertex 1\n".to_string(),
+            // unknown type
+            "ply\nformat ascii 1.0\nelement vertex 1\nproperty half x\nend_header\n".to_string(),
+            // the body ends early
+            ASCII.replace("3 0 1 4\n", ""),
+            // the face refers to a vertex that does not exist
+            ASCII.replace("3 0 1 4", "3 0 1 5"),
+        ];
+
+        for ply in malformed {
+            assert!(matches!(parse(ply.as_bytes()), Err(LoadError::Parse(_))), "{}", ply);
+        }
+
+        let truncated = binary("binary_little_endian", |bytes, value| bytes.extend(value));
+        assert!(parse(&truncated[..truncated.len() - 2]).is_err());
+
+        // a corrupt count is an error of the body, not a failed allocation
+        for format in ["ascii", "binary_little_endian"] {
+            let huge = format!("ply\nformat {} 1.0\nelement vertex {}\nproperty float x\nproperty float y\nproperty float z\nend_header\n0 0 0\n", format, usize::MAX);
+            assert!(matches!(parse(huge.as_bytes()), Err(LoadError::Parse(_))), "{}", format);
+        }
+    }
+}
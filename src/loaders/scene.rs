@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use glam::EulerRot;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::accelerators::accelerator::Accelerator;
+use crate::accelerators::bvh::Bvh;
+use crate::camera::CameraSettings;
+use crate::hit::World;
+use crate::light::{LightKind, PunctualLight};
+use crate::loaders::gltf::load_gltf;
+use crate::loaders::obj::load_obj;
+use crate::loaders::ply::load_ply;
+use crate::loaders::LoadError;
+use crate::material::{Dielectric, Lambertian, Metal, SharedMaterial};
+use crate::primitives::instance::Instance;
+use crate::primitives::sphere::Sphere;
+use crate::primitives::triangle::Triangle;
+use crate::tracer::{Background, Tracer};
+use crate::utils::util::{Affine3, Color, Float, Point3, Quat, Vec3};
+
+// the color of the PLY meshes without a material
+const DEFAULT_COLOR: Color = Color::new(0.5, 0.5, 0.5);
+
+/*
+    The settings of the image: its size, the samples per pixel, the depth
+    of the paths, the background and the file the image is saved to.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub background: Background,
+    pub output: PathBuf,
+}
+
+impl ImageSettings {
+    pub fn aspect_ratio(&self) -> Float {
+        self.width as Float / self.height as Float
+    }
+
+    pub fn tracer(&self) -> Tracer {
+        let mut tracer = Tracer::new(self.width, self.height, self.samples_per_pixel);
+        tracer.set_background(self.background);
+        tracer
+    }
+}
+
+/*
+    A scene read from a scene file: the world, seen through the camera,
+    the lights and the settings of the image to render. The camera is
+    created for the aspect ratio of the image, once its size is settled.
+*/
+pub struct Scene {
+    pub world: World,
+    pub camera: CameraSettings,
+    pub lights: Vec<PunctualLight>,
+    pub image: ImageSettings,
+}
+
+/*
+    Loads a scene file in the TOML format. The file describes:
+        - the image, with its width and either its height or its aspect ratio,
+          and the background, the sky by default or a color like [0.0, 0.0, 0.0]
+        - the camera, with the parameters of Camera::new
+        - the materials by name, lambertian, metal or dielectric
+        - the spheres and the triangles, which refer to the materials by name
+        - the meshes, loaded from OBJ, PLY or glTF files next to the scene file
+          and placed with a translation, a rotation and a scale
+        - the punctual lights, which are read but not rendered yet
+    A scene looks like:
+
+        [image]
+        width = 600
+        aspect_ratio = 1.5
+        samples_per_pixel = 100
+
+        [camera]
+        lookfrom = [13.0, 2.0, 3.0]
+        lookat = [0.0, 0.0, 0.0]
+        vfov = 20.0
+
+        [materials.ground]
+        type = "lambertian"
+        albedo = [0.5, 0.5, 0.5]
+
+        [[spheres]]
+        center = [0.0, -1000.0, 0.0]
+        radius = 1000.0
+        material = "ground"
+
+        [[meshes]]
+        file = "bunny.ply"
+        material = "ground"
+        scale = [10.0, 10.0, 10.0]
+
+    The errors tell the line and the column of the file they are found at.
+*/
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, LoadError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    parse(&source, directory).map_err(|err| match err {
+        LoadError::Parse(message) => LoadError::Parse(format!("{}: {}", path.display(), message)),
+        err => err,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    image: ImageDescription,
+    camera: CameraDescription,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDescription>,
+    #[serde(default)]
+    spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    triangles: Vec<TriangleDescription>,
+    #[serde(default)]
+    meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageDescription {
+    width: Spanned<u32>,
+    height: Option<u32>,
+    #[serde(default = "default_aspect_ratio")]
+    aspect_ratio: Spanned<Float>,
+    #[serde(default = "default_samples_per_pixel")]
+    samples_per_pixel: u32,
+    #[serde(default = "default_max_depth")]
+    max_depth: u32,
+    #[serde(default)]
+    background: BackgroundDescription,
+    #[serde(default = "default_output")]
+    output: PathBuf,
+}
+
+// the sky by name, or a uniform color
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum BackgroundDescription {
+    #[default]
+    Sky,
+    #[serde(untagged)]
+    Color([Float; 3]),
+}
+
+fn default_aspect_ratio() -> Spanned<Float> {
+    Spanned::new(0..0, 3.0 / 2.0)
+}
+
+fn default_samples_per_pixel() -> u32 {
+    100
+}
+
+fn default_max_depth() -> u32 {
+    50
+}
+
+fn default_output() -> PathBuf {
+    PathBuf::from("output/rendering.png")
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    lookfrom: [Float; 3],
+    lookat: [Float; 3],
+    #[serde(default = "default_vup")]
+    vup: [Float; 3],
+    vfov: Float,
+    #[serde(default)]
+    aperture: Float,
+    focus_dist: Option<Float>, // the distance to lookat by default
+}
+
+fn default_vup() -> [Float; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum MaterialDescription {
+    Lambertian {
+        albedo: [Float; 3],
+    },
+    Metal {
+        albedo: [Float; 3],
+        #[serde(default)]
+        fuzz: Float,
+    },
+    Dielectric {
+        index_of_refraction: Float,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDescription {
+    center: [Float; 3],
+    radius: Float,
+    material: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TriangleDescription {
+    vertices: [[Float; 3]; 3],
+    material: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDescription {
+    file: Spanned<PathBuf>,
+    material: Option<Spanned<String>>, // only for the PLY files, the others bring their own materials
+    #[serde(default)]
+    translate: [Float; 3],
+    #[serde(default)]
+    rotate: [Float; 3], // the rotations about x, y and z in degrees, in that order
+    #[serde(default = "default_scale")]
+    scale: [Float; 3],
+}
+
+fn default_scale() -> [Float; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+// the fields of the lights of every type, which are checked against the type once read. The
+// fields keep their position in the file, which the enum tagged by the type would lose
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDescription {
+    #[serde(rename = "type")]
+    kind: Spanned<LightType>,
+    position: Option<Spanned<[Float; 3]>>, // of the point and spot lights
+    direction: Option<Spanned<[Float; 3]>>, // of the directional and spot lights
+    #[serde(default = "default_light_color")]
+    color: [Float; 3],
+    #[serde(default = "default_intensity")]
+    intensity: Float,
+    inner_cone_angle: Option<Spanned<Float>>, // degrees, of the spot lights
+    outer_cone_angle: Option<Spanned<Float>>, // degrees, of the spot lights
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum LightType {
+    Point,
+    Directional,
+    Spot,
+}
+
+fn default_light_color() -> [Float; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+// the line and the column, both counted from 1, of the given byte offset into the source
+fn location(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+// the error at the position of the given value in the source
+fn error_at<T>(source: &str, value: &Spanned<T>, message: String) -> LoadError {
+    let (line, column) = location(source, value.span().start);
+    LoadError::Parse(format!("line {}, column {}: {}", line, column, message))
+}
+
+fn parse(source: &str, directory: &Path) -> Result<Scene, LoadError> {
+    // the errors of the syntax and of the fields tell their line and column themselves
+    let file: SceneFile = toml::from_str(source).map_err(|err| LoadError::Parse(err.to_string()))?;
+
+    let image = image_settings(source, &file.image)?;
+    let camera = camera_settings(&file.camera);
+
+    let materials: HashMap<&str, SharedMaterial> = file.materials
+        .iter()
+        .map(|(name, material)| (name.as_str(), convert_material(material)))
+        .collect();
+
+    let material = |name: &Spanned<String>| {
+        materials
+            .get(name.get_ref().as_str())
+            .cloned()
+            .ok_or_else(|| error_at(source, name, format!("unknown material {}", name.get_ref())))
+    };
+
+    let mut world = World::new();
+    let mut lights = file.lights
+        .iter()
+        .map(|light| convert_light(source, light))
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    for sphere in &file.spheres {
+        world.push(Box::new(Sphere::new(Point3::from_array(sphere.center), sphere.radius, material(&sphere.material)?)));
+    }
+
+    for triangle in &file.triangles {
+        world.push(Box::new(Triangle::new(triangle.vertices.map(Point3::from_array), material(&triangle.material)?)));
+    }
+
+    for mesh in &file.meshes {
+        let transform = Affine3::from_scale_rotation_translation(
+            Vec3::from_array(mesh.scale),
+            Quat::from_euler(EulerRot::XYZ, mesh.rotate[0].to_radians(), mesh.rotate[1].to_radians(), mesh.rotate[2].to_radians()),
+            Vec3::from_array(mesh.translate),
+        );
+
+        if transform.matrix3.determinant() == 0.0 {
+            return Err(error_at(source, &mesh.file, "the scale of the mesh is zero".to_string()));
+        }
+
+        let mesh_material = mesh.material.as_ref().map(&material).transpose()?;
+        let (triangles, mesh_lights) = load_mesh(source, directory, &mesh.file, mesh_material)?;
+
+        lights.extend(mesh_lights.into_iter().map(|light| PunctualLight {
+            position: transform.transform_point3(light.position),
+            direction: transform.transform_vector3(light.direction).normalize(),
+            ..light
+        }));
+
+        // the mesh is built once in its own space and placed into the world with the transform
+        if !triangles.is_empty() {
+            let mut bvh = Bvh::new(None, None);
+            bvh.build(triangles);
+            world.push(Box::new(Instance::new(Arc::new(bvh), transform)));
+        }
+    }
+
+    Ok(Scene {
+        world,
+        camera,
+        lights,
+        image,
+    })
+}
+
+fn image_settings(source: &str, image: &ImageDescription) -> Result<ImageSettings, LoadError> {
+    let width = *image.width.get_ref();
+
+    // the height follows the aspect ratio unless it is given
+    let height = match image.height {
+        Some(height) => height,
+        None => {
+            let aspect_ratio = *image.aspect_ratio.get_ref();
+            let height = width as Float / aspect_ratio;
+
+            if aspect_ratio <= 0.0 || !height.is_finite() || height > u32::MAX as Float {
+                return Err(error_at(source, &image.aspect_ratio, format!("the aspect ratio {} is invalid", aspect_ratio)));
+            }
+            height as u32
+        }
+    };
+
+    // the tracer spreads the samples from the first to the last pixel of every row and column
+    if width < 2 || height < 2 {
+        return Err(error_at(source, &image.width, format!("the image of {}x{} pixels is too small", width, height)));
+    }
+
+    Ok(ImageSettings {
+        width,
+        height,
+        samples_per_pixel: image.samples_per_pixel,
+        max_depth: image.max_depth,
+        background: match image.background {
+            BackgroundDescription::Sky => Background::Sky,
+            BackgroundDescription::Color(color) => Background::Color(Color::from_array(color)),
+        },
+        output: image.output.clone(),
+    })
+}
+
+fn camera_settings(camera: &CameraDescription) -> CameraSettings {
+    let lookfrom = Point3::from_array(camera.lookfrom);
+    let lookat = Point3::from_array(camera.lookat);
+
+    CameraSettings {
+        lookfrom,
+        lookat,
+        vup: Vec3::from_array(camera.vup),
+        vfov: camera.vfov,
+        aspect_ratio: None, // the aspect ratio of the image
+        aperture: camera.aperture,
+        focus_dist: camera.focus_dist.unwrap_or((lookfrom - lookat).length()),
+    }
+}
+
+// the triangles of a mesh file in its own space, and the lights it holds
+fn load_mesh(source: &str,
+             directory: &Path,
+             file: &Spanned<PathBuf>,
+             material: Option<SharedMaterial>) -> Result<(World, Vec<PunctualLight>), LoadError> {
+
+    let path = directory.join(file.get_ref());
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+
+    if material.is_some() && extension != "ply" {
+        return Err(error_at(source, file, format!("{} brings its own materials", file.get_ref().display())));
+    }
+
+    // the errors of the mesh files tell the mesh of the scene file they come from
+    let locate = |err: LoadError| match err {
+        LoadError::Io(err) => {
+            let (line, column) = location(source, file.span().start);
+            LoadError::Io(io::Error::new(err.kind(), format!("line {}, column {}: {}: {}", line, column, path.display(), err)))
+        }
+        LoadError::Parse(message) => error_at(source, file, message),
+    };
+
+    match extension.as_str() {
+        "obj" => {
+            let meshes = load_obj(&path).map_err(locate)?;
+            Ok((meshes.iter().flat_map(|mesh| mesh.triangles()).collect(), Vec::new()))
+        }
+        "ply" => {
+            let material = material.unwrap_or_else(|| Arc::new(Lambertian::new(DEFAULT_COLOR)));
+            let mesh = Arc::new(load_ply(&path).map_err(locate)?.into_mesh(material));
+            Ok((mesh.triangles(), Vec::new()))
+        }
+        "gltf" | "glb" => {
+            let scene = load_gltf(&path).map_err(locate)?;
+            Ok((scene.world, scene.lights))
+        }
+        _ => Err(error_at(source, file, format!("unknown mesh format {}", file.get_ref().display()))),
+    }
+}
+
+fn convert_material(material: &MaterialDescription) -> SharedMaterial {
+    match *material {
+        MaterialDescription::Lambertian { albedo } => Arc::new(Lambertian::new(Color::from_array(albedo))),
+        MaterialDescription::Metal { albedo, fuzz } => Arc::new(Metal::new(Color::from_array(albedo), fuzz)),
+        MaterialDescription::Dielectric { index_of_refraction } => Arc::new(Dielectric::new(index_of_refraction)),
+    }
+}
+
+fn convert_light(source: &str, light: &LightDescription) -> Result<PunctualLight, LoadError> {
+    let kind = *light.kind.get_ref();
+    let name = format!("{:?}", kind).to_lowercase();
+
+    // the field the type of the light needs, or the one it has no use for
+    let missing = |field_name: &str| error_at(source, &light.kind, format!("the {} light has no {}", name, field_name));
+    let unused = |field: Option<&Spanned<[Float; 3]>>, field_name: &str| match field {
+        Some(value) => Err(error_at(source, value, format!("the {} light has no {}", name, field_name))),
+        None => Ok(()),
+    };
+
+    let direction = match kind {
+        LightType::Point => {
+            unused(light.direction.as_ref(), "direction")?;
+            -Vec3::Z
+        }
+        LightType::Directional | LightType::Spot => {
+            let field = light.direction.as_ref().ok_or_else(|| missing("direction"))?;
+            Vec3::from_array(*field.get_ref())
+                .try_normalize()
+                .ok_or_else(|| error_at(source, field, "the direction of the light is zero".to_string()))?
+        }
+    };
+
+    let position = match kind {
+        LightType::Directional => {
+            unused(light.position.as_ref(), "position")?;
+            Point3::ZERO
+        }
+        LightType::Point | LightType::Spot => {
+            Point3::from_array(*light.position.as_ref().ok_or_else(|| missing("position"))?.get_ref())
+        }
+    };
+
+    // the cone angles belong to the spot lights only
+    let angle = |field: &Option<Spanned<Float>>, field_name: &str| match (field, kind) {
+        (Some(angle), LightType::Spot) => Ok(Some(angle.get_ref().to_radians())),
+        (Some(angle), _) => Err(error_at(source, angle, format!("the {} light has no {}", name, field_name))),
+        (None, LightType::Spot) => Err(missing(field_name)),
+        (None, _) => Ok(None),
+    };
+
+    let kind = match (angle(&light.inner_cone_angle, "inner_cone_angle")?, angle(&light.outer_cone_angle, "outer_cone_angle")?) {
+        (Some(inner_cone_angle), Some(outer_cone_angle)) => LightKind::Spot { inner_cone_angle, outer_cone_angle },
+        _ if kind == LightType::Point => LightKind::Point,
+        _ => LightKind::Directional,
+    };
+
+    Ok(PunctualLight {
+        kind,
+        position,
+        direction,
+        color: Color::from_array(light.color),
+        intensity: light.intensity,
+        range: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::RAY_EPSILON;
+    use crate::hit::Hit;
+    use crate::loaders::tests::TestDirectory;
+    use crate::ray::Ray;
+
+    const SCENE: &str = r#"
+[image]
+width = 300
+aspect_ratio = 1.5
+samples_per_pixel = 16
+
+[camera]
+lookfrom = [0.0, 0.0, 10.0]
+lookat = [0.0, 0.0, 0.0]
+vfov = 40.0
+
+[materials.red]
+type = "lambertian"
+albedo = [0.8, 0.1, 0.1]
+
+[materials.mirror]
+type = "metal"
+albedo = [0.9, 0.9, 0.9]
+
+[materials.glass]
+type = "dielectric"
+index_of_refraction = 1.5
+
+[[spheres]]
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = "red"
+
+[[spheres]]
+center = [3.0, 0.0, 0.0]
+radius = 1.0
+material = "glass"
+
+[[triangles]]
+vertices = [[-5.0, -5.0, -5.0], [5.0, -5.0, -5.0], [0.0, 5.0, -5.0]]
+material = "mirror"
+
+[[lights]]
+type = "spot"
+position = [0.0, 5.0, 0.0]
+direction = [0.0, -1.0, 0.0]
+inner_cone_angle = 10.0
+outer_cone_angle = 20.0
+"#;
+
+    fn parse_scene(source: &str) -> Result<Scene, LoadError> {
+        parse(source, Path::new(""))
+    }
+
+    fn parse_error(source: &str) -> String {
+        match parse_scene(source) {
+            Err(LoadError::Parse(message)) => message,
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the scene is valid"),
+        }
+    }
+
+    #[test]
+    fn parses_the_scene() {
+        let scene = parse_scene(SCENE).unwrap();
+
+        assert_eq!(scene.image.width, 300);
+        assert_eq!(scene.image.height, 200);
+        assert_eq!(scene.image.samples_per_pixel, 16);
+        assert_eq!(scene.image.max_depth, default_max_depth());
+        assert_eq!(scene.image.output, default_output());
+        assert_eq!(scene.image.background, Background::Sky);
+
+        // the background is the sky by name or a color
+        let black = parse_scene(&SCENE.replace("aspect_ratio = 1.5", "aspect_ratio = 1.5\nbackground = [0.0, 0.0, 0.0]")).unwrap();
+        assert_eq!(black.image.background, Background::Color(Color::ZERO));
+        let sky = parse_scene(&SCENE.replace("aspect_ratio = 1.5", "aspect_ratio = 1.5\nbackground = \"sky\"")).unwrap();
+        assert_eq!(sky.image.background, Background::Sky);
+        assert!(parse_scene(&SCENE.replace("aspect_ratio = 1.5", "aspect_ratio = 1.5\nbackground = \"night\"")).is_err());
+
+        assert_eq!(scene.world.len(), 3);
+
+        // the ray through the center of the image hits the red sphere
+        let ray = scene.camera.camera(scene.image.aspect_ratio()).get_ray(0.5, 0.5);
+        let rec = scene.world.hit(&ray, RAY_EPSILON, Float::INFINITY).unwrap();
+        assert!((rec.p - Point3::new(0.0, 0.0, 1.0)).length() < 0.0001);
+        let (attenuation, _) = rec.mat.scatter(&ray, &rec).unwrap();
+        assert!((attenuation - Color::new(0.8, 0.1, 0.1)).length() < 0.0001);
+
+        // the mirror triangle behind the sphere
+        let ray = Ray::new(Point3::new(-2.0, -2.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!((scene.world.hit(&ray, RAY_EPSILON, Float::INFINITY).unwrap().t - 15.0).abs() < 0.0001);
+
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.lights[0].direction, -Vec3::Y);
+        assert!(matches!(scene.lights[0].kind, LightKind::Spot { outer_cone_angle, .. } if (outer_cone_angle - (20.0 as Float).to_radians()).abs() < 0.0001));
+    }
+
+    #[test]
+    fn places_meshes_with_their_transform() {
+        let directory = TestDirectory::new("scene_meshes");
+        directory.write("quad.obj", "v -1 -1 0\nv 1 -1 0\nv 1 1 0\nv -1 1 0\nf 1 2 3 4\n");
+
+        let scene = format!("{}\n[[meshes]]\nfile = \"quad.obj\"\ntranslate = [0.0, 10.0, 0.0]\nrotate = [90.0, 0.0, 0.0]\nscale = [2.0, 2.0, 2.0]\n", SCENE);
+        let scene = load_scene(directory.write("scene.toml", scene)).unwrap();
+        assert_eq!(scene.world.len(), 4);
+
+        // the quad is turned into the plane y = 10 and spans [-2, 2] along x and z
+        let down = |x: Float, z: Float| Ray::new(Point3::new(x, 20.0, z), Vec3::new(0.0, -1.0, 0.0));
+        assert!((scene.world.hit(&down(1.5, -1.5), RAY_EPSILON, Float::INFINITY).unwrap().t - 10.0).abs() < 0.0001);
+        assert!(scene.world.hit(&down(2.5, 0.0), RAY_EPSILON, 15.0).is_none());
+    }
+
+    #[test]
+    fn reports_errors_with_their_location() {
+        // the material name is on line 27, after "material = "
+        let message = parse_error(&SCENE.replace("material = \"red\"", "material = \"blue\""));
+        assert!(message.starts_with("line 27, column 12: unknown material blue"), "{}", message);
+
+        // the syntax and the fields are checked by the parser, which tells the line too
+        let message = parse_error(&SCENE.replace("radius = 1.0\nmaterial = \"glass\"", "radius = \"big\"\nmaterial = \"glass\""));
+        assert!(message.contains("line 31"), "{}", message);
+
+        let message = parse_error(&SCENE.replace("vfov = 40.0", "vfov = 40.0\nfov = 40.0"));
+        assert!(message.contains("line 11") && message.contains("fov"), "{}", message);
+
+        let message = parse_error(&SCENE.replace("type = \"metal\"", "type = \"plastic\""));
+        assert!(message.contains("line 17") && message.contains("plastic"), "{}", message);
+
+        let message = parse_error(&SCENE.replace("width = 300", "width = 1"));
+        assert!(message.starts_with("line 3, column 9"), "{}", message);
+
+        for aspect_ratio in ["0.0", "-1.5", "nan", "1e-38"] {
+            let message = parse_error(&SCENE.replace("aspect_ratio = 1.5", &format!("aspect_ratio = {}", aspect_ratio)));
+            assert!(message.starts_with("line 4, column 16: the aspect ratio"), "{}", message);
+        }
+
+        // the light fields are checked against the type of the light
+        let message = parse_error(&SCENE.replace("direction = [0.0, -1.0, 0.0]", "direction = [0.0, 0.0, 0.0]"));
+        assert!(message.starts_with("line 41, column 13: the direction of the light is zero"), "{}", message);
+
+        let message = parse_error(&SCENE.replace("type = \"spot\"", "type = \"point\""));
+        assert!(message.starts_with("line 41, column 13: the point light has no direction"), "{}", message);
+
+        let message = parse_error(&SCENE.replace("position = [0.0, 5.0, 0.0]\n", ""));
+        assert!(message.starts_with("line 39, column 8: the spot light has no position"), "{}", message);
+
+        let message = parse_error(&format!("{}\n[[meshes]]\nfile = \"model.stl\"\n", SCENE));
+        assert!(message.contains("unknown mesh format"), "{}", message);
+
+        // the mesh files are missing
+        let missing = parse_scene(&format!("{}\n[[meshes]]\nfile = \"missing.ply\"\n", SCENE));
+        assert!(matches!(missing, Err(LoadError::Io(err)) if err.to_string().contains("missing.ply")));
+    }
+}
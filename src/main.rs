@@ -1,138 +1,272 @@
 //main.rs - is the crate root file of a binary crate with the same name as the package.
 
-// declare the following modules. The compiler will look for the module's code
-// in the following places:
-// 1. src/*
+// the modules are declared in the library crate (src/lib.rs)
+// the following use keywords will bring the paths into the scope
+use path_tracer::accelerators::accelerator::Accelerator;
+use path_tracer::accelerators::bvh;
+use path_tracer::accelerators::bvh4::Bvh4;
+use path_tracer::accelerators::grid::Grid;
+use path_tracer::accelerators::kdtree::KdTree;
+use path_tracer::camera::Camera;
+use path_tracer::hit::World;
+use path_tracer::loaders::pbrt::load_pbrt;
+use path_tracer::loaders::scene::load_scene;
+use path_tracer::tracer::{Region, Tracer};
+use path_tracer::utils::util::Float;
 
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod camera;
-mod hit;
-mod material;
-mod ray;
-mod utils;
-mod tracer;
-mod primitives;
-mod accelerators;
+// the scene rendered when no scene file is given
+const DEFAULT_SCENE: &str = "scenes/random_spheres.toml";
 
-// the following use keywords will bring the paths into the scope
-use camera::Camera;
-use hit::World;
-use material::{Dielectric, Lambertian, Metal};
-use primitives::sphere::Sphere;
-use accelerators::accelerator::Accelerator;
-use accelerators::bvh;
-
-
-use glam::Vec3;
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
-use rand::prelude::*;
-use utils::util::Color;
-use utils::util::Point3;
-use utils::util::Util;
-
-use tracer::Tracer;
-
-fn random_scene() -> World {
-    let mut rng = rand::thread_rng();
-    let mut world = World::with_capacity(550);
-
-    let ground_mat = Lambertian::new(Color::new(0.5, 0.5, 0.5));
-    let ground_sphere = Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_mat);
-
-    world.push(Box::new(ground_sphere));
-
-    for a in -11..=11 {
-        for b in -11..=11 {
-            let choose_mat: f64 = rng.gen();
-            let center = Point3::new(
-                (a as f32) + rng.gen_range(0.0..0.9),
-                0.2,
-                (b as f32) + rng.gen_range(0.0..0.9),
-            );
-
-            if choose_mat < 0.8 {
-                // Diffuse
-                let albedo = Util::random(0.0..1.0) * Util::random(0.0..1.0);
-                let sphere_mat = Lambertian::new(albedo);
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
-
-                world.push(Box::new(sphere));
-            } else if choose_mat < 0.95 {
-                // Metal
-                let albedo = Util::random(0.4..1.0);
-                let fuzz = rng.gen_range(0.0..0.5);
-                let sphere_mat = Metal::new(albedo, fuzz);
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
-
-                world.push(Box::new(sphere));
-            } else {
-                // Glass
-                let sphere_mat = Dielectric::new(1.5);
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
+// the acceleration structures the scene can be rendered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AcceleratorKind {
+    Bvh,
+    Bvh4,
+    Kdtree,
+    Grid,
+}
 
-                world.push(Box::new(sphere));
-            }
+// the methods splitting the nodes of the bvh while building it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SplitKind {
+    Sah,
+    Hlbvh,
+    Middle,
+    EqualCounts,
+}
+
+impl SplitKind {
+    fn split_method(self) -> bvh::SplitMethod {
+        match self {
+            SplitKind::Sah => bvh::SplitMethod::SAH(bvh::SahParams::default()),
+            SplitKind::Hlbvh => bvh::SplitMethod::HLBVH(bvh::HlbvhParams::default()),
+            SplitKind::Middle => bvh::SplitMethod::Middle,
+            SplitKind::EqualCounts => bvh::SplitMethod::EqualCounts,
+        }
+    }
+}
+
+// the formats the image can be saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ImageFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tiff,
+    Tga,
+    Ppm,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::Tga => "tga",
+            ImageFormat::Ppm => "ppm",
         }
     }
+}
+
+/*
+    The command line of the renderer. The scene file gives the settings of the
+    image, the options given on the command line take precedence over them.
+*/
+#[derive(Debug, Parser)]
+#[command(version, about = "Render a scene file with a path tracer")]
+struct Cli {
+    /// The scene file to render, a TOML scene or a pbrt scene with the extension .pbrt
+    #[arg(default_value = DEFAULT_SCENE)]
+    scene: PathBuf,
+
+    /// The width of the image in pixels, the height follows the aspect ratio of the scene unless given
+    #[arg(short = 'W', long)]
+    width: Option<u32>,
 
-    let mat1 = Dielectric::new(1.5);
-    let mat2 = Lambertian::new(Color::new(0.4, 0.2, 0.1));
-    let mat3 = Metal::new(Color::new(0.7, 0.6, 0.5), 0.0);
+    /// The height of the image in pixels, the width follows the aspect ratio of the scene unless given
+    #[arg(short = 'H', long)]
+    height: Option<u32>,
 
-    let sphere1 = Sphere::new(Point3::new(0.0, 1.0, 0.0), 1.0, mat1);
-    let sphere2 = Sphere::new(Point3::new(-4.0, 1.0, 0.0), 1.0, mat2);
-    let sphere3 = Sphere::new(Point3::new(4.0, 1.0, 0.0), 1.0, mat3);
+    /// The number of samples per pixel
+    #[arg(short, long = "spp")]
+    samples_per_pixel: Option<u32>,
 
-    world.push(Box::new(sphere1));
-    world.push(Box::new(sphere2));
-    world.push(Box::new(sphere3));
+    /// The maximum number of bounces of a path
+    #[arg(short = 'd', long)]
+    max_depth: Option<u32>,
 
-    world
+    /// The number of threads, all the cores by default
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+
+    /// The seed of the random numbers, for renderings that can be reproduced
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The file the image is saved to
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// The format of the image, which replaces the extension of the output file
+    #[arg(short, long, value_enum)]
+    format: Option<ImageFormat>,
+
+    /// The acceleration structure built over the scene
+    #[arg(short, long, value_enum, default_value_t = AcceleratorKind::Bvh4)]
+    accelerator: AcceleratorKind,
+
+    /// The method splitting the nodes of the bvh and bvh4 accelerators
+    #[arg(long, value_enum, default_value_t = SplitKind::Sah)]
+    split: SplitKind,
+
+    /// Render only the region x,y,width,height of the image, in pixels from the upper left corner
+    #[arg(long, value_parser = parse_region)]
+    region: Option<Region>,
+
+    /// Do not show the progress bar and the messages
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn parse_region(value: &str) -> Result<Region, String> {
+    let numbers = value
+        .split(',')
+        .map(|number| number.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("{}", err))?;
+
+    match numbers.as_slice() {
+        &[x, y, width, height] => Ok(Region { x, y, width, height }),
+        _ => Err("expected x,y,width,height".to_string()),
+    }
 }
 
-//image setup
-const ASPECT_RATIO: f32 = 3.0 / 2.0;
-const IMAGE_WIDTH: u32 = 1024;
-const IMAGE_HEIGHT: u32 = ((IMAGE_WIDTH as f32) / ASPECT_RATIO) as u32;
-const SAMPLES_PER_PIXEL: u32 = 1000;
-const MAX_DEPTH: u32 = 100;
-const IMAGE_OUT_DIR: &str = "output";
-const IMAGE_FILE_NAME: &str = "rendering-1024X.png";
+// build the accelerator over the world and render the image with it
+fn render<A: Accelerator>(mut accelerator: A, world: World, tracer: &Tracer, camera: &Camera, max_depth: u32) {
+    accelerator.build(world);
+    tracer.trace(camera, &accelerator, max_depth);
+}
 
 fn main() {
-    //world
-    let world = random_scene(); // crate an empty world
-
-    // create a new bvh instance
-    let mut bvh = bvh::Bvh::new(Some(4),Some(bvh::SplitMethod::Middle));
-
-    // build the bvh
-    bvh.build(&world);
-
-    // Camera
-    let lookfrom = Point3::new(13.0, 2.0, 3.0);
-    let lookat = Point3::new(0.0, 0.0, 0.0);
-    let vup = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
-    let aperture = 0.1;
-
-    let cam = Camera::new(
-        lookfrom,
-        lookat,
-        vup,
-        20.0,
-        ASPECT_RATIO,
-        aperture,
-        dist_to_focus,
-    );
-
-    let tracer = Tracer::new(IMAGE_WIDTH,IMAGE_HEIGHT,SAMPLES_PER_PIXEL);
-
-    println!("Image Resolution: {}x{}", IMAGE_WIDTH, IMAGE_HEIGHT);
-    println!("Image will be saved at: {}/{}", IMAGE_OUT_DIR, IMAGE_FILE_NAME);
-    println!("Rendering Scene ...");
-    tracer.trace(&cam, &world, MAX_DEPTH);
-
-    println!("Saving Image ...");
-    tracer.save(IMAGE_OUT_DIR, IMAGE_FILE_NAME);
+    let cli = Cli::parse();
+
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("the thread pool is built once");
+    }
+
+    // the pbrt scenes are imported, the other scene files are read as TOML
+    let scene = if cli.scene.extension().is_some_and(|extension| extension == "pbrt") {
+        load_pbrt(&cli.scene)
+    } else {
+        load_scene(&cli.scene)
+    };
+
+    let scene = match scene {
+        Ok(scene) => scene,
+        Err(err) => {
+            eprintln!("Error loading the scene {}: {}", cli.scene.display(), err);
+            process::exit(1);
+        }
+    };
+
+    // the punctual lights are read with the scenes, but the tracer lights the scenes with the
+    // sky and the emitting surfaces only. Even in quiet mode, the image would be unexpected
+    if !scene.lights.is_empty() {
+        eprintln!("Warning: the point, spot and directional lights of the scene ({}) are not rendered, \
+                   only the sky and the emitting surfaces light the scene", scene.lights.len());
+    }
+
+    // the size given on the command line keeps the aspect ratio of the scene if only one side is given
+    let mut image = scene.image;
+    let aspect_ratio = image.aspect_ratio();
+
+    match (cli.width, cli.height) {
+        (Some(width), Some(height)) => (image.width, image.height) = (width, height),
+        (Some(width), None) => (image.width, image.height) = (width, (width as Float / aspect_ratio) as u32),
+        (None, Some(height)) => (image.width, image.height) = ((height as Float * aspect_ratio) as u32, height),
+        (None, None) => {}
+    }
+
+    if image.width < 2 || image.height < 2 {
+        Cli::command()
+            .error(ErrorKind::ValueValidation, format!("the image of {}x{} pixels is too small", image.width, image.height))
+            .exit();
+    }
+
+    image.samples_per_pixel = cli.samples_per_pixel.unwrap_or(image.samples_per_pixel);
+    image.max_depth = cli.max_depth.unwrap_or(image.max_depth);
+    image.output = cli.output.unwrap_or(image.output);
+
+    if let Some(format) = cli.format {
+        image.output.set_extension(format.extension());
+    }
+
+    let mut tracer = image.tracer();
+    tracer.set_seed(cli.seed);
+    tracer.set_quiet(cli.quiet);
+
+    if let Some(region) = cli.region {
+        if region.width == 0 || region.height == 0 ||
+            region.width > image.width.saturating_sub(region.x) || region.height > image.height.saturating_sub(region.y) {
+            Cli::command()
+                .error(ErrorKind::ValueValidation, format!("the region {},{},{},{} is empty or exceeds the image of {}x{} pixels",
+                                                         region.x, region.y, region.width, region.height, image.width, image.height))
+                .exit();
+        }
+        tracer.set_region(region);
+    }
+
+    let camera = scene.camera.camera(image.aspect_ratio());
+
+    if !cli.quiet {
+        println!("Image Resolution: {}x{}", image.width, image.height);
+        println!("Image will be saved at: {}", image.output.display());
+        println!("Rendering Scene ...");
+    }
+
+    match cli.accelerator {
+        AcceleratorKind::Bvh | AcceleratorKind::Bvh4 => {
+            // create a new bvh instance
+            let mut bvh = bvh::Bvh::new(Some(4), Some(cli.split.split_method()));
+
+            // build the bvh
+            // the bvh takes the ownership of the world
+            bvh.build(scene.world);
+
+            if !cli.quiet {
+                println!("{}", bvh.stats());
+            }
+
+            if cli.accelerator == AcceleratorKind::Bvh {
+                tracer.trace(&camera, &bvh, image.max_depth);
+            } else {
+                // collapse the binary bvh into the four wide one, which tests
+                // four bounding boxes at once while rendering
+                tracer.trace(&camera, &Bvh4::from(bvh), image.max_depth);
+            }
+        }
+        AcceleratorKind::Kdtree => render(KdTree::new(None), scene.world, &tracer, &camera, image.max_depth),
+        AcceleratorKind::Grid => render(Grid::new(None), scene.world, &tracer, &camera, image.max_depth),
+    }
+
+    if !cli.quiet {
+        println!("Saving Image ...");
+    }
+
+    let output_dir = image.output.parent().unwrap_or(Path::new("")).to_string_lossy();
+    let file_name = image.output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    if let Err(err) = tracer.save(&output_dir, &file_name) {
+        eprintln!("Error saving the image {}: {}", image.output.display(), err);
+        process::exit(1);
+    }
 }
@@ -1,11 +1,32 @@
+use std::sync::Arc;
+
 use rand::Rng;
 
 use crate::hit::HitRecord;
 use crate::ray::Ray;
-use crate::utils::util::{Color, Util};
+use crate::utils::util::{Color, Float, Util};
 
-pub trait Scatter: Sync {
+pub trait Scatter: Send + Sync {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)>;
+
+    // the light emitted by the material at the hit, none unless the material is a light
+    fn emitted(&self, _rec: &HitRecord) -> Color {
+        Color::ZERO
+    }
+}
+
+// a material chosen at runtime and shared by many objects, for
+// instance by the meshes of a file referring to the same material
+pub type SharedMaterial = Arc<dyn Scatter>;
+
+impl<S: Scatter + ?Sized> Scatter for Arc<S> {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+        (**self).scatter(r_in, rec)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        (**self).emitted(rec)
+    }
 }
 
 pub struct Lambertian {
@@ -20,9 +41,9 @@ impl Lambertian {
 
 impl Scatter for Lambertian {
     fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let mut scatter_direction = rec.normal + Util::random_in_unit_sphere().normalize();
+        let mut scatter_direction = rec.shading_normal + Util::random_in_unit_sphere().normalize();
         if Util::near_zero(&scatter_direction) {
-            scatter_direction = rec.normal;
+            scatter_direction = rec.shading_normal;
         }
 
         let scattered = Ray::new(rec.p, scatter_direction);
@@ -33,21 +54,21 @@ impl Scatter for Lambertian {
 
 pub struct Metal {
     albedo: Color,
-    fuzz: f32,
+    fuzz: Float,
 }
 
 impl Metal {
-    pub fn new(a: Color, f: f32) -> Self {
+    pub fn new(a: Color, f: Float) -> Self {
         Metal { albedo: a, fuzz: f }
     }
 }
 
 impl Scatter for Metal {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let reflected = Util::reflect(&r_in.direction(), &rec.normal).normalize();
+        let reflected = Util::reflect(&r_in.direction(), &rec.shading_normal).normalize();
         let scattered = Ray::new(rec.p, reflected + self.fuzz * Util::random_in_unit_sphere());
 
-        if scattered.direction().dot(rec.normal) > 0.0 {
+        if scattered.direction().dot(rec.shading_normal) > 0.0 {
             Some((self.albedo, scattered))
         } else {
             None
@@ -56,11 +77,11 @@ impl Scatter for Metal {
 }
 
 pub struct Dielectric {
-    ir: f32,
+    ir: Float,
 }
 
 impl Dielectric {
-    pub fn new(index_of_refraction: f32) -> Self {
+    pub fn new(index_of_refraction: Float) -> Self {
         Dielectric {
             ir: index_of_refraction,
         }
@@ -72,7 +93,7 @@ impl Dielectric {
         cheap and surprisingly accurate polynomial approximation by Christophe Schlick. This yields
         our full glass material.
     */
-    pub fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
+    pub fn reflectance(cosine: Float, ref_idx: Float) -> Float {
         // use schlick's approxinmation for reflectance
         let r0 = ((1.0 - ref_idx) / (1.0 + ref_idx)).powi(2);
         r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
@@ -88,17 +109,16 @@ impl Scatter for Dielectric {
         };
 
         let unit_direction = r_in.direction().normalize();
-        let cos_theta = ((-1.0) * unit_direction).dot(rec.normal).min(1.0);
+        let cos_theta = ((-1.0) * unit_direction).dot(rec.shading_normal).min(1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
 
-        let mut rng = rand::thread_rng();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let will_reflect = rng.gen::<f32>() < Self::reflectance(cos_theta, refraction_ratio);
+        let will_reflect = Util::with_rng(|rng| rng.gen::<Float>()) < Self::reflectance(cos_theta, refraction_ratio);
 
         let direction = if cannot_refract || will_reflect {
-            Util::reflect(&unit_direction, &rec.normal)
+            Util::reflect(&unit_direction, &rec.shading_normal)
         } else {
-            Util::refract(&unit_direction, &rec.normal, refraction_ratio)
+            Util::refract(&unit_direction, &rec.shading_normal, refraction_ratio)
         };
 
         let scattered = Ray::new(rec.p, direction);
@@ -106,3 +126,33 @@ impl Scatter for Dielectric {
         Some((Color::new(1.0, 1.0, 1.0), scattered))
     }
 }
+
+/*
+    A diffuse area light, which emits the same light in every direction from the
+    front face of the surface, or from both faces if it is two sided. The light
+    does not reflect any of the light arriving at it.
+*/
+pub struct DiffuseLight {
+    emit: Color,
+    two_sided: bool,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color, two_sided: bool) -> Self {
+        DiffuseLight { emit, two_sided }
+    }
+}
+
+impl Scatter for DiffuseLight {
+    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<(Color, Ray)> {
+        None
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        if rec.front_face || self.two_sided {
+            self.emit
+        } else {
+            Color::ZERO
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use crate::utils::util::{Affine3, Float, Point3};
+
+use crate::accelerators::accelerator::Accelerator;
+use crate::accelerators::bvh::Bvh;
+use crate::hit::{Hit, HitRecord};
+use crate::ray::Ray;
+use crate::utils::aabb::Aabb;
+
+/*
+    An instance places a bottom level bvh, built once over the objects of a mesh in
+    object space, into the scene with an affine transform. Any number of instances
+    share the same bvh, so a top level bvh over the instances renders many copies
+    of a mesh without duplicating its objects in the world.
+*/
+pub struct Instance {
+    bvh: Arc<Bvh>,
+    transform: Affine3, // object space to world space
+    inverse: Affine3, // world space to object space
+}
+
+impl Instance {
+    // the transform has to be invertible
+    pub fn new(bvh: Arc<Bvh>, transform: Affine3) -> Self {
+        Self {
+            bvh,
+            transform,
+            inverse: transform.inverse(),
+        }
+    }
+
+    pub fn transform(&self) -> &Affine3 {
+        &self.transform
+    }
+
+    // the ray in the object space of the bvh
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.inverse.transform_point3(ray.origin()),
+            self.inverse.transform_vector3(ray.direction()),
+        )
+    }
+}
+
+impl Hit for Instance {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        // the direction is not normalized after the transform, so a
+        // hit at t in object space lies at the same t in world space
+        let local_ray = self.local_ray(ray);
+
+        let mut rec = self.bvh.intersect(&local_ray, t_min, t_max)?;
+
+        rec.p = ray.at(rec.t);
+
+        // normals are transformed by the inverse transpose of the linear part. The dot product
+        // of the normal and the ray direction keeps its sign, so the normal still faces the ray
+        let normal_matrix = self.inverse.matrix3.transpose();
+        rec.normal = (normal_matrix * rec.normal).normalize();
+        rec.shading_normal = (normal_matrix * rec.shading_normal).normalize();
+
+        // the tangents are transformed like any other direction
+        rec.dpdu = self.transform.transform_vector3(rec.dpdu);
+        rec.dpdv = self.transform.transform_vector3(rec.dpdv);
+
+        Some(rec)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.bvh.occluded(&self.local_ray(ray), t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let aabb = self.bvh.bounding_box()?;
+        let (min, max) = (aabb.min(), aabb.max());
+
+        // the transformed corners of the object space box bound the instance
+        let mut bounds = Aabb::empty();
+
+        for corner in 0..8 {
+            let p = Point3::new(
+                if corner & 1 == 0 { min.x } else { max.x },
+                if corner & 2 == 0 { min.y } else { max.y },
+                if corner & 4 == 0 { min.z } else { max.z },
+            );
+
+            bounds.grow_mut(&self.transform.transform_point3(p));
+        }
+
+        Some(bounds)
+    }
+
+    // get the centroid of the instance
+    fn centroid(&self) -> Point3 {
+        match self.bvh.bounding_box() {
+            Some(aabb) => self.transform.transform_point3(aabb.center()),
+            None => self.transform.transform_point3(Point3::ZERO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::bvh::SplitMethod;
+    use crate::hit::World;
+    use crate::material::Lambertian;
+    use crate::primitives::triangle::Triangle;
+    use crate::utils::util::{Color, Quat, Vec3, consts};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // a small tree like mesh: a fan of triangles around the y axis
+    fn mesh_vertices() -> Vec<[Point3; 3]> {
+        (0..16)
+            .map(|i| {
+                let angle = i as Float * consts::TAU / 16.0;
+                let (sin, cos) = angle.sin_cos();
+                [
+                    Point3::new(0.0, 2.0, 0.0),
+                    Point3::new(cos, 0.0, sin),
+                    Point3::new((angle + 0.4).cos(), 0.0, (angle + 0.4).sin()),
+                ]
+            })
+            .collect()
+    }
+
+    fn mesh_world(transform: &Affine3) -> World {
+        mesh_vertices()
+            .into_iter()
+            .map(|vertices| {
+                let vertices = vertices.map(|v| transform.transform_point3(v));
+                Box::new(Triangle::new(vertices, Lambertian::new(Color::new(0.2, 0.6, 0.2)))) as Box<dyn Hit>
+            })
+            .collect()
+    }
+
+    fn transforms() -> Vec<Affine3> {
+        let mut rng = StdRng::seed_from_u64(17);
+
+        (0..50)
+            .map(|_| {
+                Affine3::from_scale_rotation_translation(
+                    Vec3::splat(rng.gen_range(0.5..2.0)),
+                    Quat::from_rotation_y(rng.gen_range(0.0..consts::TAU)),
+                    Vec3::new(rng.gen_range(-20.0..20.0), 0.0, rng.gen_range(-20.0..20.0)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forest_matches_transformed_triangles() {
+        // the bottom level bvh over the mesh is shared by all the instances
+        let mut mesh = Bvh::new(Some(2), Some(SplitMethod::Middle));
+        mesh.build(mesh_world(&Affine3::IDENTITY));
+        let mesh = Arc::new(mesh);
+
+        let instances: World = transforms()
+            .into_iter()
+            .map(|transform| Box::new(Instance::new(Arc::clone(&mesh), transform)) as Box<dyn Hit>)
+            .collect();
+
+        let mut forest = Bvh::new(Some(1), None);
+        forest.build(instances);
+        assert_eq!(forest.validate(), Ok(()));
+
+        // the same forest with the triangles transformed into world space
+        let triangles: World = transforms().iter().flat_map(mesh_world).collect();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut num_hits = 0;
+
+        for _ in 0..2000 {
+            let origin = Point3::new(rng.gen_range(-25.0..25.0), rng.gen_range(0.5..3.0), rng.gen_range(-25.0..25.0));
+            let direction = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-0.3..0.3), rng.gen_range(-1.0..1.0));
+            let ray = Ray::new(origin, direction);
+
+            match (triangles.hit(&ray, 0.001, Float::INFINITY), forest.intersect(&ray, 0.001, Float::INFINITY)) {
+                (Some(expected), Some(actual)) => {
+                    assert!((expected.t - actual.t).abs() < 0.001);
+                    assert!((expected.p - actual.p).length() < 0.001);
+                    assert!((expected.normal - actual.normal).length() < 0.001);
+                    assert_eq!(expected.front_face, actual.front_face);
+
+                    // the surface attributes of the shared mesh carry over into world space
+                    assert!((expected.shading_normal - actual.shading_normal).length() < 0.001);
+                    assert!((expected.uv - actual.uv).length() < 0.001);
+                    assert!((expected.dpdu - actual.dpdu).length() < 0.01);
+                    assert!((expected.dpdv - actual.dpdv).length() < 0.01);
+                    num_hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("instances and transformed triangles disagree on a hit"),
+            }
+
+            let t_max = rng.gen_range(1.0..30.0);
+            assert_eq!(Hit::occluded(&triangles, &ray, 0.001, t_max), forest.occluded(&ray, 0.001, t_max));
+        }
+
+        assert!(num_hits > 0);
+    }
+
+    #[test]
+    fn bounding_box_of_transformed_mesh() {
+        let mut mesh = Bvh::new(None, None);
+        mesh.build(mesh_world(&Affine3::IDENTITY));
+
+        let transform = Affine3::from_scale_rotation_translation(
+            Vec3::splat(2.0),
+            Quat::from_rotation_z(consts::FRAC_PI_2),
+            Vec3::new(10.0, 0.0, 0.0),
+        );
+        let instance = Instance::new(Arc::new(mesh), transform);
+
+        // the apex of the fan at y = 2 is rotated onto the negative x axis and scaled
+        let aabb = instance.bounding_box().unwrap();
+        assert!((aabb.min().x - 6.0).abs() < 0.001);
+        assert!((aabb.max().x - 10.0).abs() < 0.001);
+
+        // every transformed triangle lies inside the box of the instance
+        for object in mesh_world(&transform) {
+            assert!(aabb.approx_contains_aabb_eps(&object.bounding_box().unwrap(), 0.001));
+        }
+    }
+}
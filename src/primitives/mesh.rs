@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use crate::hit::{Hit, HitRecord, World};
+use crate::material::Scatter;
+use crate::primitives::triangle::{self, TriangleHit};
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
+use crate::utils::aabb::Aabb;
+use crate::utils::util::{Float, Point3, Vec2, Vec3, Vec4};
+
+/*
+    A triangle mesh keeps the vertices in shared buffers, which the triangles refer
+    to by index, together with the one material of the mesh. The normals and the
+    texture coordinates are optional, and given per vertex when present.
+    Every triangle of the mesh is placed into the world with a lightweight handle,
+    so the memory of a large mesh is dominated by its buffers rather than by
+    copies of the vertices and the material in every triangle.
+*/
+pub struct TriangleMesh<M: Scatter> {
+    positions: Vec<Point3>,
+    indices: Vec<[u32; 3]>, // the vertex indices of every triangle
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<Vec2>>,
+    material: M,
+}
+
+impl<M: Scatter> TriangleMesh<M> {
+    // the normals and the texture coordinates have to be given for every vertex
+    pub fn new(positions: Vec<Point3>,
+               indices: Vec<[u32; 3]>,
+               normals: Option<Vec<Vec3>>,
+               uvs: Option<Vec<Vec2>>,
+               material: M) -> Self {
+
+        // the vertices are indexed with 32 bits
+        assert!(positions.len() <= u32::MAX as usize, "too many vertices for the mesh");
+        assert!(indices.len() <= u32::MAX as usize, "too many triangles for the mesh");
+
+        assert!(indices.iter().flatten().all(|&i| (i as usize) < positions.len()), "vertex index out of range");
+
+        if let Some(normals) = &normals {
+            assert_eq!(normals.len(), positions.len(), "a normal is required for every vertex");
+        }
+
+        if let Some(uvs) = &uvs {
+            assert_eq!(uvs.len(), positions.len(), "texture coordinates are required for every vertex");
+        }
+
+        Self {
+            positions,
+            indices,
+            normals,
+            uvs,
+            material,
+        }
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn positions(&self) -> &[Point3] {
+        &self.positions
+    }
+
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_deref()
+    }
+
+    pub fn uvs(&self) -> Option<&[Vec2]> {
+        self.uvs.as_deref()
+    }
+
+    pub fn material(&self) -> &M {
+        &self.material
+    }
+
+    // the positions of the vertices of the given triangle
+    pub fn vertices(&self, triangle: usize) -> [Point3; 3] {
+        self.indices[triangle].map(|i| self.positions[i as usize])
+    }
+
+    // the handle of the given triangle
+    pub fn triangle(self: &Arc<Self>, triangle: usize) -> MeshTriangle<M> {
+        assert!(triangle < self.indices.len(), "triangle index out of range");
+
+        MeshTriangle {
+            mesh: Arc::clone(self),
+            index: triangle as u32,
+        }
+    }
+
+    // the handles of all the triangles, to be placed into the world or an accelerator
+    pub fn triangles(self: &Arc<Self>) -> World
+    where
+        M: 'static,
+    {
+        (0..self.indices.len())
+            .map(|i| Box::new(self.triangle(i)) as Box<dyn Hit>)
+            .collect()
+    }
+}
+
+/*
+    A triangle of a mesh, which looks its vertices up
+    in the buffers of the mesh it shares with the others.
+*/
+pub struct MeshTriangle<M: Scatter> {
+    mesh: Arc<TriangleMesh<M>>,
+    index: u32,
+}
+
+impl<M: Scatter> MeshTriangle<M> {
+    pub fn mesh(&self) -> &TriangleMesh<M> {
+        &self.mesh
+    }
+
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    fn vertices(&self) -> [Point3; 3] {
+        self.mesh.vertices(self.index as usize)
+    }
+
+    // the hit record with the normals and the texture coordinates of the mesh
+    fn record(&self, vertices: &[Point3; 3], ray: &Ray, hit: TriangleHit) -> HitRecord<'_> {
+        let indices = self.mesh.indices[self.index as usize];
+        let normals = self.mesh.normals.as_ref().map(|normals| indices.map(|i| normals[i as usize]));
+        let uvs = self.mesh.uvs.as_ref().map(|uvs| indices.map(|i| uvs[i as usize]));
+
+        triangle::record(vertices, normals, uvs, ray, hit, &self.mesh.material)
+    }
+}
+
+impl<M: Scatter> Hit for MeshTriangle<M> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let vertices = self.vertices();
+        triangle::intersect(&vertices, ray, t_min, t_max).map(|hit| self.record(&vertices, ray, hit))
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        triangle::intersect(&self.vertices(), ray, t_min, t_max).is_some()
+    }
+
+    fn hit_packet(&self, packet: &RayPacket, t_min: Float, t_max: Vec4, active: u32) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+        let vertices = self.vertices();
+        let hits = triangle::intersect_packet(&vertices, packet, t_min, t_max, active);
+        std::array::from_fn(|lane| hits[lane].map(|hit| self.record(&vertices, packet.ray(lane), hit)))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle::bounds(&self.vertices()))
+    }
+
+    // get the centroid of the triangle
+    fn centroid(&self) -> Point3 {
+        let vertices = self.vertices();
+        (vertices[0] + vertices[1] + vertices[2]) / 3.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::{Accelerator, RAY_EPSILON};
+    use crate::accelerators::bvh::Bvh;
+    use crate::accelerators::bvh::tests::assert_packets_match_single_rays;
+    use crate::material::Lambertian;
+    use crate::primitives::triangle::Triangle;
+    use crate::utils::util::Color;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SIZE: u32 = 24;
+
+    fn height(x: Float, z: Float) -> Float {
+        (x * 0.7).sin() * (z * 0.5).cos()
+    }
+
+    // the normal of the height field
+    fn terrain_normal(x: Float, z: Float) -> Vec3 {
+        let dx = 0.7 * (x * 0.7).cos() * (z * 0.5).cos();
+        let dz = -0.5 * (x * 0.7).sin() * (z * 0.5).sin();
+        Vec3::new(-dx, 1.0, -dz).normalize()
+    }
+
+    fn grid_points() -> impl Iterator<Item = (Float, Float)> {
+        (0..=SIZE).flat_map(|z| (0..=SIZE).map(move |x| (x as Float, z as Float)))
+    }
+
+    // a height field over a grid of SIZE x SIZE quads, two triangles each
+    fn terrain() -> TriangleMesh<Lambertian> {
+        terrain_with(None, None)
+    }
+
+    fn terrain_with(normals: Option<Vec<Vec3>>, uvs: Option<Vec<Vec2>>) -> TriangleMesh<Lambertian> {
+        let positions = grid_points().map(|(x, z)| Point3::new(x, height(x, z), z)).collect();
+
+        let vertex = |x: u32, z: u32| z * (SIZE + 1) + x;
+
+        let indices = (0..SIZE)
+            .flat_map(|z| (0..SIZE).flat_map(move |x| {
+                [
+                    [vertex(x, z), vertex(x + 1, z), vertex(x + 1, z + 1)],
+                    [vertex(x, z), vertex(x + 1, z + 1), vertex(x, z + 1)],
+                ]
+            }))
+            .collect();
+
+        TriangleMesh::new(positions, indices, normals, uvs, Lambertian::new(Color::new(0.4, 0.6, 0.3)))
+    }
+
+    #[test]
+    fn mesh_matches_owned_triangles() {
+        let mesh = Arc::new(terrain());
+        assert_eq!(mesh.num_triangles(), (2 * SIZE * SIZE) as usize);
+
+        let mut bvh = Bvh::new(Some(4), None);
+        bvh.build(mesh.triangles());
+        assert_eq!(bvh.validate(), Ok(()));
+
+        // the same terrain with every triangle owning its vertices
+        let triangles: World = (0..mesh.num_triangles())
+            .map(|i| Box::new(Triangle::new(mesh.vertices(i), Lambertian::new(Color::ONE))) as Box<dyn Hit>)
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut num_hits = 0;
+
+        for _ in 0..2000 {
+            let origin = Point3::new(rng.gen_range(-5.0..30.0), rng.gen_range(2.0..10.0), rng.gen_range(-5.0..30.0));
+            let direction = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..0.2), rng.gen_range(-1.0..1.0));
+            let ray = Ray::new(origin, direction);
+
+            match (triangles.hit(&ray, RAY_EPSILON, Float::INFINITY), bvh.intersect(&ray, RAY_EPSILON, Float::INFINITY)) {
+                (Some(expected), Some(actual)) => {
+                    assert!((expected.t - actual.t).abs() < 0.0001);
+                    assert!((expected.normal - actual.normal).length() < 0.0001);
+                    num_hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("mesh and owned triangles disagree on a hit"),
+            }
+
+            assert_eq!(Hit::occluded(&triangles, &ray, RAY_EPSILON, 8.0), bvh.occluded(&ray, RAY_EPSILON, 8.0));
+        }
+
+        assert!(num_hits > 0);
+        assert_packets_match_single_rays(&bvh);
+    }
+
+    #[test]
+    fn triangles_share_the_mesh() {
+        let mesh = Arc::new(terrain());
+        let world = mesh.triangles();
+
+        // every handle refers to the one mesh, instead of copying its vertices and material
+        assert_eq!(Arc::strong_count(&mesh), world.len() + 1);
+        assert!(std::mem::size_of::<MeshTriangle<Lambertian>>() <= 16);
+
+        let triangle = mesh.triangle(5);
+        assert_eq!(triangle.index(), 5);
+        assert_eq!(triangle.bounding_box().unwrap().min().x, mesh.vertices(5)[0].x - Float::EPSILON);
+
+        drop(world);
+        drop(triangle);
+        assert_eq!(Arc::strong_count(&mesh), 1);
+    }
+
+    #[test]
+    fn smooth_shading_interpolates_the_vertices() {
+        let normals = grid_points().map(|(x, z)| terrain_normal(x, z)).collect();
+        let uvs = grid_points().map(|(x, z)| Vec2::new(x, z) / SIZE as Float).collect();
+        let mesh = Arc::new(terrain_with(Some(normals), Some(uvs)));
+
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(mesh.triangles());
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let (mut smooth_error, mut flat_error) = (0.0, 0.0);
+
+        for _ in 0..500 {
+            let origin = Point3::new(rng.gen_range(1.0..23.0), 5.0, rng.gen_range(1.0..23.0));
+            let ray = Ray::new(origin, Vec3::new(0.0, -1.0, 0.0));
+            let rec = bvh.intersect(&ray, RAY_EPSILON, Float::INFINITY).unwrap();
+
+            // the texture coordinates are linear in the grid, so the interpolation reproduces them
+            assert!((rec.uv - Vec2::new(rec.p.x, rec.p.z) / SIZE as Float).length() < 0.0001);
+
+            let expected = terrain_normal(rec.p.x, rec.p.z);
+            smooth_error += (rec.shading_normal - expected).length();
+            flat_error += (rec.normal - expected).length();
+            assert!(rec.shading_normal.dot(rec.normal) > 0.0);
+
+            // the tangents follow the texture coordinates along x and z
+            assert!(rec.dpdu.normalize().dot(Vec3::X) > 0.5);
+            assert!(rec.dpdv.normalize().dot(Vec3::Z) > 0.5);
+        }
+
+        // the interpolated normals follow the smooth surface closer than the facets do
+        assert!(smooth_error < 0.5 * flat_error);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod sphere;
-pub mod triangle;
\ No newline at end of file
+pub mod triangle;
+pub mod instance;
+pub mod mesh;
\ No newline at end of file
@@ -1,29 +1,28 @@
 
-use glam::Vec3;
+use crate::utils::util::{Float, Point3, Vec2, Vec3, consts};
 use crate::hit::{Hit, HitRecord};
 use crate::material::Scatter;
 use crate::ray::Ray;
-use crate::utils::util::Point3;
 use crate::utils::aabb::Aabb;
 
 pub struct Sphere<M: Scatter> {
     center: Point3,
-    radius: f32,
+    radius: Float,
     mat: M,
 }
 
 impl<M: Scatter> Sphere<M> {
-    pub fn new(center: Point3, radius: f32, mat: M) -> Self {
+    pub fn new(center: Point3, radius: Float, mat: M) -> Self {
         Self {
             center,
             radius,
             mat,
         }
     }
-}
 
-impl<M: Scatter> Hit for Sphere<M> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    // returns the distance to the nearest intersection
+    // of the ray in between t_min and t_max
+    fn intersect(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<Float> {
         let oc = r.origin() - self.center;
         let a = r.direction().length().powi(2);
         let half_b = oc.dot(r.direction());
@@ -38,30 +37,54 @@ impl<M: Scatter> Hit for Sphere<M> {
         let sqrtd = discriminant.sqrt();
         let mut root = (-half_b - sqrtd) / a;
         if root < t_min || t_max < root {
-            root = (-half_b - sqrtd) / a;
+            root = (-half_b + sqrtd) / a;
             if root < t_min || t_max < root {
                 return None;
             }
         }
 
-        let mut rec = HitRecord {
-            t: root,
-            p: r.at(root),
-            mat: &self.mat,
-            normal: Vec3::new(0.0, 0.0, 0.0),
-            front_face: false,
-        };
+        Some(root)
+    }
+}
+
+impl<M: Scatter> Hit for Sphere<M> {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let root = self.intersect(r, t_min, t_max)?;
+
+        let outward_normal = (r.at(root) - self.center) / self.radius;
+        let mut rec = HitRecord::new(r, root, outward_normal, &self.mat);
+
+        // the point on the unit sphere, theta is the angle up from -y and phi
+        // the angle around the y axis from -x, they span the texture coordinates
+        let p = (rec.p - self.center) / self.radius.abs();
+        let theta = (-p.y).clamp(-1.0, 1.0).acos();
+        let phi = (-p.z).atan2(p.x) + consts::PI;
 
-        let outward_normal = (rec.p - self.center) / self.radius;
-        rec.set_face_normal(r, outward_normal);
+        rec.uv = Vec2::new(phi / consts::TAU, theta / consts::PI);
+
+        // the derivatives of p = (-cos(phi) sin(theta), -cos(theta), sin(phi) sin(theta)) along
+        // u and v. They vanish at the poles, which keep the tangents of the record
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+
+        if sin_theta > 0.0 {
+            let radius = self.radius.abs();
+            rec.dpdu = consts::TAU * radius * Vec3::new(sin_phi * sin_theta, 0.0, cos_phi * sin_theta);
+            rec.dpdv = consts::PI * radius * Vec3::new(-cos_phi * cos_theta, sin_theta, sin_phi * cos_theta);
+        }
 
         Some(rec)
     }
+
+    fn occluded(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(r, t_min, t_max).is_some()
+    }
     
     fn bounding_box(&self) -> Option<Aabb> {
         //return the bounding box of the sphere 
-        // that is already calculated in the constructor
-        let r = Vec3::new(self.radius, self.radius, self.radius);
+        // that is already calculated in the constructor.
+        // the hollow spheres facing inwards have a negative radius
+        let r = Vec3::splat(self.radius.abs());
         let box_sphere = Aabb::new(self.center - r, self.center + r);        
         Some(box_sphere)
     }
@@ -71,3 +94,63 @@ impl<M: Scatter> Hit for Sphere<M> {
         self.center
     }    
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::accelerator::Accelerator;
+    use crate::accelerators::bvh::Bvh;
+    use crate::material::Lambertian;
+    use crate::utils::util::Color;
+
+    #[test]
+    fn texture_coordinates_and_tangents() {
+        let sphere = Sphere::new(Point3::new(0.0, 0.0, 0.0), 2.0, Lambertian::new(Color::ONE));
+
+        // the ray hits the sphere on the +z axis, on the equator a quarter of the way around
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = sphere.hit(&ray, 0.001, Float::INFINITY).unwrap();
+
+        assert!((rec.uv - Vec2::new(0.25, 0.5)).length() < 0.0001);
+
+        // the tangents lie in the surface and span it with the orientation of the normal
+        assert!(rec.dpdu.dot(rec.normal).abs() < 0.0001);
+        assert!(rec.dpdv.dot(rec.normal).abs() < 0.0001);
+        assert!(rec.dpdu.cross(rec.dpdv).normalize().dot(rec.normal) > 0.9999);
+
+        // the sphere is smooth already, so it shades with its geometric normal
+        assert_eq!(rec.shading_normal, rec.normal);
+    }
+
+    #[test]
+    fn hit_from_inside() {
+        let sphere = Sphere::new(Point3::new(0.0, 0.0, 0.0), 2.0, Lambertian::new(Color::ONE));
+
+        // the nearer root lies behind the ray, which leaves the sphere at the farther one
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = sphere.hit(&ray, 0.001, Float::INFINITY).unwrap();
+
+        assert!((rec.t - 3.0).abs() < 0.0001);
+        assert!(!rec.front_face);
+    }
+
+    #[test]
+    fn hollow_spheres_are_bounded() {
+        let hollow = Sphere::new(Point3::new(1.0, 0.0, 0.0), -2.0, Lambertian::new(Color::ONE));
+
+        let aabb = hollow.bounding_box().unwrap();
+        assert_eq!(aabb.min(), Point3::new(-1.0, -2.0, -2.0));
+        assert_eq!(aabb.max(), Point3::new(3.0, 2.0, 2.0));
+
+        // the accelerators cull the objects by their boxes
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(vec![Box::new(hollow)]);
+
+        let ray = Ray::new(Point3::new(1.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = bvh.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+        assert!((rec.t - 3.0).abs() < 0.0001);
+
+        // the normal of the hollow sphere points inwards
+        assert!(!rec.front_face);
+    }
+}
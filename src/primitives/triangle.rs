@@ -1,7 +1,7 @@
+use crate::utils::util::{Float, Point3, Vec2, Vec3, Vec4};
 
 use crate::hit::Hit;
-use crate::ray::Ray;
-use crate::utils::util::Point3;
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
 use crate::material::Scatter;
 use crate::hit::HitRecord;
 use crate::utils::aabb::Aabb;
@@ -21,78 +21,408 @@ impl <M: Scatter> Triangle<M>{
 }
 
 impl<M: Scatter> Hit for Triangle<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let edge1 = self.vertices[1] - self.vertices[0];
-        let edge2 = self.vertices[2] - self.vertices[0];
-        let h = ray.direction().cross(edge2);
-        let a = edge1.dot(h);
-    
-        if a > -std::f32::EPSILON && a < std::f32::EPSILON {
-            return None; // This ray is parallel to this triangle.
-        }
-    
-        let f = 1.0 / a;
-        let s = ray.origin() - self.vertices[0];
-        let u = f * s.dot(h);
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        intersect(&self.vertices, ray, t_min, t_max).map(|hit| record(&self.vertices, None, None, ray, hit, &self.material))
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        intersect(&self.vertices, ray, t_min, t_max).is_some()
+    }
+
+    fn hit_packet(&self, packet: &RayPacket, t_min: Float, t_max: Vec4, active: u32) -> [Option<HitRecord<'_>>; PACKET_SIZE] {
+        let hits = intersect_packet(&self.vertices, packet, t_min, t_max, active);
+        std::array::from_fn(|lane| hits[lane].map(|hit| record(&self.vertices, None, None, packet.ray(lane), hit, &self.material)))
+    }
     
-        if u < 0.0 || u > 1.0 {
-            return None;
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(bounds(&self.vertices))
+    }
+
+    // get the centroid of the triangle
+    fn centroid(&self) -> Point3 {
+        (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0
+    }    
+}
+
+/*
+    The geometry of a triangle, shared by the triangles owning their vertices and
+    the triangles of a mesh, which look their vertices up in the buffers of the mesh.
+*/
+
+// an intersection of a ray with a triangle
+#[derive(Clone, Copy)]
+pub(crate) struct TriangleHit {
+    pub t: Float,
+    pub barycentrics: Vec3, // the weights of the vertices at the intersection
+}
+
+// the texture coordinates of a triangle without any
+const DEFAULT_UVS: [Vec2; 3] = [Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(1.0, 1.0)];
+
+// the hit record of the ray hitting the triangle. The normals and the texture coordinates of the
+// vertices are interpolated with the barycentrics, if the triangle has any
+pub(crate) fn record<'a>(vertices: &[Point3; 3],
+                         normals: Option<[Vec3; 3]>,
+                         uvs: Option<[Vec2; 3]>,
+                         ray: &Ray,
+                         hit: TriangleHit,
+                         mat: &'a dyn Scatter) -> HitRecord<'a> {
+    let edge1 = vertices[1] - vertices[0];
+    let edge2 = vertices[2] - vertices[0];
+
+    // calculate the face normal
+    let normal = edge1.cross(edge2).normalize();
+
+    let mut rec = HitRecord::new(ray, hit.t, normal, mat);
+    let b = hit.barycentrics;
+
+    let uvs = uvs.unwrap_or(DEFAULT_UVS);
+    rec.uv = b.x * uvs[0] + b.y * uvs[1] + b.z * uvs[2];
+    rec.barycentrics = b;
+
+    // the tangents solve p - p2 = (u - u2) dpdu + (v - v2) dpdv for the
+    // vertices, degenerate texture coordinates keep those of the record
+    let duv02 = uvs[0] - uvs[2];
+    let duv12 = uvs[1] - uvs[2];
+    let dp02 = vertices[0] - vertices[2];
+    let dp12 = vertices[1] - vertices[2];
+    let determinant = duv02.x * duv12.y - duv02.y * duv12.x;
+
+    if determinant.abs() > 1.0e-9 {
+        let dpdu = (duv12.y * dp02 - duv02.y * dp12) / determinant;
+        let dpdv = (duv02.x * dp12 - duv12.x * dp02) / determinant;
+
+        if dpdu.cross(dpdv).length_squared() > 0.0 {
+            rec.dpdu = dpdu;
+            rec.dpdv = dpdv;
         }
-    
-        let q = s.cross(edge1);
-        let v = f * ray.direction().dot(q);
-    
-        if v < 0.0 || u + v > 1.0 {
-            return None;
+    }
+
+    if let Some(normals) = normals {
+        let shading_normal = b.x * normals[0] + b.y * normals[1] + b.z * normals[2];
+
+        if shading_normal.length_squared() > 0.0 {
+            rec.set_shading_normal(shading_normal.normalize());
         }
-    
-        // At this stage we can compute t to find out where the intersection point is on the line.
-        let t = f * edge2.dot(q);
-    
-        if t > std::f32::EPSILON {
-            // ray intersection
-            let p = ray.origin() + t * ray.direction();
-
-            // calculate the face normal
-            let normal = edge1.cross(edge2).normalize();
-
-            let mut rec = HitRecord 
-            {   p: p, 
-                normal: normal, 
-                mat: &self.material, 
-                t: t, 
-                front_face: false };
-
-            rec.set_face_normal(ray, normal);
-            
-            Some(rec)
+    }
+
+    rec
+}
+
+/*
+    The watertight test of Woop et al. The vertices are moved into a space in which the
+    ray starts at the origin and points along +z, where the ray hits the triangle if the
+    2D edge functions of the projected vertices agree in sign. A ray passing through an
+    edge or a vertex shared by several triangles finds the same edge functions in all of
+    them, so it can not slip in between. Returns the intersection if it lies in between
+    t_min and t_max.
+*/
+pub(crate) fn intersect(vertices: &[Point3; 3], ray: &Ray, t_min: Float, t_max: Float) -> Option<TriangleHit> {
+    let [kx, ky, kz] = shear_axes(ray.direction());
+    let direction = ray.direction();
+
+    // shear the ray direction onto the z axis
+    let sx = direction[kx] / direction[kz];
+    let sy = direction[ky] / direction[kz];
+    let sz = 1.0 / direction[kz];
+
+    let [a, b, c] = vertices.map(|v| v - ray.origin());
+
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+    let (u, v, w) = edge_functions([ax, ay], [bx, by], [cx, cy]);
+
+    // the ray misses the triangle if it lies outside of any of the edges, or the
+    // triangle is seen edge on
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+
+    if det == 0.0 {
+        return None;
+    }
+
+    // the distance is interpolated from the scaled z coordinates of the vertices
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+
+    // the intersection has to lie in the acceptable range, so that the
+    // closest hit found so far is not overwritten by a farther one
+    if t >= t_min && t <= t_max {
+        Some(TriangleHit { t, barycentrics: Vec3::new(u, v, w) / det })
+    } else {
+        None
+    }
+}
+
+// the watertight test of intersect, carried out for all the rays
+// of the packet at once in the lanes of the SIMD vectors
+pub(crate) fn intersect_packet(vertices: &[Point3; 3], packet: &RayPacket, t_min: Float, t_max: Vec4, active: u32) -> [Option<TriangleHit>; PACKET_SIZE] {
+    // every ray of the packet has its own sheared space
+    let axes: [[usize; 3]; PACKET_SIZE] = std::array::from_fn(|lane| shear_axes(packet.ray(lane).direction()));
+
+    // the components along the k-th sheared axis of every ray
+    let permute = |v: &[Vec4; 3], k: usize| Vec4::from_array(std::array::from_fn(|lane| v[axes[lane][k]][lane]));
+
+    let direction = [0, 1, 2].map(|axis| packet.direction(axis));
+    let (dx, dy, dz) = (permute(&direction, 0), permute(&direction, 1), permute(&direction, 2));
+
+    let sz = dz.recip();
+    let sx = dx * sz;
+    let sy = dy * sz;
+
+    // the vertices relative to the ray origins, sheared onto the z axis
+    let [a, b, c] = vertices.map(|v| {
+        let relative = [0, 1, 2].map(|axis| Vec4::splat(v[axis]) - packet.origin(axis));
+        let (x, y, z) = (permute(&relative, 0), permute(&relative, 1), permute(&relative, 2));
+
+        (x - sx * z, y - sy * z, sz * z)
+    });
+
+    let u = c.0 * b.1 - c.1 * b.0;
+    let v = a.0 * c.1 - a.1 * c.0;
+    let w = b.0 * a.1 - b.1 * a.0;
+
+    let zero = Vec4::ZERO;
+    let det = u + v + w;
+    let t = (u * a.2 + v * b.2 + w * c.2) / det;
+
+    // rays outside of an edge, seeing the triangle edge on or
+    // out of the acceptable range of t are rejected
+    let outside = (u.cmplt(zero) | v.cmplt(zero) | w.cmplt(zero)) & (u.cmpgt(zero) | v.cmpgt(zero) | w.cmpgt(zero));
+    let mask = !outside & det.cmpne(zero) & t.cmpge(Vec4::splat(t_min)) & t.cmple(t_max);
+
+    // the rays passing exactly through an edge are left to the scalar test
+    let on_edge = (u.cmpeq(zero) | v.cmpeq(zero) | w.cmpeq(zero)).bitmask();
+
+    let mask = mask.bitmask() & active;
+
+    std::array::from_fn(|lane| {
+        if active & on_edge & (1 << lane) != 0 {
+            intersect(vertices, packet.ray(lane), t_min, t_max[lane])
+        } else if mask & (1 << lane) != 0 {
+            Some(TriangleHit { t: t[lane], barycentrics: Vec3::new(u[lane], v[lane], w[lane]) / det[lane] })
         } else {
-            // This means that there is a line intersection but not a ray intersection.
             None
         }
+    })
+}
+
+pub(crate) fn bounds(vertices: &[Point3; 3]) -> Aabb {
+    // find the min and max of the x, y, and z coordinates of the triangle
+    // add/sub epsilon to avoid infinitely thin bounding boxes
+    let min = Point3::new(
+        vertices[0].x.min(vertices[1].x.min(vertices[2].x)) - Float::EPSILON,
+        vertices[0].y.min(vertices[1].y.min(vertices[2].y)) - Float::EPSILON,
+        vertices[0].z.min(vertices[1].z.min(vertices[2].z)) - Float::EPSILON,
+    );
+
+    let max = Point3::new(
+        vertices[0].x.max(vertices[1].x.max(vertices[2].x)) + Float::EPSILON,
+        vertices[0].y.max(vertices[1].y.max(vertices[2].y)) + Float::EPSILON,
+        vertices[0].z.max(vertices[1].z.max(vertices[2].z)) + Float::EPSILON,
+    );
+
+    Aabb::new(min, max)
+}
+
+// the axes of the space sheared along the ray direction, z is the dominant axis of the direction. x
+// and y are swapped for a negative z, so that the winding of the triangle is kept
+fn shear_axes(direction: Vec3) -> [usize; 3] {
+    let abs = direction.abs();
+
+    let kz = if abs.x > abs.y {
+        if abs.x > abs.z { 0 } else { 2 }
+    } else if abs.y > abs.z {
+        1
+    } else {
+        2
+    };
+
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+
+    if direction[kz] < 0.0 {
+        [ky, kx, kz]
+    } else {
+        [kx, ky, kz]
     }
-    
-    fn bounding_box(&self) -> Option<Aabb> {
-        
-        // find the min and max of the x, y, and z coordinates of the triangle
-        // add/sub epsilon to avoid infinitely thin bounding boxes
-        let min = Point3::new(
-            self.vertices[0].x.min(self.vertices[1].x.min(self.vertices[2].x)) - std::f32::EPSILON,
-            self.vertices[0].y.min(self.vertices[1].y.min(self.vertices[2].y)) - std::f32::EPSILON,
-            self.vertices[0].z.min(self.vertices[1].z.min(self.vertices[2].z)) - std::f32::EPSILON,
+}
+
+// the 2D edge functions of the sheared vertices, an edge function is exactly zero when the
+// ray passes through the edge. This is only told reliably in double precision, so the edge
+// functions are evaluated once more in double precision in that case
+fn edge_functions(a: [Float; 2], b: [Float; 2], c: [Float; 2]) -> (Float, Float, Float) {
+    let u = c[0] * b[1] - c[1] * b[0];
+    let v = a[0] * c[1] - a[1] * c[0];
+    let w = b[0] * a[1] - b[1] * a[0];
+
+    #[cfg(not(feature = "f64"))]
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        let [a, b, c] = [a, b, c].map(|p| p.map(f64::from));
+
+        return (
+            (c[0] * b[1] - c[1] * b[0]) as Float,
+            (a[0] * c[1] - a[1] * c[0]) as Float,
+            (b[0] * a[1] - b[1] * a[0]) as Float,
         );
+    }
+
+    (u, v, w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::utils::util::{Color, Vec2, consts};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
-        let max = Point3::new(
-            self.vertices[0].x.max(self.vertices[1].x.max(self.vertices[2].x)) + std::f32::EPSILON,
-            self.vertices[0].y.max(self.vertices[1].y.max(self.vertices[2].y)) + std::f32::EPSILON,
-            self.vertices[0].z.max(self.vertices[1].z.max(self.vertices[2].z)) + std::f32::EPSILON,
+    #[test]
+    fn packet_hit_matches_scalar() {
+        let triangle = Triangle::new(
+            [Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Lambertian::new(Color::new(0.5, 0.5, 0.5)),
         );
 
-        Some(Aabb::new(min, max))       
+        let mut rng = StdRng::seed_from_u64(5);
+
+        for _ in 0..500 {
+            let rays: [Ray; PACKET_SIZE] = std::array::from_fn(|_| {
+                let origin = Point3::new(rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0), rng.gen_range(-5.0..5.0));
+                let target = Point3::new(rng.gen_range(-1.5..1.5), rng.gen_range(-1.5..1.5), 0.0);
+                Ray::new(origin, target - origin)
+            });
+
+            let t_max = Vec4::new(Float::INFINITY, 0.5, Float::INFINITY, Float::INFINITY);
+            let packet = RayPacket::new(rays);
+
+            // the third ray is left out of the packet
+            let hits = triangle.hit_packet(&packet, 0.001, t_max, 0b1011);
+
+            for (lane, hit) in hits.iter().enumerate() {
+                let expected = if lane == 2 { None } else { triangle.hit(packet.ray(lane), 0.001, t_max[lane]) };
+
+                match (expected, hit) {
+                    (Some(expected), Some(actual)) => {
+                        assert!((expected.t - actual.t).abs() < 0.0001);
+                        assert!((expected.normal - actual.normal).length() < 0.0001);
+                    }
+                    (None, None) => {}
+                    _ => panic!("packet and scalar triangle test disagree on a hit"),
+                }
+            }
+        }
+
+        // a ray parallel to the triangle misses it
+        let parallel = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hit = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let packet = RayPacket::new([parallel, hit, Ray::new(Point3::ZERO, Vec3::X), Ray::new(Point3::ZERO, Vec3::X)]);
+
+        let hits = triangle.hit_packet(&packet, 0.001, Vec4::splat(Float::INFINITY), 0b0011);
+        assert!(hits[0].is_none());
+        assert!((hits[1].as_ref().unwrap().t - 5.0).abs() < 0.0001);
     }
 
-    // get the centroid of the triangle
-    fn centroid(&self) -> Point3 {
-        (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0
-    }    
-}
\ No newline at end of file
+    #[test]
+    fn occluded_within_range() {
+        let triangle = Triangle::new(
+            [Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+        );
+
+        // the triangle lies 5 units along the ray
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(triangle.occluded(&ray, 0.001, Float::INFINITY));
+        assert!(triangle.occluded(&ray, 0.001, 5.5));
+        assert!(!triangle.occluded(&ray, 0.001, 4.5));
+        assert!(!triangle.occluded(&ray, 5.5, Float::INFINITY));
+
+        let miss = Ray::new(Point3::new(3.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!triangle.occluded(&miss, 0.001, Float::INFINITY));
+    }
+
+    #[test]
+    fn rays_through_shared_edges_do_not_leak() {
+        // a fan of triangles in a tilted plane, sharing the edges and the center vertex. The
+        // plane is seen from above, so every ray aimed at an edge has to hit the fan
+        let corners: Vec<Point3> = (0..7)
+            .map(|i| {
+                let (sin, cos) = (i as Float * consts::TAU / 7.0).sin_cos();
+                Point3::new(cos, sin, 0.1 + 0.3 * cos - 0.2 * sin)
+            })
+            .collect();
+
+        let fan: Vec<Triangle<Lambertian>> = (0..7)
+            .map(|i| Triangle::new([Point3::new(0.0, 0.0, 0.1), corners[i], corners[(i + 1) % 7]], Lambertian::new(Color::ONE)))
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(11);
+
+        for _ in 0..20000 {
+            // aim at a point on one of the shared edges, or at the shared center vertex
+            let edge = rng.gen_range(0..7);
+            let target = if rng.gen_bool(0.1) {
+                Point3::new(0.0, 0.0, 0.1)
+            } else {
+                Point3::new(0.0, 0.0, 0.1).lerp(corners[edge], rng.gen_range(0.01..0.99))
+            };
+
+            let origin = Point3::new(rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0), rng.gen_range(1.0..5.0));
+            let ray = Ray::new(origin, target - origin);
+
+            assert!(fan.iter().any(|triangle| triangle.hit(&ray, 0.0, Float::INFINITY).is_some()),
+                    "a ray slipped through the edges of the fan");
+        }
+    }
+
+    #[test]
+    fn hit_honors_t_min() {
+        let triangle = Triangle::new(
+            [Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+        );
+
+        // the triangle lies closer to the origin than the epsilon of a float
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0e-8), Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(triangle.hit(&ray, 0.0, Float::INFINITY).is_some());
+        assert!(triangle.hit(&ray, 0.001, Float::INFINITY).is_none());
+
+        // the hit from behind reports the back face
+        let ray = Ray::new(Point3::new(0.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0));
+        let rec = triangle.hit(&ray, 0.001, Float::INFINITY).unwrap();
+        assert!((rec.t - 2.0).abs() < 0.0001);
+        assert!(!rec.front_face);
+    }
+
+    #[test]
+    fn record_interpolates_barycentrics() {
+        let vertices = [Point3::new(-1.0, -1.0, 0.0), Point3::new(2.0, -1.0, 0.5), Point3::new(0.0, 1.0, 0.0)];
+        let triangle = Triangle::new(vertices, Lambertian::new(Color::ONE));
+
+        let ray = Ray::new(Point3::new(0.3, -0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = triangle.hit(&ray, 0.001, Float::INFINITY).unwrap();
+
+        // the barycentrics weigh the vertices into the hit point
+        let b = rec.barycentrics;
+        assert!((b.x + b.y + b.z - 1.0).abs() < 0.0001);
+        assert!((b.x * vertices[0] + b.y * vertices[1] + b.z * vertices[2] - rec.p).length() < 0.0001);
+
+        // without texture coordinates the triangle is mapped onto (0, 0), (1, 0) and (1, 1)
+        assert!((rec.uv - Vec2::new(b.y + b.z, b.z)).length() < 0.0001);
+
+        // moving along the tangents by the difference of the texture coordinates leads from vertex to vertex
+        let p0 = vertices[2] + (0.0 - 1.0) * rec.dpdu + (0.0 - 1.0) * rec.dpdv;
+        let p1 = vertices[2] + (1.0 - 1.0) * rec.dpdu + (0.0 - 1.0) * rec.dpdv;
+        assert!((p0 - vertices[0]).length() < 0.0001);
+        assert!((p1 - vertices[1]).length() < 0.0001);
+
+        assert_eq!(rec.shading_normal, rec.normal);
+    }
+}
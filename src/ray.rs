@@ -1,6 +1,12 @@
-use glam::Vec3;
+use crate::utils::util::{Float, Point3, Vec3, Vec4};
 
-use crate::utils::util::Point3;
+use crate::utils::aabb::Aabb;
+
+// number of rays traced together in a packet, one per lane of a SIMD vector
+pub const PACKET_SIZE: usize = 4;
+
+// mask with the bits of all the rays of a packet set
+pub const PACKET_MASK: u32 = (1 << PACKET_SIZE) - 1;
 
 pub struct Ray {
     origo: Point3,
@@ -23,11 +29,75 @@ impl Ray {
         self.dir
     }
 
-    pub fn at(&self, t: f32) -> Point3 {
+    pub fn at(&self, t: Float) -> Point3 {
         self.origo + t * self.dir
     }
 }
 
+/*
+    A packet of coherent rays, for instance the samples of a pixel, which traverse the
+    acceleration structure together. The rays are stored in structure of arrays layout,
+    so that every lane of the glam SIMD vectors holds the component of one of the rays.
+*/
+pub struct RayPacket {
+    rays: [Ray; PACKET_SIZE],
+    origin: [Vec4; 3],
+    direction: [Vec4; 3],
+    inv_direction: [Vec4; 3],
+}
+
+impl RayPacket {
+    pub fn new(rays: [Ray; PACKET_SIZE]) -> Self {
+        let component = |v: fn(&Ray) -> Vec3, axis: usize| Vec4::from_array(std::array::from_fn(|lane| v(&rays[lane])[axis]));
+
+        let origin = [0, 1, 2].map(|axis| component(Ray::origin, axis));
+        let direction = [0, 1, 2].map(|axis| component(Ray::direction, axis));
+        let inv_direction = direction.map(|d| d.recip());
+
+        Self {
+            rays,
+            origin,
+            direction,
+            inv_direction,
+        }
+    }
+
+    // the ray in the given lane
+    pub fn ray(&self, lane: usize) -> &Ray {
+        &self.rays[lane]
+    }
+
+    // the components of the origins along the given axis
+    pub fn origin(&self, axis: usize) -> Vec4 {
+        self.origin[axis]
+    }
+
+    // the components of the directions along the given axis
+    pub fn direction(&self, axis: usize) -> Vec4 {
+        self.direction[axis]
+    }
+
+    // intersect all the rays with the bounding box at once. Returns a bit mask of
+    // the rays hitting the box in between t_min and their own t_max together with
+    // the distances at which the rays enter the box
+    pub fn hit_aabb(&self, aabb: &Aabb, t_min: Float, t_max: Vec4) -> (u32, Vec4) {
+        let (min, max) = (aabb.min(), aabb.max());
+
+        let mut t_near = Vec4::splat(t_min);
+        let mut t_far = t_max;
+
+        for axis in 0..3 {
+            let t0 = (Vec4::splat(min[axis]) - self.origin[axis]) * self.inv_direction[axis];
+            let t1 = (Vec4::splat(max[axis]) - self.origin[axis]) * self.inv_direction[axis];
+
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+
+        (t_near.cmple(t_far).bitmask(), t_near)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,4 +119,29 @@ mod tests {
         let expected = Vec3::new(16.0, 21.0, 1.0);
         assert_vec3_equal!(expected, position);
     }
+
+    #[test]
+    fn packet_box_test_matches_scalar() {
+        let aabb = Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+
+        let packet = RayPacket::new([
+            Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)),
+            Ray::new(Point3::new(3.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)),
+            Ray::new(Point3::new(-5.0, 0.5, 0.5), Vec3::new(1.0, 0.1, 0.0)),
+            Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 1.0)),
+        ]);
+
+        let (mask, t_near) = packet.hit_aabb(&aabb, 0.001, Vec4::splat(Float::INFINITY));
+
+        for lane in 0..PACKET_SIZE {
+            assert_eq!(mask & (1 << lane) != 0, aabb.hit(packet.ray(lane), 0.001, Float::INFINITY));
+        }
+
+        assert_eq!(mask, 0b0101);
+        assert_approx_eq!(t_near[0], 4.0, 0.0001);
+
+        // a ray ending before the box misses it
+        let (mask, _) = packet.hit_aabb(&aabb, 0.001, Vec4::new(3.0, 10.0, 10.0, 10.0));
+        assert_eq!(mask, 0b0100);
+    }
 }
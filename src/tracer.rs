@@ -3,13 +3,33 @@ use indicatif::{ParallelProgressIterator, ProgressBar, ProgressFinish, ProgressS
 use rayon::prelude::*;
 use rand::prelude::*;
 
-use crate::utils::util::{Color,Util};
+use crate::utils::util::{Color, Float, Util};
 use crate::camera::Camera;
-use crate::hit::{Hit, World};
-use crate::ray::Ray;
+use crate::accelerators::accelerator::{Accelerator, RAY_EPSILON};
+use crate::hit::HitRecord;
+use crate::ray::{Ray, RayPacket, PACKET_SIZE};
 use std::{fs, path::Path};
 use std::sync::RwLock;
 
+// a rectangle of pixels of the image, given by its upper left corner and its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// what the rays leaving the scene see
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Background {
+    // the sky, white at the horizon blending into blue overhead, which lights the scene
+    #[default]
+    Sky,
+    // a uniform color, black for the scenes lit by their emitting surfaces only
+    Color(Color),
+}
+
 pub struct Tracer {
 
     // the fields within the struct are private
@@ -17,6 +37,10 @@ pub struct Tracer {
     image_width: u32,
     image_height: u32,
     samples_per_pixel: u32,
+    region: Region, // the pixels rendered into the image buffer
+    seed: Option<u64>, // seeds the random numbers of every pixel, unseeded renderings differ every time
+    quiet: bool, // no progress bar and messages
+    background: Background,
 }
 
 impl Tracer {
@@ -29,35 +53,82 @@ impl Tracer {
             image_width: width,
             image_height: height,
             samples_per_pixel: samples,
+            region: Region { x: 0, y: 0, width, height },
+            seed: None,
+            quiet: false,
+            background: Background::Sky,
         }
     }
 
-    fn ray_color(&self, r: &Ray, world: &World, depth: u32) -> Color {
+    // render only the given region of the image, which becomes the size of the saved image.
+    // The camera still spans the whole image, so the region is cropped out of the full rendering
+    pub fn set_region(&mut self, region: Region) {
+        assert!(region.width > 0 && region.height > 0, "the region is empty");
+        assert!(region.width <= self.image_width.saturating_sub(region.x) &&
+                region.height <= self.image_height.saturating_sub(region.y),
+                "the region exceeds the image");
+
+        self.region = region;
+        self.image_buffer = RwLock::new(RgbImage::new(region.width, region.height));
+    }
+
+    // seed the random numbers, so that renderings with the same seed are the same
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    // hide the progress bar and the messages, for batch jobs
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    // set what the rays leaving the scene see, the sky by default
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    fn ray_color<A: Accelerator>(&self, r: &Ray, scene: &A, depth: u32) -> Color {
+        if depth == 0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        self.shade(r, scene.intersect(r, RAY_EPSILON, Float::INFINITY), scene, depth)
+    }
+
+    // the color carried along the ray given its closest intersection with the scene
+    fn shade<A: Accelerator>(&self, r: &Ray, hit: Option<HitRecord<'_>>, scene: &A, depth: u32) -> Color {
         if depth == 0 {
             return Color::new(0.0, 0.0, 0.0);
         }
 
-        match world.hit(r,0.001, f32::INFINITY) {
+        match hit {
             Some(rec) => {
+                let emitted = rec.mat.emitted(&rec);
+
                 match rec.mat.scatter(r,&rec) {
-                    Some((attenuation, scattered)) => attenuation * self.ray_color(&scattered, world, depth - 1),
-                    None => Color::new(0.0, 0.0, 0.0),
+                    Some((attenuation, scattered)) => emitted + attenuation * self.ray_color(&scattered, scene, depth - 1),
+                    None => emitted,
                 }
             },
-            None => {
-                let unit_direction = r.direction().normalize();
-                let t = 0.5 * (unit_direction.y + 1.0);
-                (1.0 - t) * Color::new(1.0, 1.0, 1.0) + t * Color::new(0.5, 0.7, 1.0)
-            }
+            None => match self.background {
+                Background::Sky => {
+                    let unit_direction = r.direction().normalize();
+                    let t = 0.5 * (unit_direction.y + 1.0);
+                    (1.0 - t) * Color::new(1.0, 1.0, 1.0) + t * Color::new(0.5, 0.7, 1.0)
+                }
+                Background::Color(color) => color,
+            },
         }
     }
 
-    pub fn trace(&self, cam: &Camera, world: &World, max_depth: u32) {
+    // render the scene through any acceleration structure built over the world
+    pub fn trace<A: Accelerator>(&self, cam: &Camera, scene: &A, max_depth: u32) {
 
         let style = ProgressStyle::default_bar().template(
             "{spinner:.green} [{wide_bar:.green/white}] {percent}% - {elapsed_precise} elapsed {msg}",
         );
-        let progress_bar = ProgressBar::new((self.image_width * self.image_height) as u64);
+        let num_pixels = (self.region.width * self.region.height) as u64;
+        let progress_bar = if self.quiet { ProgressBar::hidden() } else { ProgressBar::new(num_pixels) };
         progress_bar.set_style(style.unwrap().progress_chars("#>-"));
 
         match self.image_buffer.write() {
@@ -67,21 +138,46 @@ impl Tracer {
                     .progress_with(progress_bar.clone())
                     .for_each(|( x, y,px_out)| {
 
+                        // the pixel of the whole image
+                        let (x, y) = (x + self.region.x, y + self.region.y);
+
+                        // every pixel of a seeded rendering draws its own sequence of random
+                        // numbers, whichever thread renders it and whatever region is rendered
+                        if let Some(seed) = self.seed {
+                            let pixel = (y as u64) * (self.image_width as u64) + x as u64;
+                            Util::seed(seed ^ pixel.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                        }
+
                         let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-                        let mut rng = rand::thread_rng();
 
                         // generate random samples
-                        let random_samples : Vec<(f32, f32)> = (0..self.samples_per_pixel)
-                            .map(|_| (rng.gen(), rng.gen()))
-                            .collect();
+                        let random_samples : Vec<(Float, Float)> = Util::with_rng(|rng| {
+                            (0..self.samples_per_pixel)
+                                .map(|_| (rng.gen(), rng.gen()))
+                                .collect()
+                        });
+
+                        let camera_ray = |(random_u, random_v): (Float, Float)| {
+                            let u = ((x as Float) + random_u) / ((self.image_width - 1) as Float);
+                            let v = 1.0 - (((y as Float) + random_v) / ((self.image_height - 1) as Float));
+                            cam.get_ray(u, v)
+                        };
 
-                        for (random_U,random_V) in random_samples {
+                        // the primary rays traverse the scene together in packets of the samples
+                        // of a pixel, rather than of adjacent pixels. The samples of a pixel are
+                        // at least as coherent, and every pixel keeps drawing its own sequence of
+                        // random numbers. The last packet of a pixel is filled up with copies of
+                        // its first ray, whose hits are dropped
+                        for samples in random_samples.chunks(PACKET_SIZE) {
+                            let rays: [Option<Ray>; PACKET_SIZE] = std::array::from_fn(|lane| samples.get(lane).map(|&sample| camera_ray(sample)));
+                            let (origin, direction) = rays[0].as_ref().map(|ray| (ray.origin(), ray.direction())).unwrap();
 
-                            let u = ((x as f32) + random_U) / ((self.image_width - 1) as f32);
-                            let v = 1.0 - (((y as f32) + random_V) / ((self.image_height - 1) as f32));
-                            let ray = cam.get_ray(u, v); 
-            
-                            pixel_color += self.ray_color(&ray, &world, max_depth);                       
+                            let packet = RayPacket::new(rays.map(|ray| ray.unwrap_or_else(|| Ray::new(origin, direction))));
+                            let hits = scene.intersect_packet(&packet, RAY_EPSILON, Float::INFINITY);
+
+                            for (lane, hit) in hits.into_iter().enumerate().take(samples.len()) {
+                                pixel_color += self.shade(packet.ray(lane), hit, scene, max_depth);
+                            }
                         }
                         *px_out = Rgb(Util::gamma_correction(&pixel_color, self.samples_per_pixel));
                     });
@@ -91,23 +187,182 @@ impl Tracer {
         progress_bar.with_finish(ProgressFinish::WithMessage("\nScene Rendering Completed.".into()));
     }
 
-    pub fn save(&self,image_path: &str, file_name: &str){
-        let folder_creation = fs::create_dir_all(image_path);
-
-        if folder_creation.is_err() {
-            panic!("Error creating the output folder");
-        }
+    // save the image into the folder, which is created if missing. The errors are returned
+    // for the caller to report, a batch job must not end successfully without its image
+    pub fn save(&self, image_path: &str, file_name: &str) -> Result<(), image::ImageError> {
+        fs::create_dir_all(image_path)?;
 
         let path = Path::new(".");
         let dirs = path.join(image_path).join(file_name);
 
-        match self.image_buffer.read()
-        {
-            Ok(locked_buffer) => match locked_buffer.save(dirs) {
-                Ok(_) => println!("Image saved successfully"),
-                Err(err) => eprintln!("Error saving image: {err}",)
-            },
+        match self.image_buffer.read() {
+            Ok(locked_buffer) => locked_buffer.save(dirs)?,
             Err(_) => panic!("Error locking the image buffer"),
         };
+
+        if !self.quiet {
+            println!("Image saved successfully");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accelerators::bvh::Bvh;
+    use crate::accelerators::bvh::tests::sphere_world;
+    use crate::accelerators::bvh4::Bvh4;
+    use crate::hit::World;
+    use crate::loaders::tests::TestDirectory;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+    use crate::primitives::sphere::Sphere;
+    use crate::utils::aabb::Aabb;
+    use crate::utils::util::{Point3, Vec3};
+
+    const WIDTH: u32 = 16;
+    const HEIGHT: u32 = 12;
+
+    // a diffuse ground, a fuzzy metal and a glass sphere seen through a lens
+    fn render(region: Option<Region>, seed: Option<u64>) -> RgbImage {
+        let world: World = vec![
+            Box::new(Sphere::new(Point3::new(0.0, -100.5, -1.0), 100.0, Lambertian::new(Color::new(0.5, 0.5, 0.5)))),
+            Box::new(Sphere::new(Point3::new(-0.5, 0.0, -1.0), 0.5, Metal::new(Color::new(0.8, 0.6, 0.2), 0.3))),
+            Box::new(Sphere::new(Point3::new(0.5, 0.0, -1.0), 0.5, Dielectric::new(1.5))),
+        ];
+
+        let mut bvh = Bvh::new(None, None);
+        bvh.build(world);
+
+        render_with(&bvh, 8, region, seed)
+    }
+
+    // render the spheres of the scene through the accelerator built over them
+    fn render_with<A: Accelerator>(scene: &A, samples_per_pixel: u32, region: Option<Region>, seed: Option<u64>) -> RgbImage {
+        let cam = Camera::new(Point3::new(0.0, 0.5, 2.0), Point3::new(0.0, 0.0, -1.0), Vec3::Y, 40.0,
+                              WIDTH as Float / HEIGHT as Float, 0.1, 3.0);
+
+        let mut tracer = Tracer::new(WIDTH, HEIGHT, samples_per_pixel);
+        tracer.set_quiet(true);
+        tracer.set_seed(seed);
+        if let Some(region) = region {
+            tracer.set_region(region);
+        }
+
+        tracer.trace(&cam, scene, 8);
+        tracer.image_buffer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn seeded_renderings_are_reproducible() {
+        let image = render(None, Some(7));
+        assert_eq!(image, render(None, Some(7)));
+        assert_ne!(image, render(None, Some(8)));
+    }
+
+    // the accelerator tracing the rays of the packets one by one
+    struct SingleRays<A>(A);
+
+    impl<A: Accelerator> Accelerator for SingleRays<A> {
+        fn build(&mut self, world: World) {
+            self.0.build(world);
+        }
+
+        fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+            self.0.intersect(ray, t_min, t_max)
+        }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            self.0.bounding_box()
+        }
+    }
+
+    #[test]
+    fn packets_render_like_single_rays() {
+        let mut bvh4 = Bvh4::new(None, None);
+        bvh4.build(sphere_world(50));
+
+        let mut single_rays = SingleRays(Bvh4::new(None, None));
+        single_rays.build(sphere_world(50));
+
+        // including the pixels whose last packet is partly filled
+        for samples_per_pixel in [1, 3, 4, 6] {
+            assert_eq!(render_with(&bvh4, samples_per_pixel, None, Some(3)), render_with(&single_rays, samples_per_pixel, None, Some(3)));
+        }
+    }
+
+    #[test]
+    fn save_reports_errors() {
+        let directory = TestDirectory::new("tracer_save");
+        let mut tracer = Tracer::new(WIDTH, HEIGHT, 1);
+        tracer.set_quiet(true);
+
+        let folder = directory.join("images");
+        let folder = folder.to_string_lossy();
+        assert!(tracer.save(&folder, "image.png").is_ok());
+        assert!(directory.join("images/image.png").exists());
+
+        // the format is unknown, and the folder cannot be created below a file
+        assert!(tracer.save(&folder, "image.unknown").is_err());
+        let file = directory.write("file", "");
+        assert!(tracer.save(&file.join("images").to_string_lossy(), "image.png").is_err());
+    }
+
+    #[test]
+    fn region_is_cropped_out_of_the_image() {
+        let region = Region { x: 3, y: 5, width: 6, height: 4 };
+        let image = render(None, Some(1));
+        let cropped = render(Some(region), Some(1));
+
+        assert_eq!(cropped.dimensions(), (6, 4));
+
+        // the pixels of the region are rendered with the same random numbers as in the whole image
+        for (x, y, pixel) in cropped.enumerate_pixels() {
+            assert_eq!(pixel, image.get_pixel(x + region.x, y + region.y));
+        }
+    }
+
+    #[test]
+    fn lights_are_seen_from_their_emitting_side() {
+        // the camera sits inside a sphere, which emits from its outside unless it is two sided
+        let render = |two_sided: bool| {
+            let mut bvh = Bvh::new(None, None);
+            bvh.build(vec![Box::new(Sphere::new(Point3::ZERO, 10.0, DiffuseLight::new(Color::ONE, two_sided)))]);
+
+            let cam = Camera::new(Point3::ZERO, Point3::new(0.0, 0.0, -1.0), Vec3::Y, 40.0,
+                                  WIDTH as Float / HEIGHT as Float, 0.0, 1.0);
+
+            let mut tracer = Tracer::new(WIDTH, HEIGHT, 4);
+            tracer.set_quiet(true);
+            tracer.set_seed(Some(1));
+            tracer.trace(&cam, &bvh, 8);
+            tracer.image_buffer.into_inner().unwrap()
+        };
+
+        assert!(render(true).pixels().all(|pixel| pixel.0 == [255, 255, 255]));
+        assert!(render(false).pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn rays_leaving_the_scene_see_the_background() {
+        let render = |background: Background| {
+            let bvh = Bvh::new(None, None);
+            let cam = Camera::new(Point3::ZERO, Point3::new(0.0, 0.0, -1.0), Vec3::Y, 40.0,
+                                  WIDTH as Float / HEIGHT as Float, 0.0, 1.0);
+
+            let mut tracer = Tracer::new(WIDTH, HEIGHT, 4);
+            tracer.set_quiet(true);
+            tracer.set_background(background);
+            tracer.trace(&cam, &bvh, 8);
+            tracer.image_buffer.into_inner().unwrap()
+        };
+
+        assert!(render(Background::Color(Color::ZERO)).pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+        assert!(render(Background::Color(Color::ONE)).pixels().all(|pixel| pixel.0 == [255, 255, 255]));
+
+        // the sky is blue overhead
+        let sky = render(Background::Sky);
+        assert!(sky.get_pixel(0, 0).0[2] > sky.get_pixel(0, 0).0[0]);
     }
 }
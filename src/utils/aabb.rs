@@ -1,9 +1,7 @@
-use glam::Vec3;
+use crate::utils::util::{Float, Point3, Vec3};
 use std::fmt;
 
 use crate::utils::axis::Axis;
-use crate::utils::util::Point3;
-use crate::hit::{Hit, HitRecord};
 use crate::ray::Ray;
 
 const DIMENSION : usize = 3;    
@@ -37,8 +35,12 @@ impl Aabb {
         self.max
     }
 
-    // returns true if the given ray intersects the bounding box
-    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+    // returns true if the given ray intersects the bounding box. A ray that only touches the box
+    // still hits it, so that the flat boxes around axis aligned triangles are not missed. Padding
+    // the boxes by an epsilon does not keep them open: past a magnitude of one the epsilon is below
+    // the precision of the coordinates and rounds away, and the entry and exit along a ray from far
+    // away round to the same distance even when it does not
+    pub fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
         let mut t_min = t_min;
         let mut t_max = t_max;
 
@@ -54,7 +56,7 @@ impl Aabb {
             t_min = t_min.max(t0);
             t_max = t_max.min(t1);
 
-            if t_max <= t_min {
+            if t_max < t_min {
                 return false;
             }
         }
@@ -62,11 +64,37 @@ impl Aabb {
         true
     }
 
+    // returns the parametric range of the ray inside the bounding box clipped to
+    // t_min and t_max, None if the ray misses the bounding box in that range
+    pub fn hit_range(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+
+        for i in 0..DIMENSION {
+            let inv_d = 1.0 / r.direction()[i];
+            let mut t0 = (self.min[i] - r.origin()[i]) * inv_d;
+            let mut t1 = (self.max[i] - r.origin()[i]) * inv_d;
+
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+
+            if t_max < t_min {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+
     // creates an empty bounding box
     pub fn empty() -> Aabb {
         Aabb {
-            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
-            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            min: Point3::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+            max: Point3::new(Float::NEG_INFINITY, Float::NEG_INFINITY, Float::NEG_INFINITY),
         }
     }
 
@@ -83,27 +111,27 @@ impl Aabb {
             && p.z <= self.max.z
     }
 
-    pub fn approx_contains_eps(&self, p: &Point3, epsilon: f32) -> bool {
+    pub fn approx_contains_eps(&self, p: &Point3, epsilon: Float) -> bool {
         p.x - self.min.x > -epsilon
             && p.x - self.max.x < epsilon
-            && p.y - self.min.y > epsilon
+            && p.y - self.min.y > -epsilon
             && p.y - self.max.y < epsilon
-            && p.z - self.min.z > epsilon
+            && p.z - self.min.z > -epsilon
             && p.z - self.max.z < epsilon
     }
 
-    pub fn approx_contains_aabb_eps(&self, other: &Aabb, epsilon: f32) -> bool {
+    pub fn approx_contains_aabb_eps(&self, other: &Aabb, epsilon: Float) -> bool {
         self.approx_contains_eps(&other.min, epsilon)
             && self.approx_contains_eps(&other.max, epsilon)
     }
 
-    pub fn relative_eq(&self, other: &Aabb, epsilon: f32) -> bool {
-        f32::abs(self.min.x - other.min.x) < epsilon
-            && f32::abs(self.min.y - other.min.y) < epsilon
-            && f32::abs(self.min.z - other.min.z) < epsilon
-            && f32::abs(self.max.x - other.max.x) < epsilon
-            && f32::abs(self.max.y - other.max.y) < epsilon
-            && f32::abs(self.max.z - other.max.z) < epsilon
+    pub fn relative_eq(&self, other: &Aabb, epsilon: Float) -> bool {
+        Float::abs(self.min.x - other.min.x) < epsilon
+            && Float::abs(self.min.y - other.min.y) < epsilon
+            && Float::abs(self.min.z - other.min.z) < epsilon
+            && Float::abs(self.max.x - other.max.x) < epsilon
+            && Float::abs(self.max.y - other.max.y) < epsilon
+            && Float::abs(self.max.z - other.max.z) < epsilon
     }
 
     pub fn include(&self, other: &Aabb) -> Aabb {
@@ -172,12 +200,12 @@ impl Aabb {
         self.min + (self.size() / 2.0)
     }
 
-    pub fn surface_area(&self) -> f32 {
+    pub fn surface_area(&self) -> Float {
         let size = self.size();
         2.0 * (size.x * size.y + size.x * size.z + size.y * size.z)
     }
 
-    pub fn volume(&self) -> f32 {
+    pub fn volume(&self) -> Float {
         let size = self.size();
         size.x * size.y * size.z
     }
@@ -249,6 +277,39 @@ mod tests {
         assert!(max.x < x && max.y < y && max.z < z);
     }
 
+    #[test]
+    fn hit_range_test() {
+        let aabb = Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(aabb.hit_range(&ray, 0.0, Float::INFINITY), Some((4.0, 6.0)));
+
+        // the range is clipped to the range of the ray
+        assert_eq!(aabb.hit_range(&ray, 0.0, 5.0), Some((4.0, 5.0)));
+        assert_eq!(aabb.hit_range(&ray, 0.0, 3.0), None);
+
+        let miss = Ray::new(Point3::new(2.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(aabb.hit_range(&miss, 0.0, Float::INFINITY), None);
+    }
+
+    #[test]
+    fn hit_flat_box_test() {
+        // the box around a quad in the z = 0 plane is thinner than the
+        // precision of the distance along the ray, the entry and exit coincide
+        let aabb = Aabb::new(Point3::new(-1.0, -1.0, -Float::EPSILON), Point3::new(1.0, 1.0, Float::EPSILON));
+
+        let ray = Ray::new(Point3::new(0.5, 0.5, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(aabb.hit(&ray, 0.001, Float::INFINITY));
+
+        let miss = Ray::new(Point3::new(1.5, 0.5, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!aabb.hit(&miss, 0.001, Float::INFINITY));
+
+        // the epsilon the triangles pad their boxes with rounds away at z = 5
+        let flat = Aabb::new(Point3::new(-1.0, -1.0, 5.0 - Float::EPSILON), Point3::new(1.0, 1.0, 5.0 + Float::EPSILON));
+        assert_eq!(flat.min().z, flat.max().z);
+        assert!(flat.hit(&ray, 0.001, Float::INFINITY));
+    }
+
     #[test]
     fn containment_test() {
         let aabb = Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
@@ -266,8 +327,8 @@ mod tests {
         let point_barely_outside = Point3::new(1.0000_0000_1, -1.0000_0000_1, 1.0000_0000_001);
         let point_outside = Point3::new(1.0, -2.0, 4.0);
 
-        // assert!(aabb.approx_contains_eps(&point_barely_outside));
-        //assert!(!aabb.approx_contains_eps(&point_outside));
+        assert!(aabb.approx_contains_eps(&point_barely_outside, 0.00001));
+        assert!(!aabb.approx_contains_eps(&point_outside, 0.00001));
     }
 
     #[test]
@@ -418,6 +479,6 @@ mod tests {
         let aabb = Aabb::new(min, max);
         let axis = aabb.largest_axis();
 
-        //assert!(axis == Axis::X);
+        assert!(axis == Axis::X);
     }
 }
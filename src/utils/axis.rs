@@ -4,6 +4,7 @@ use std::ops::{Index, IndexMut};
 
 struct MyType<T>(T);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Axis {
     /// Index of the X axis.
     X = 0,
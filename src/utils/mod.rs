@@ -1,4 +1,5 @@
 pub mod util;
 pub mod stack;
 pub mod aabb;
-pub mod axis;
\ No newline at end of file
+pub mod axis;
+pub mod morton;
\ No newline at end of file
@@ -0,0 +1,127 @@
+use rayon::prelude::*;
+
+use crate::utils::util::{Float, Point3};
+
+// number of bits of a morton code, 10 bits for each of the three axes
+pub const MORTON_BITS: u32 = 30;
+
+// number of distinct values along each axis
+const MORTON_SCALE: Float = 1024.0;
+
+// the radix sort handles 6 bits per pass, so 5 passes sort 30 bit codes
+const BITS_PER_PASS: u32 = 6;
+const NUM_BUCKETS: usize = 1 << BITS_PER_PASS;
+const BUCKET_MASK: u32 = (1 << BITS_PER_PASS) - 1;
+
+// minimum number of elements handed to a thread while sorting
+const MIN_CHUNK_SIZE: usize = 4096;
+
+// a primitive index tagged with the morton code of its centroid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MortonPrimitive {
+    pub primitive_index: usize,
+    pub morton_code: u32,
+}
+
+// spread the lower 10 bits of x, so that there are two zero bits
+// in between every bit of x
+pub fn left_shift3(x: u32) -> u32 {
+    let mut x = x & 0b11_1111_1111;
+
+    x = (x | (x << 16)) & 0b0000_0011_0000_0000_0000_0000_1111_1111;
+    x = (x | (x << 8)) & 0b0000_0011_0000_0000_1111_0000_0000_1111;
+    x = (x | (x << 4)) & 0b0000_0011_0000_1100_0011_0000_1100_0011;
+    x = (x | (x << 2)) & 0b0000_1001_0010_0100_1001_0010_0100_1001;
+
+    x
+}
+
+// interleave the bits of a point given relative to its bounds, that is in
+// between 0 and 1 along every axis, into a 30 bit morton code.
+// The bit at position 3 * i + axis holds the bit i of the given axis
+pub fn encode_morton3(p: Point3) -> u32 {
+    let quantize = |v: Float| (v * MORTON_SCALE).clamp(0.0, MORTON_SCALE - 1.0) as u32;
+
+    (left_shift3(quantize(p.z)) << 2) | (left_shift3(quantize(p.y)) << 1) | left_shift3(quantize(p.x))
+}
+
+// sort the primitives by their morton codes with a least significant digit radix sort.
+// Every pass distributes chunks of the primitives into buckets in parallel and
+// gathers the buckets chunk by chunk, which keeps every pass stable
+pub fn radix_sort(primitives: &mut Vec<MortonPrimitive>) {
+    let chunk_size = (primitives.len() / rayon::current_num_threads()).max(MIN_CHUNK_SIZE);
+
+    for pass in 0..MORTON_BITS / BITS_PER_PASS {
+        let low_bit = pass * BITS_PER_PASS;
+
+        let chunk_buckets: Vec<Vec<Vec<MortonPrimitive>>> = primitives
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut buckets = vec![Vec::new(); NUM_BUCKETS];
+
+                for primitive in chunk {
+                    let bucket = (primitive.morton_code >> low_bit) & BUCKET_MASK;
+                    buckets[bucket as usize].push(*primitive);
+                }
+
+                buckets
+            })
+            .collect();
+
+        primitives.clear();
+
+        for bucket in 0..NUM_BUCKETS {
+            for buckets in &chunk_buckets {
+                primitives.extend_from_slice(&buckets[bucket]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn left_shift() {
+        assert_eq!(left_shift3(0b1), 0b1);
+        assert_eq!(left_shift3(0b11), 0b1001);
+        assert_eq!(left_shift3(0b11_1111_1111), 0b1001_0010_0100_1001_0010_0100_1001);
+    }
+
+    #[test]
+    fn encode_interleaves_axes() {
+        // the smallest non zero value along each axis sets the lowest bit of that axis
+        let step = 1.0 / MORTON_SCALE;
+
+        assert_eq!(encode_morton3(Point3::new(0.0, 0.0, 0.0)), 0);
+        assert_eq!(encode_morton3(Point3::new(step, 0.0, 0.0)), 0b001);
+        assert_eq!(encode_morton3(Point3::new(0.0, step, 0.0)), 0b010);
+        assert_eq!(encode_morton3(Point3::new(0.0, 0.0, step)), 0b100);
+
+        // the upper bound is clamped into the 30 bits
+        assert_eq!(encode_morton3(Point3::new(1.0, 1.0, 1.0)), (1 << MORTON_BITS) - 1);
+    }
+
+    #[test]
+    fn radix_sort_matches_std_sort() {
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let mut primitives: Vec<MortonPrimitive> = (0..20_000)
+            .map(|i| MortonPrimitive {
+                primitive_index: i,
+                morton_code: rng.gen_range(0..(1 << MORTON_BITS)),
+            })
+            .collect();
+
+        let mut expected = primitives.clone();
+        expected.sort_by_key(|p| p.morton_code);
+
+        radix_sort(&mut primitives);
+
+        // both sorts are stable, so even the order of equal codes has to agree
+        assert_eq!(primitives, expected);
+    }
+}
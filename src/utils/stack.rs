@@ -2,6 +2,12 @@ pub struct Stack<T> {
     elements: Vec<T>,
 }
 
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Stack<T> {
     pub fn new() -> Self {
         Self { elements: Vec::new() }
@@ -38,7 +44,7 @@ mod tests {
     fn push_and_pop() {
         let mut stack = Stack::new();
         stack.push(42);
-        assert_eq!(stack.is_empty(), false);
+        assert!(!stack.is_empty());
         assert_eq!(stack.peek(), Some(&42));
         assert_eq!(stack.pop(), Some(42));
         assert!(stack.is_empty());
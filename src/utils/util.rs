@@ -1,16 +1,54 @@
-use glam::Vec3;
 use rand::prelude::*;
+use std::cell::RefCell;
 use std::ops::Range;
 
+/*
+    The precision of the geometry. The rays, points and the acceleration structures
+    are in single precision, unless the f64 feature switches them to double precision
+    for scenes whose large coordinates leave too few bits of an f32 for the details.
+*/
+#[cfg(not(feature = "f64"))]
+mod precision {
+    pub type Float = f32;
+    pub use glam::{Affine3A as Affine3, Mat4, Quat, Vec2, Vec3, Vec4};
+    pub use std::f32::consts;
+}
+
+#[cfg(feature = "f64")]
+mod precision {
+    pub type Float = f64;
+    pub use glam::{DAffine3 as Affine3, DMat4 as Mat4, DQuat as Quat, DVec2 as Vec2, DVec3 as Vec3, DVec4 as Vec4};
+    pub use std::f64::consts;
+}
+
+pub use precision::*;
+
+thread_local! {
+    // the random numbers of the renderer come from a generator of every thread. The tracer
+    // reseeds it for every pixel of a seeded rendering, so the image does not depend on
+    // which thread renders which pixel
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
 pub struct Util;
 
 pub type Point3 = Vec3; // give a new name to the existing type
 pub type Color = Vec3; // give a new name to the existing type
 
 impl Util {
+    /// Reseed the random number generator of the current thread
+    pub fn seed(seed: u64) {
+        RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+    }
+
+    /// Run the closure with the random number generator of the current thread
+    pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+        RNG.with(|rng| f(&mut rng.borrow_mut()))
+    }
+
     /// Convert the Vec3 to Color Array
     pub fn to_rgb(vec: &Vec3) -> [u8; 3] {
-        fn f(num: f32) -> u8 {
+        fn f(num: Float) -> u8 {
             if num < 0.0 {
                 0
             } else if num >= 1.0 {
@@ -26,29 +64,29 @@ impl Util {
     pub fn gamma_correction(vec: &Vec3, samples_per_pixel: u32) -> [u8; 3] {
         [
             (256.0
-                * (vec.x / (samples_per_pixel as f32/* explicit conversion */))
+                * (vec.x / (samples_per_pixel as Float/* explicit conversion */))
                     .sqrt()
                     .clamp(0.0, 0.999)) as u8, /* explicit conversion */
             (256.0
-                * (vec.y / (samples_per_pixel as f32/* explicit conversion */))
+                * (vec.y / (samples_per_pixel as Float/* explicit conversion */))
                     .sqrt()
                     .clamp(0.0, 0.999)) as u8, /* explicit conversion */
             (256.0
-                * (vec.z / (samples_per_pixel as f32/* explicit conversion */))
+                * (vec.z / (samples_per_pixel as Float/* explicit conversion */))
                     .sqrt()
                     .clamp(0.0, 0.999)) as u8, /* explicit conversion */
         ]
     }
 
     /// Generate Vec3 by generating random number
-    pub fn random(r: Range<f32>) -> Vec3 {
-        let mut rng = rand::thread_rng();
-
-        Vec3::new(
-            rng.gen_range(r.clone()),
-            rng.gen_range(r.clone()),
-            rng.gen_range(r.clone()),
-        )
+    pub fn random(r: Range<Float>) -> Vec3 {
+        Self::with_rng(|rng| {
+            Vec3::new(
+                rng.gen_range(r.clone()),
+                rng.gen_range(r.clone()),
+                rng.gen_range(r.clone()),
+            )
+        })
     }
 
     pub fn random_in_unit_sphere() -> Vec3 {
@@ -70,18 +108,16 @@ impl Util {
     }
 
     pub fn random_in_unit_disk() -> Vec3 {
-        let mut rng = rand::thread_rng();
-
-        loop {
+        Self::with_rng(|rng| loop {
             let p = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
             if p.length() < 1.0 {
                 return p;
             }
-        }
+        })
     }
 
     pub fn near_zero(vec: &Vec3) -> bool {
-        const EPS: f32 = 1.0e-8;
+        const EPS: Float = 1.0e-8;
         vec.x.abs() < EPS && vec.y.abs() < EPS && vec.z.abs() < EPS
     }
 
@@ -98,7 +134,7 @@ impl Util {
         *incoming_vec - 2.0 * incoming_vec.dot(*n) * *n
     }
 
-    pub fn refract(incoming_vec: &Vec3, n: &Vec3, etai_over_etat: f32) -> Vec3 {
+    pub fn refract(incoming_vec: &Vec3, n: &Vec3, etai_over_etat: Float) -> Vec3 {
         let cos_theta = ((-1.0) * *incoming_vec).dot(*n).min(1.0);
         let r_out_perp = etai_over_etat * (*incoming_vec + cos_theta * *n);
         let r_out_parallel = -(1.0 - r_out_perp.length().powi(2)).abs().sqrt() * *n;